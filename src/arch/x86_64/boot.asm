@@ -188,7 +188,7 @@ p3_table:
 p2_table:
     resb 4096
 stack_bottom:
-    resb 4096 * 2
+    resb 4096 * 16
 stack_top:
 
 
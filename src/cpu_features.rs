@@ -0,0 +1,103 @@
+use spin::Once;
+
+/// The optional processor features the kernel cares about, queried once
+/// through `cpuid` and cached afterwards.
+struct Features {
+    no_execute: bool,
+    page_attribute_table: bool,
+    global_pages: bool,
+    huge_pages_1gib: bool,
+    physical_address_bits: u8,
+}
+
+static FEATURES: Once<Features> = Once::new();
+
+fn features() -> &'static Features {
+    FEATURES.call_once(|| {
+        let edx = cpuid(1).3;
+        let max_extended_leaf = cpuid(0x8000_0000).0;
+        let extended_edx = if max_extended_leaf >= 0x8000_0001 {
+            cpuid(0x8000_0001).3
+        } else {
+            0
+        };
+        // processors that don't report it support 36 bits
+        let physical_address_bits = if max_extended_leaf >= 0x8000_0008 {
+            cpuid(0x8000_0008).0 as u8
+        } else {
+            36
+        };
+
+        Features {
+            no_execute: extended_edx & (1 << 20) != 0,
+            page_attribute_table: edx & (1 << 16) != 0,
+            global_pages: edx & (1 << 13) != 0,
+            huge_pages_1gib: extended_edx & (1 << 26) != 0,
+            physical_address_bits: physical_address_bits,
+        }
+    })
+}
+
+/// Executes `cpuid` for the given leaf and returns `(eax, ebx, ecx, edx)`.
+fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(0)
+             :: "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+/// Returns true if the processor supports the no-execute bit in page table
+/// entries (which additionally has to be enabled through EFER.NXE).
+#[cfg(not(test))]
+pub fn has_nx() -> bool {
+    features().no_execute
+}
+
+/// In host tests the answer comes from the mock, so both outcomes can be
+/// tested.
+#[cfg(test)]
+pub fn has_nx() -> bool {
+    ::hw::with_mock(|hw| hw.no_execute.get())
+}
+
+/// Returns true if the processor supports the page attribute table.
+pub fn has_pat() -> bool {
+    features().page_attribute_table
+}
+
+/// Returns true if the processor supports global pages (which additionally
+/// have to be enabled through CR4.PGE).
+pub fn has_global_pages() -> bool {
+    features().global_pages
+}
+
+/// Returns true if the processor supports 1GiB pages.
+#[cfg(not(test))]
+pub fn has_1gib_pages() -> bool {
+    features().huge_pages_1gib
+}
+
+/// In host tests the answer comes from the mock, so both outcomes can be
+/// tested.
+#[cfg(test)]
+pub fn has_1gib_pages() -> bool {
+    ::hw::with_mock(|hw| hw.huge_pages_1gib.get())
+}
+
+/// Returns the number of physical address bits the processor supports
+/// (MAXPHYADDR).
+#[cfg(not(test))]
+pub fn physical_address_bits() -> u8 {
+    features().physical_address_bits
+}
+
+/// In host tests the width comes from the mock, so it doesn't depend on the
+/// machine running the tests.
+#[cfg(test)]
+pub fn physical_address_bits() -> u8 {
+    ::hw::with_mock(|hw| hw.physical_address_bits.get())
+}
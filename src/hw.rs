@@ -0,0 +1,478 @@
+//! Wrappers around the privileged registers and instructions used by the
+//! memory code. The kernel always runs in ring 0, so reading registers and
+//! flushing the TLB can't fault and are exposed as safe functions. Writes
+//! that can break the address space stay `unsafe`.
+//!
+//! All of them go through the `Hw` trait, so host tests can check which
+//! registers the paging code touches and how often it flushes the TLB.
+
+use x86::{controlregs, msr, tlb};
+use cpu_features;
+
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+
+pub(crate) const IA32_EFER: u32 = 0xc000_0080;
+const IA32_PAT: u32 = 0x277;
+pub(crate) const IA32_APIC_BASE: u32 = 0x1b;
+
+// the control register and EFER bits the memory code reads or sets
+pub(crate) const CR0_WRITE_PROTECT: u64 = 1 << 16;
+pub(crate) const CR0_PAGING: u64 = 1 << 31;
+pub(crate) const CR4_PAE: u64 = 1 << 5;
+pub(crate) const CR4_GLOBAL_PAGES: u64 = 1 << 7;
+pub(crate) const EFER_LONG_MODE_ENABLE: u64 = 1 << 8;
+pub(crate) const EFER_LONG_MODE_ACTIVE: u64 = 1 << 10;
+pub(crate) const EFER_NXE: u64 = 1 << 11;
+
+/// Above this number of pages, `flush_range` flushes the whole TLB instead of
+/// invalidating every page. Refilling the TLB is cheaper than that many
+/// `invlpg` instructions.
+const FLUSH_RANGE_THRESHOLD: usize = 32;
+
+/// The privileged registers and instructions behind the functions in this
+/// module. The kernel runs on `X86Hw`; host tests run on `MockHw`, which
+/// records the calls instead of executing them.
+pub trait Hw {
+    fn read_cr0(&self) -> u64;
+    unsafe fn write_cr0(&self, value: u64);
+    fn read_cr2(&self) -> usize;
+    fn read_cr3(&self) -> usize;
+    unsafe fn write_cr3(&self, p4_address: usize);
+    fn read_cr4(&self) -> u64;
+    unsafe fn write_cr4(&self, value: u64);
+    fn read_msr(&self, msr: u32) -> u64;
+    unsafe fn write_msr(&self, msr: u32, value: u64);
+    fn flush(&self, address: usize);
+    fn flush_all(&self);
+}
+
+/// The processor the kernel runs on.
+#[cfg_attr(test, allow(dead_code))]
+pub struct X86Hw;
+
+impl Hw for X86Hw {
+    fn read_cr0(&self) -> u64 {
+        unsafe { controlregs::cr0() as u64 }
+    }
+
+    unsafe fn write_cr0(&self, value: u64) {
+        controlregs::cr0_write(value as usize);
+    }
+
+    fn read_cr2(&self) -> usize {
+        unsafe { controlregs::cr2() as usize }
+    }
+
+    fn read_cr3(&self) -> usize {
+        unsafe { controlregs::cr3() as usize }
+    }
+
+    unsafe fn write_cr3(&self, p4_address: usize) {
+        controlregs::cr3_write(p4_address as u64);
+    }
+
+    fn read_cr4(&self) -> u64 {
+        unsafe { controlregs::cr4() as u64 }
+    }
+
+    unsafe fn write_cr4(&self, value: u64) {
+        controlregs::cr4_write(value as usize);
+    }
+
+    fn read_msr(&self, msr: u32) -> u64 {
+        unsafe { msr::rdmsr(msr) }
+    }
+
+    unsafe fn write_msr(&self, msr: u32, value: u64) {
+        msr::wrmsr(msr, value);
+    }
+
+    fn flush(&self, address: usize) {
+        unsafe { tlb::flush(address) };
+    }
+
+    fn flush_all(&self) {
+        unsafe { tlb::flush_all() };
+    }
+}
+
+#[cfg(not(test))]
+fn with_hw<F, R>(f: F) -> R
+    where F: FnOnce(&Hw) -> R
+{
+    f(&X86Hw)
+}
+
+#[cfg(test)]
+fn with_hw<F, R>(f: F) -> R
+    where F: FnOnce(&Hw) -> R
+{
+    MOCK_HW.with(|hw| f(hw))
+}
+
+pub fn read_cr0() -> u64 {
+    with_hw(|hw| hw.read_cr0())
+}
+
+pub fn read_cr4() -> u64 {
+    with_hw(|hw| hw.read_cr4())
+}
+
+pub fn read_efer() -> u64 {
+    with_hw(|hw| hw.read_msr(IA32_EFER))
+}
+
+/// Returns the physical address of the active P4 table.
+pub fn read_cr3() -> usize {
+    with_hw(|hw| hw.read_cr3())
+}
+
+/// Loads a new P4 table, which also flushes all non-global TLB entries.
+///
+/// # Safety
+/// `p4_address` must be the physical address of a valid P4 table that maps
+/// the currently executing code, its stack and its data.
+pub unsafe fn write_cr3(p4_address: usize) {
+    with_hw(|hw| hw.write_cr3(p4_address));
+}
+
+/// Returns the faulting address of the last page fault.
+pub fn read_cr2() -> usize {
+    with_hw(|hw| hw.read_cr2())
+}
+
+/// Invalidates the TLB entry for the page containing `address`.
+pub fn flush(address: usize) {
+    with_hw(|hw| hw.flush(address));
+}
+
+/// Invalidates all non-global TLB entries.
+pub fn flush_all() {
+    with_hw(|hw| hw.flush_all());
+}
+
+/// Invalidates the TLB entries for `page_count` pages starting at the page
+/// containing `start_address`. Small ranges are flushed page by page, larger
+/// ones by flushing all entries, including global ones.
+pub fn flush_range(start_address: usize, page_count: usize) {
+    const PAGE_SIZE: usize = 4096;
+
+    if page_count > FLUSH_RANGE_THRESHOLD {
+        flush_all_including_global();
+        return;
+    }
+    for i in 0..page_count {
+        flush(start_address + i * PAGE_SIZE);
+    }
+}
+
+/// Invalidates all TLB entries. Toggling CR4.PGE also drops global entries,
+/// which survive a CR3 reload.
+fn flush_all_including_global() {
+    with_hw(|hw| unsafe {
+        let cr4 = hw.read_cr4();
+        if cr4 & CR4_GLOBAL_PAGES != 0 {
+            hw.write_cr4(cr4 & !CR4_GLOBAL_PAGES);
+            hw.write_cr4(cr4);
+        } else {
+            hw.flush_all();
+        }
+    });
+}
+
+/// A processor feature that was requested but isn't supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedFeature {
+    NoExecute,
+}
+
+/// Sets EFER.NXE so that the `NO_EXECUTE` page table bit is honored. All
+/// other EFER bits are preserved, and nothing is written if the bit is set
+/// already. Fails on processors without NX support, where setting the bit
+/// would raise a general protection fault.
+pub fn enable_nxe() -> Result<(), UnsupportedFeature> {
+    if !cpu_features::has_nx() {
+        return Err(UnsupportedFeature::NoExecute);
+    }
+    with_hw(|hw| {
+        let efer = hw.read_msr(IA32_EFER);
+        if efer & EFER_NXE == 0 {
+            unsafe { hw.write_msr(IA32_EFER, efer | EFER_NXE) };
+        }
+    });
+    Ok(())
+}
+
+/// Returns true if EFER.NXE is set.
+pub fn nxe_enabled() -> bool {
+    read_efer() & EFER_NXE != 0
+}
+
+/// Writes the page attribute table MSR.
+pub fn write_pat(value: u64) {
+    with_hw(|hw| unsafe { hw.write_msr(IA32_PAT, value) });
+}
+
+/// Returns the physical base address of the local APIC from the
+/// IA32_APIC_BASE MSR, with the flag bits masked off.
+pub fn read_apic_base() -> u64 {
+    with_hw(|hw| hw.read_msr(IA32_APIC_BASE)) & 0x000f_ffff_ffff_f000
+}
+
+/// Sets CR4.PGE so that `GLOBAL` entries survive CR3 reloads.
+pub fn enable_pge() {
+    with_hw(|hw| unsafe { hw.write_cr4(hw.read_cr4() | CR4_GLOBAL_PAGES) });
+}
+
+/// Returns true if CR4.PGE is set.
+pub fn pge_enabled() -> bool {
+    read_cr4() & CR4_GLOBAL_PAGES != 0
+}
+
+/// Sets CR0.WP so that ring 0 code can't write to read-only pages.
+pub fn enable_write_protect() {
+    with_hw(|hw| unsafe { hw.write_cr0(hw.read_cr0() | CR0_WRITE_PROTECT) });
+}
+
+/// Returns true if CR0.WP is set.
+pub fn write_protect_enabled() -> bool {
+    read_cr0() & CR0_WRITE_PROTECT != 0
+}
+
+/// Clears CR0.WP while alive, so that ring 0 code can write through read-only
+/// mappings. Dropping the guard restores the previous state of the bit, also
+/// when leaving the scope early.
+///
+/// # Safety
+/// Every read-only mapping in the kernel becomes writable, including the
+/// kernel's code and the page tables' read-only aliases. Keep the scope as
+/// short as possible and make sure nothing else runs on this CPU while the
+/// guard exists, e.g. by disabling interrupts.
+pub struct WriteProtectDisabled {
+    was_enabled: bool,
+}
+
+impl WriteProtectDisabled {
+    pub unsafe fn new() -> WriteProtectDisabled {
+        let cr0 = read_cr0();
+        with_hw(|hw| hw.write_cr0(cr0 & !CR0_WRITE_PROTECT));
+        WriteProtectDisabled { was_enabled: cr0 & CR0_WRITE_PROTECT != 0 }
+    }
+}
+
+impl Drop for WriteProtectDisabled {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            enable_write_protect();
+        }
+    }
+}
+
+/// Returns the current stack pointer.
+pub fn stack_pointer() -> usize {
+    let rsp: usize;
+    unsafe { asm!("mov %rsp, $0" : "=r"(rsp)) };
+    rsp
+}
+
+/// Stands in for the processor in host tests. Register writes are stored and
+/// read back, flushes are counted.
+#[cfg(test)]
+pub struct MockHw {
+    pub cr0: Cell<u64>,
+    pub cr2: Cell<usize>,
+    pub cr3: Cell<usize>,
+    pub cr4: Cell<u64>,
+    pub msrs: RefCell<Vec<(u32, u64)>>,
+    pub msr_writes: Cell<usize>,
+    pub cr3_writes: Cell<usize>,
+    pub flushed: RefCell<Vec<usize>>,
+    pub flush_alls: Cell<usize>,
+    /// What `cpu_features::has_nx` reports.
+    pub no_execute: Cell<bool>,
+    /// What `cpu_features::physical_address_bits` reports.
+    pub physical_address_bits: Cell<u8>,
+    /// What `cpu_features::has_1gib_pages` reports.
+    pub huge_pages_1gib: Cell<bool>,
+}
+
+#[cfg(test)]
+impl MockHw {
+    fn new() -> MockHw {
+        MockHw {
+            cr0: Cell::new(0),
+            cr2: Cell::new(0),
+            cr3: Cell::new(0),
+            cr4: Cell::new(0),
+            msrs: RefCell::new(Vec::new()),
+            msr_writes: Cell::new(0),
+            cr3_writes: Cell::new(0),
+            flushed: RefCell::new(Vec::new()),
+            flush_alls: Cell::new(0),
+            no_execute: Cell::new(true),
+            physical_address_bits: Cell::new(46),
+            huge_pages_1gib: Cell::new(true),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Hw for MockHw {
+    fn read_cr0(&self) -> u64 {
+        self.cr0.get()
+    }
+
+    unsafe fn write_cr0(&self, value: u64) {
+        self.cr0.set(value);
+    }
+
+    fn read_cr2(&self) -> usize {
+        self.cr2.get()
+    }
+
+    fn read_cr3(&self) -> usize {
+        self.cr3.get()
+    }
+
+    unsafe fn write_cr3(&self, p4_address: usize) {
+        self.cr3.set(p4_address);
+        self.cr3_writes.set(self.cr3_writes.get() + 1);
+    }
+
+    fn read_cr4(&self) -> u64 {
+        self.cr4.get()
+    }
+
+    unsafe fn write_cr4(&self, value: u64) {
+        self.cr4.set(value);
+    }
+
+    fn read_msr(&self, msr: u32) -> u64 {
+        self.msrs.borrow().iter().find(|&&(m, _)| m == msr).map_or(0, |&(_, value)| value)
+    }
+
+    unsafe fn write_msr(&self, msr: u32, value: u64) {
+        let mut msrs = self.msrs.borrow_mut();
+        msrs.retain(|&(m, _)| m != msr);
+        msrs.push((msr, value));
+        self.msr_writes.set(self.msr_writes.get() + 1);
+    }
+
+    fn flush(&self, address: usize) {
+        self.flushed.borrow_mut().push(address);
+    }
+
+    fn flush_all(&self) {
+        self.flush_alls.set(self.flush_alls.get() + 1);
+    }
+}
+
+// every test runs on its own thread, so each one starts with a fresh mock
+#[cfg(test)]
+thread_local!(static MOCK_HW: MockHw = MockHw::new());
+
+/// Passes the current thread's mock to `f`.
+#[cfg(test)]
+pub fn with_mock<F, R>(f: F) -> R
+    where F: FnOnce(&MockHw) -> R
+{
+    MOCK_HW.with(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_range_flushes_small_ranges_page_by_page() {
+        flush_range(0x1000, 3);
+        with_mock(|hw| {
+            assert_eq!(*hw.flushed.borrow(), vec![0x1000, 0x2000, 0x3000]);
+            assert_eq!(hw.flush_alls.get(), 0);
+        });
+    }
+
+    #[test]
+    fn flush_range_flushes_page_by_page_up_to_the_threshold() {
+        flush_range(0x1000, FLUSH_RANGE_THRESHOLD);
+        with_mock(|hw| {
+            assert_eq!(hw.flushed.borrow().len(), FLUSH_RANGE_THRESHOLD);
+            assert_eq!(hw.flush_alls.get(), 0);
+        });
+    }
+
+    #[test]
+    fn flush_range_flushes_everything_for_large_ranges() {
+        flush_range(0x1000, 100);
+        with_mock(|hw| {
+            assert!(hw.flushed.borrow().is_empty());
+            assert_eq!(hw.flush_alls.get(), 1);
+        });
+    }
+
+    #[test]
+    fn flush_range_toggles_pge_for_large_ranges() {
+        enable_pge();
+        flush_range(0x1000, FLUSH_RANGE_THRESHOLD + 1);
+        with_mock(|hw| {
+            assert!(hw.flushed.borrow().is_empty());
+            assert_eq!(hw.cr4.get(), CR4_GLOBAL_PAGES);
+        });
+    }
+
+    #[test]
+    fn write_protect_guard_restores_the_bit() {
+        enable_write_protect();
+        {
+            let _guard = unsafe { WriteProtectDisabled::new() };
+            assert!(!write_protect_enabled());
+        }
+        assert!(write_protect_enabled());
+    }
+
+    #[test]
+    fn write_protect_guard_leaves_a_cleared_bit_cleared() {
+        {
+            let _guard = unsafe { WriteProtectDisabled::new() };
+        }
+        assert!(!write_protect_enabled());
+    }
+
+    #[test]
+    fn enable_nxe_sets_only_the_nxe_bit() {
+        const EFER_SCE: u64 = 1 << 0;
+        let efer = EFER_SCE | EFER_LONG_MODE_ENABLE | EFER_LONG_MODE_ACTIVE;
+        with_mock(|hw| unsafe { hw.write_msr(IA32_EFER, efer) });
+
+        assert_eq!(enable_nxe(), Ok(()));
+        assert_eq!(read_efer(), efer | EFER_NXE);
+        assert!(nxe_enabled());
+    }
+
+    #[test]
+    fn enable_nxe_is_idempotent() {
+        with_mock(|hw| unsafe { hw.write_msr(IA32_EFER, EFER_NXE) });
+        assert_eq!(enable_nxe(), Ok(()));
+        assert_eq!(enable_nxe(), Ok(()));
+        with_mock(|hw| {
+            assert_eq!(hw.read_msr(IA32_EFER), EFER_NXE);
+            // only the write that set up the test
+            assert_eq!(hw.msr_writes.get(), 1);
+        });
+    }
+
+    #[test]
+    fn enable_nxe_fails_without_nx_support() {
+        with_mock(|hw| hw.no_execute.set(false));
+        assert_eq!(enable_nxe(), Err(UnsupportedFeature::NoExecute));
+        assert!(!nxe_enabled());
+        with_mock(|hw| assert_eq!(hw.msr_writes.get(), 0));
+    }
+
+    #[test]
+    fn apic_base_masks_the_flag_bits() {
+        with_mock(|hw| unsafe { hw.write_msr(IA32_APIC_BASE, 0xfee0_0900) });
+        assert_eq!(read_apic_base(), 0xfee0_0000);
+    }
+}
@@ -1,8 +1,13 @@
 #![feature(lang_items)]
 #![feature(const_fn)]
 #![feature(unique)]
-#![feature(step_by)]
-#![no_std]
+#![feature(asm)]
+#![feature(allocator_api)]
+#![feature(global_allocator)]
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(test)]
+extern crate core;
 extern crate rlibc;
 extern crate spin;
 extern crate multiboot2;
@@ -12,6 +17,8 @@ extern crate x86;
 
 #[macro_use]
 mod vga_buffer;
+mod cpu_features;
+mod hw;
 mod memory;
 
 #[no_mangle]
@@ -23,59 +30,30 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) {
     println!("Hello World{}", "!");
 
     let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
-    let memory_map_tag = boot_info.memory_map_tag()
-        .expect("Memory map tag required");
-    let elf_sections_tag = boot_info.elf_sections_tag()
-        .expect("Elf sections tag required");
-
-    let kernel_start = elf_sections_tag.sections().map(|s| s.addr).min().unwrap();
-    let kernel_end = elf_sections_tag.sections().map(|s| s.addr + s.size).max()
-        .unwrap();
-
-    let multiboot_start = multiboot_information_address;
-    let multiboot_end = multiboot_start + (boot_info.total_size as usize);
-
-    println!("kernel start: 0x{:x}, kernel end: 0x{:x}",
-        kernel_start, kernel_end);
-    println!("multiboot start: 0x{:x}, multiboot end: 0x{:x}",
-        multiboot_start, multiboot_end);
-
-    let mut frame_allocator = memory::AreaFrameAllocator::new(
-        kernel_start as usize, kernel_end as usize, multiboot_start,
-        multiboot_end, memory_map_tag.memory_areas());
 
     // this is the new part
-    enable_nxe_bit();
-    enable_write_protect_bit();
-    memory::remap_the_kernel(&mut frame_allocator, boot_info);
+    // without NX support, NO_EXECUTE is stripped from all mappings
+    let _ = hw::enable_nxe();
+    if cpu_features::has_global_pages() {
+        hw::enable_pge();
+    }
+    hw::enable_write_protect();
+    memory::init_page_attribute_table();
+    let (mut memory_controller, memory_info) = memory::init(boot_info);
+    println!("{} KiB of {} KiB memory usable",
+        memory_info.usable_ram_bytes / 1024, memory_info.total_ram_bytes / 1024);
     println!("It did not crash!");
 
     loop {}
 }
 
+#[cfg(not(test))]
 #[lang = "eh_personality"] extern fn eh_personality() {}
 
+#[cfg(not(test))]
 #[lang = "panic_fmt"] 
 extern fn panic_fmt(fmt: core::fmt::Arguments, file: &str, line: u32) -> ! {
     println!("\n\nPANIC in {} at line {}:", file, line);
     println!("    {}", fmt);
     loop{}
 }
-
-
-fn enable_nxe_bit() {
-    use x86::msr::{IA32_EFER, rdmsr, wrmsr};
-
-    let nxe_bit = 1 << 11;
-    unsafe {
-        let efer = rdmsr(IA32_EFER);
-        wrmsr(IA32_EFER, efer | nxe_bit);
-    }
-}
-
-fn enable_write_protect_bit() {
-    use x86::controlregs::{cr0, cr0_write};
-
-    let wp_bit = 1 << 16;
-    unsafe { cr0_write(cr0() | wp_bit) };
-}
@@ -0,0 +1,226 @@
+use super::paging::{Page, ActivePageTable, VirtualAddress, PhysicalAddress, NO_CACHE,
+                    NO_EXECUTE, virtual_pointer};
+use super::{FrameRange, AreaFrameAllocator};
+use multiboot2::BootInformation;
+use core::{mem, slice};
+
+
+/// The multiboot2 tags containing a copy of the ACPI 1.0 RSDP and the ACPI 2.0
+/// XSDP.
+const RSDP_V1_TAG: u32 = 14;
+const RSDP_V2_TAG: u32 = 15;
+
+/// The size of the header every system description table starts with.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// The longest root table that is accepted. An XSDT of this size has about
+/// 8000 entries, far more than any real system; a longer length is garbage.
+const MAX_ROOT_TABLE_LENGTH: usize = 64 * 1024;
+
+/// The size of the ACPI 1.0 part of the RSDP, which its checksum covers.
+const RSDP_V1_SIZE: usize = 20;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the following fields only exist in ACPI 2.0 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The identity mapped ACPI root tables.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiMapping {
+    /// The copy of the RSDP in the multiboot information.
+    pub rsdp: VirtualAddress,
+    pub revision: u8,
+    /// The address of the XSDT for ACPI 2.0 and later, of the RSDT otherwise.
+    pub root_table: PhysicalAddress,
+    pub root_table_length: usize,
+}
+
+/// Finds the RSDP in the multiboot information and identity maps the root
+/// table it points to read-only and uncached. The frames of the root table are
+/// reserved in `allocator`. Returns `None` if the boot loader didn't pass an
+/// RSDP, if its signature or checksum is wrong or if the root table has an
+/// implausible length; in the last case only the table header is mapped.
+pub fn map_acpi_tables(boot_info: &BootInformation,
+                       active_table: &mut ActivePageTable,
+                       allocator: &mut AreaFrameAllocator)
+                       -> Option<AcpiMapping>
+{
+    let rsdp_address = match find_rsdp(boot_info) {
+        Some(address) => address,
+        None => return None,
+    };
+    let rsdp = unsafe { &*(rsdp_address as *const Rsdp) };
+    if &rsdp.signature != b"RSD PTR " || !checksum_valid(rsdp_address, RSDP_V1_SIZE) {
+        return None;
+    }
+    // the extended checksum covers the ACPI 2.0 fields as well
+    if rsdp.revision >= 2 && !checksum_valid(rsdp_address, mem::size_of::<Rsdp>()) {
+        return None;
+    }
+
+    let revision = rsdp.revision;
+    let root_table = if revision >= 2 && rsdp.xsdt_address != 0 {
+        rsdp.xsdt_address as usize
+    } else {
+        rsdp.rsdt_address as usize
+    };
+
+    // map the header first to find out how long the table is
+    identity_map_table(root_table, SDT_HEADER_SIZE, active_table, allocator);
+    let root_table_length = unsafe { *virtual_pointer::<u32>(root_table + 4) as usize };
+    if root_table_length < SDT_HEADER_SIZE || root_table_length > MAX_ROOT_TABLE_LENGTH {
+        return None;
+    }
+    identity_map_table(root_table, root_table_length, active_table, allocator);
+
+    Some(AcpiMapping {
+        rsdp: rsdp_address,
+        revision: revision,
+        root_table: root_table,
+        root_table_length: root_table_length,
+    })
+}
+
+/// Returns true if the `length` bytes at `address` add up to zero, as ACPI
+/// checksums require.
+fn checksum_valid(address: VirtualAddress, length: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Returns the address of the RSDP copy in the multiboot information,
+/// preferring the ACPI 2.0 one.
+fn find_rsdp(boot_info: &BootInformation) -> Option<VirtualAddress> {
+    let mut rsdp_v1 = None;
+    let mut rsdp_v2 = None;
+
+    // the tags follow the 8 byte header and are 8 byte aligned
+    let mut tag_address = boot_info.start_address() + 8;
+    while tag_address < boot_info.end_address() {
+        let (typ, size) = unsafe {
+            (*(tag_address as *const u32), *((tag_address + 4) as *const u32) as usize)
+        };
+        match typ {
+            0 => break, // end tag
+            RSDP_V1_TAG => rsdp_v1 = Some(tag_address + 8),
+            RSDP_V2_TAG => rsdp_v2 = Some(tag_address + 8),
+            _ => {}
+        }
+        tag_address += (size + 7) & !7;
+    }
+
+    rsdp_v2.or(rsdp_v1)
+}
+
+fn identity_map_table(start: PhysicalAddress,
+                      size: usize,
+                      active_table: &mut ActivePageTable,
+                      allocator: &mut AreaFrameAllocator)
+{
+    let frames = FrameRange::containing_addresses(start, start + size);
+    allocator.reserve(frames).expect("too many reserved frame ranges for the ACPI tables");
+
+    for frame in frames.iter() {
+        let page = Page::containing_address(frame.start_address());
+        if active_table.translate_page(page).is_none() {
+            active_table.identity_map(frame, NO_CACHE | NO_EXECUTE, allocator)
+                .flush(active_table);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Frame;
+    use memory::test_support::{small_allocator, active_table, boot_information,
+                               physical_pointer};
+
+    /// Where the tests put the RSDT, in the memory of `small_allocator`.
+    const RSDT_ADDRESS: PhysicalAddress = 0x30_0000;
+
+    /// Returns an ACPI 1.0 RSDP pointing to `RSDT_ADDRESS`, with a valid
+    /// checksum.
+    fn rsdp(signature: &[u8; 8]) -> Vec<u8> {
+        let mut bytes = signature.to_vec();
+        bytes.push(0); // checksum
+        bytes.extend_from_slice(b"THERMT");
+        bytes.push(0); // revision
+        for i in 0..4 {
+            bytes.push((RSDT_ADDRESS >> (8 * i)) as u8);
+        }
+        bytes[8] = 0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+        bytes
+    }
+
+    /// Writes the header of an RSDT of the given length to the model memory.
+    fn write_rsdt(length: u32) {
+        unsafe {
+            *physical_pointer::<[u8; 4]>(RSDT_ADDRESS) = *b"RSDT";
+            *physical_pointer::<u32>(RSDT_ADDRESS + 4) = length;
+        }
+    }
+
+    #[test]
+    fn valid_rsdp_maps_the_root_table() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        // the header and two entries
+        write_rsdt(SDT_HEADER_SIZE as u32 + 8);
+        let boot_info = boot_information(&[(RSDP_V1_TAG, &rsdp(b"RSD PTR "))]);
+
+        let mapping = map_acpi_tables(boot_info, &mut active_table, &mut allocator).unwrap();
+        assert_eq!(mapping.revision, 0);
+        assert_eq!(mapping.root_table, RSDT_ADDRESS);
+        assert_eq!(mapping.root_table_length, SDT_HEADER_SIZE + 8);
+        let frame = Frame::containing_address(RSDT_ADDRESS);
+        assert!(allocator.is_reserved(&frame));
+        assert_eq!(active_table.translate_page(Page::containing_address(RSDT_ADDRESS)),
+                   Some(frame));
+    }
+
+    #[test]
+    fn rsdp_with_a_bad_signature_is_ignored() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        write_rsdt(SDT_HEADER_SIZE as u32);
+        let boot_info = boot_information(&[(RSDP_V1_TAG, &rsdp(b"RSD PTX "))]);
+
+        assert!(map_acpi_tables(boot_info, &mut active_table, &mut allocator).is_none());
+        assert!(!allocator.is_reserved(&Frame::containing_address(RSDT_ADDRESS)));
+    }
+
+    #[test]
+    fn rsdp_with_a_bad_checksum_is_ignored() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        write_rsdt(SDT_HEADER_SIZE as u32);
+        let mut bytes = rsdp(b"RSD PTR ");
+        bytes[8] = bytes[8].wrapping_add(1);
+        let boot_info = boot_information(&[(RSDP_V1_TAG, &bytes)]);
+
+        assert!(map_acpi_tables(boot_info, &mut active_table, &mut allocator).is_none());
+    }
+
+    #[test]
+    fn root_table_with_an_implausible_length_is_rejected() {
+        for &length in &[SDT_HEADER_SIZE as u32 - 1, MAX_ROOT_TABLE_LENGTH as u32 + 1] {
+            let mut allocator = small_allocator();
+            let mut active_table = active_table(&mut allocator);
+            write_rsdt(length);
+            let boot_info = boot_information(&[(RSDP_V1_TAG, &rsdp(b"RSD PTR "))]);
+
+            assert!(map_acpi_tables(boot_info, &mut active_table, &mut allocator).is_none());
+        }
+    }
+}
@@ -0,0 +1,61 @@
+/// Iterates from `start` up to, but excluding, `end` in steps of `step`. The
+/// last step may be partial: `end` itself is never yielded, and stepping past
+/// `usize::MAX` ends the iteration instead of overflowing.
+#[derive(Debug, Clone)]
+pub struct StepRange {
+    start: usize,
+    end: usize,
+    step: usize,
+}
+
+impl StepRange {
+    pub fn new(start: usize, end: usize, step: usize) -> StepRange {
+        assert!(step > 0, "step must not be zero");
+        StepRange {
+            start: start,
+            end: end,
+            step: step,
+        }
+    }
+}
+
+impl Iterator for StepRange {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.start >= self.end {
+            return None;
+        }
+        let value = self.start;
+        self.start = self.start.checked_add(self.step).unwrap_or(self.end);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::usize;
+
+    #[test]
+    fn evenly_dividing_range_yields_every_step() {
+        let steps: Vec<_> = StepRange::new(0x1000, 0x4000, 0x1000).collect();
+        assert_eq!(steps, [0x1000, 0x2000, 0x3000]);
+    }
+
+    #[test]
+    fn non_dividing_range_stops_before_the_end() {
+        let steps: Vec<_> = StepRange::new(0x1000, 0x3800, 0x1000).collect();
+        assert_eq!(steps, [0x1000, 0x2000, 0x3000]);
+    }
+
+    #[test]
+    fn stepping_past_usize_max_ends_the_iteration() {
+        let start = usize::MAX - 0x1fff;
+        let steps: Vec<_> = StepRange::new(start, usize::MAX, 0x1000).collect();
+        assert_eq!(steps, [start, start + 0x1000]);
+
+        let steps: Vec<_> = StepRange::new(usize::MAX - 1, usize::MAX, 0x1000).collect();
+        assert_eq!(steps, [usize::MAX - 1]);
+    }
+}
@@ -0,0 +1,194 @@
+//! A randomized stress test for `AreaFrameAllocator`. Every seed runs a
+//! sequence of allocations and deallocations against the allocator and a
+//! reference model of the outstanding frames, and checks after every step
+//! that both agree. A failing seed reproduces the same sequence.
+
+use memory::{Frame, FrameAllocator, FrameRange, AreaFrameAllocator, AllocationOrder};
+use memory::test_support::{MIB, memory_areas};
+use std::collections::HashSet;
+const STEPS: usize = 3000;
+
+/// A xorshift64* generator, good enough to pick operations.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // the state must not be zero
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+struct Harness {
+    seed: u64,
+    step: usize,
+    allocator: AreaFrameAllocator,
+    // frame numbers handed out and not yet freed
+    outstanding: HashSet<usize>,
+    // frame numbers freed and not handed out again
+    freed: HashSet<usize>,
+    total: usize,
+}
+
+impl Harness {
+    fn new(seed: u64, order: AllocationOrder) -> Harness {
+        // two areas with a hole in between; the kernel and the multiboot
+        // information lie in the first one
+        let mut allocator = AreaFrameAllocator::new(
+            0x10_0000, 0x10_4000, 0x10_8000, 0x10_9000,
+            memory_areas(&[(MIB, MIB), (3 * MIB, MIB)]));
+        allocator.set_allocation_order(order);
+        allocator.add_node_range(FrameRange { start: 0x100, end: 0x200 }, 0);
+        allocator.add_node_range(FrameRange { start: 0x300, end: 0x400 }, 1);
+        let total = allocator.total_frames();
+        assert_eq!(total, 2 * 256 - 5);
+
+        Harness {
+            seed: seed,
+            step: 0,
+            allocator: allocator,
+            outstanding: HashSet::new(),
+            freed: HashSet::new(),
+            total: total,
+        }
+    }
+
+    fn hand_out(&mut self, frame: &Frame) {
+        let number = frame.number();
+        assert!(self.allocator.manages(frame),
+            "seed {} step {}: {:?} isn't an allocatable frame", self.seed, self.step, frame);
+        assert!(self.outstanding.insert(number),
+            "seed {} step {}: {:?} handed out twice", self.seed, self.step, frame);
+        self.freed.remove(&number);
+    }
+
+    fn allocate(&mut self) {
+        let peeked = self.allocator.peek_next_frame();
+        match self.allocator.allocate_frame() {
+            Some(frame) => {
+                assert_eq!(peeked.as_ref(), Some(&frame),
+                    "seed {} step {}: peek_next_frame was wrong", self.seed, self.step);
+                self.hand_out(&frame);
+            }
+            None => assert_eq!(self.outstanding.len(), self.total,
+                "seed {} step {}: out of frames too early", self.seed, self.step),
+        }
+    }
+
+    fn allocate_on_node(&mut self, node: u8) {
+        match self.allocator.allocate_frame_on_node(node) {
+            Some(frame) => self.hand_out(&frame),
+            None => assert_eq!(self.outstanding.len(), self.total,
+                "seed {} step {}: out of frames too early", self.seed, self.step),
+        }
+    }
+
+    fn allocate_contiguous(&mut self, count: usize, ceiling: usize) {
+        let used = self.allocator.used_frames();
+        match self.allocator.allocate_contiguous(count, &Frame::from_number(ceiling)) {
+            Some(range) => {
+                assert_eq!(range.len(), count, "seed {} step {}", self.seed, self.step);
+                assert!(range.end().number() <= ceiling, "seed {} step {}", self.seed, self.step);
+                for frame in range.iter() {
+                    self.hand_out(&frame);
+                }
+            }
+            None => assert_eq!(self.allocator.used_frames(), used,
+                "seed {} step {}: failed allocate_contiguous kept frames", self.seed, self.step),
+        }
+    }
+
+    fn deallocate(&mut self, rng: &mut Rng) {
+        if self.outstanding.is_empty() {
+            return;
+        }
+        let index = rng.below(self.outstanding.len());
+        let number = *self.outstanding.iter().nth(index).unwrap();
+        self.outstanding.remove(&number);
+        self.freed.insert(number);
+        self.allocator.try_deallocate_frame(Frame::from_number(number))
+            .unwrap_or_else(|error| panic!("seed {} step {}: {:?}", self.seed, self.step, error));
+    }
+
+    fn double_free(&mut self) {
+        let number = match self.freed.iter().next() {
+            Some(&number) => number,
+            None => return,
+        };
+        assert!(self.allocator.try_deallocate_frame(Frame::from_number(number)).is_err(),
+            "seed {} step {}: double free accepted", self.seed, self.step);
+    }
+
+    fn check_stats(&self) {
+        assert_eq!(self.allocator.used_frames(), self.outstanding.len(),
+            "seed {} step {}: used_frames", self.seed, self.step);
+        assert_eq!(self.allocator.free_frames(), self.total - self.outstanding.len(),
+            "seed {} step {}: free_frames", self.seed, self.step);
+        assert_eq!(self.allocator.total_frames(), self.total,
+            "seed {} step {}: total_frames", self.seed, self.step);
+    }
+
+    fn run(&mut self, rng: &mut Rng) {
+        for step in 0..STEPS {
+            self.step = step;
+            match rng.below(16) {
+                0...5 => self.allocate(),
+                6...10 => self.deallocate(rng),
+                11 | 12 => {
+                    let node = rng.below(2) as u8;
+                    self.allocate_on_node(node);
+                }
+                13 | 14 => {
+                    let count = 1 + rng.below(8);
+                    let ceiling = 0x300 + rng.below(0x200);
+                    self.allocate_contiguous(count, ceiling);
+                }
+                _ => self.double_free(),
+            }
+            self.check_stats();
+        }
+
+        // every frame that isn't outstanding, freed ones included, can still
+        // be obtained
+        self.step = STEPS;
+        while let Some(frame) = self.allocator.allocate_frame() {
+            self.hand_out(&frame);
+        }
+        assert!(self.freed.is_empty(),
+            "seed {}: freed frames {:?} were lost", self.seed, self.freed);
+        assert_eq!(self.outstanding.len(), self.total, "seed {}", self.seed);
+        assert_eq!(self.allocator.free_frames(), 0, "seed {}", self.seed);
+    }
+}
+
+fn stress(order: AllocationOrder) {
+    for seed in 0..16 {
+        let mut rng = Rng::new(seed);
+        Harness::new(seed, order).run(&mut rng);
+    }
+}
+
+#[test]
+fn stress_cursor_order() {
+    stress(AllocationOrder::Cursor);
+}
+
+#[test]
+fn stress_ascending_order() {
+    stress(AllocationOrder::Ascending);
+}
+
+#[test]
+fn stress_descending_order() {
+    stress(AllocationOrder::Descending);
+}
@@ -1,15 +1,77 @@
-use memory::{Frame, FrameAllocator};
+use memory::{Frame, FrameAllocator, FrameRange, FrameZone, area_frame_range};
+use memory::paging::PhysicalAddress;
+use memory::reserved_ranges::{ReservedRanges, RangesError};
 use multiboot2::{MemoryAreaIter, MemoryArea};
+#[cfg(feature = "diagnostics")]
+use core::fmt;
 
+/// The number of NUMA node ranges an `AreaFrameAllocator` can track.
+const MAX_NODE_RANGES: usize = 8;
+
+/// The order in which an `AreaFrameAllocator` hands out frames, see
+/// `AreaFrameAllocator::set_allocation_order`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationOrder {
+    /// Reuses the lowest freed frame, otherwise takes the frame at the
+    /// cursor. This is the default.
+    Cursor,
+    /// Hands out the lowest free frame first.
+    Ascending,
+    /// Hands out the highest free frame first.
+    Descending,
+}
+
+/// Where the next frame comes from.
+enum NextFrame {
+    /// A deallocated frame.
+    Freed(Frame),
+    /// The frame at the cursor.
+    Cursor(Frame),
+    /// The highest frame below `top`.
+    Top(Frame),
+}
+
+/// The reasons `AreaFrameAllocator::try_deallocate_frame` can reject the
+/// frame at the given address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeallocError {
+    /// The frame doesn't lie in one of the memory areas.
+    NotManaged(PhysicalAddress),
+    /// The frame isn't allocated, it was never handed out or freed already.
+    NotAllocated(PhysicalAddress),
+    /// The frame is reserved, see `AreaFrameAllocator::release_reserved`.
+    Reserved(PhysicalAddress),
+    /// The frame can't be merged into a range of freed frames and no range is
+    /// left for it.
+    TooManyFreedRanges(PhysicalAddress),
+}
 
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,
     current_area: Option<&'static MemoryArea>,
     areas: MemoryAreaIter,
-    kernel_start: Frame,
-    kernel_end: Frame,
-    multiboot_start: Frame,
-    multiboot_end: Frame,
+    // frames that aren't handed out, e.g. the kernel image
+    reserved: ReservedRanges,
+    // the reserved frames from `carve_reserved`, which are never released
+    carved: ReservedRanges,
+    // deallocated frames, reused before the cursor advances; they are merged
+    // into ranges, so freeing many neighboring frames takes little space
+    freed: ReservedRanges,
+    // frames above the cursor that `allocate_frame_on_node` handed out, the
+    // cursor skips them
+    taken: ReservedRanges,
+    // the number of frames handed out and not yet returned
+    used_frames: usize,
+    // the physical ranges of the NUMA nodes, e.g. from the SRAT
+    node_ranges: [(FrameRange, u8); MAX_NODE_RANGES],
+    node_ranges_len: usize,
+    // called when no frame is left, see `set_reclaim`
+    reclaim: Option<fn(&mut AreaFrameAllocator) -> usize>,
+    reclaiming: bool,
+    order: AllocationOrder,
+    // frame number below which `Descending` hands out frames; the frames from
+    // here on are used or reserved
+    top: usize,
 }
 
 impl AreaFrameAllocator {
@@ -22,73 +84,998 @@ impl AreaFrameAllocator {
             next_free_frame: Frame::containing_address(0),
             current_area: None,
             areas: memory_areas,
-            kernel_start: Frame::containing_address(kernel_start),
-            kernel_end: Frame::containing_address(kernel_end),
-            multiboot_start: Frame::containing_address(multiboot_start),
-            multiboot_end: Frame::containing_address(multiboot_end),
+            reserved: ReservedRanges::new(),
+            carved: ReservedRanges::new(),
+            freed: ReservedRanges::new(),
+            taken: ReservedRanges::new(),
+            used_frames: 0,
+            node_ranges: [(FrameRange { start: 0, end: 0 }, 0); MAX_NODE_RANGES],
+            node_ranges_len: 0,
+            reclaim: None,
+            reclaiming: false,
+            order: AllocationOrder::Cursor,
+            top: usize::max_value(),
         };
+        for &range in &[FrameRange::containing_addresses(kernel_start, kernel_end),
+                        FrameRange::containing_addresses(multiboot_start, multiboot_end)] {
+            allocator.reserve(range).expect("two reserved ranges always fit");
+        }
         allocator.choose_next_area();
         allocator
     }
 
+    /// Excludes the given frames from allocation. Overlapping and adjacent
+    /// reserved ranges are merged, so a frame is never reserved twice. Fails
+    /// without reserving anything if the range would need a slot of its own
+    /// and `MAX_RESERVED_RANGES` ranges are reserved already.
+    pub fn reserve(&mut self, range: FrameRange) -> Result<(), RangesError> {
+        self.reserved.insert(range)
+    }
+
+    /// Makes reserved frames allocatable, e.g. the bootstrap stack, which lies
+    /// in the kernel image. Fails without changing anything if the ranges of
+    /// reserved or freed frames run out.
+    ///
+    /// Panics if the range contains frames from `carve_reserved`.
+    pub fn release_reserved(&mut self, range: FrameRange) -> Result<(), RangesError> {
+        assert!(self.carved.count_in(range) == 0,
+            "carved frames in {:?}..{:?} can't be released", range.start(), range.end());
+
+        let mut reserved = self.reserved;
+        let mut freed = self.freed;
+        try!(reserved.remove(range));
+        // frames at or above the cursor and below `top` are free anyway, the
+        // others are handed out again like deallocated ones
+        let below_cursor = (range.start, ::core::cmp::min(range.end, self.next_free_frame.number));
+        let above_top = (::core::cmp::max(range.start, self.top), range.end);
+        for &(part_start, part_end) in &[below_cursor, above_top] {
+            for area in self.areas.clone() {
+                let area_frames = area_frame_range(area);
+                let start = ::core::cmp::max(part_start, area_frames.start);
+                let end = ::core::cmp::min(part_end, area_frames.end);
+                if start < end {
+                    try!(freed.insert(FrameRange { start: start, end: end }));
+                }
+            }
+        }
+
+        self.reserved = reserved;
+        self.freed = freed;
+        Ok(())
+    }
+
+    /// Returns true if `frame` is reserved, i.e. never handed out.
+    pub fn is_reserved(&self, frame: &Frame) -> bool {
+        self.reserved.contains(frame)
+    }
+
+    /// Reserves `count` contiguous free frames at or above the cursor for a
+    /// structure that is never freed, e.g. a frame bitmap. Unlike other
+    /// reserved frames, they can't be released either, and passing them to
+    /// `deallocate_frame` does nothing. Returns `None` if no memory area has
+    /// enough contiguous free frames left, or if no reserved range is left
+    /// for them.
+    pub fn carve_reserved(&mut self, count: usize) -> Option<FrameRange> {
+        assert!(count > 0, "can't carve out an empty frame range");
+
+        let mut start = self.next_free_frame.number;
+        loop {
+            let area_frames = match self.area_containing_or_above(&Frame { number: start }) {
+                Some(area) => area_frame_range(area),
+                None => return None,
+            };
+            start = ::core::cmp::max(start, area_frames.start);
+            let end = start + count;
+            if end > self.top {
+                return None;
+            }
+            if end > area_frames.end {
+                start = area_frames.end;
+                continue;
+            }
+
+            let overlapping = self.reserved.as_slice().iter()
+                .chain(self.taken.as_slice())
+                .find(|range| range.start < end && range.end > start)
+                .map(|range| range.end);
+            match overlapping {
+                Some(reserved_end) => start = reserved_end,
+                None => {
+                    // the cursor skips reserved frames, so it can stay
+                    let range = FrameRange { start: start, end: end };
+                    let mut carved = self.carved;
+                    if carved.insert(range).is_err() || self.reserve(range).is_err() {
+                        return None;
+                    }
+                    self.carved = carved;
+                    return Some(range);
+                }
+            }
+        }
+    }
+
+    /// Sets a callback that `allocate_frame` invokes once when no frame is
+    /// left, e.g. to drop caches or write out clean pages. It returns the
+    /// number of frames it deallocated; the allocation is retried only if
+    /// that is not zero. While the callback runs, allocations don't reclaim
+    /// again, so an allocating callback can't recurse, but it should not
+    /// allocate at all.
+    pub fn set_reclaim(&mut self, reclaim: fn(&mut AreaFrameAllocator) -> usize) {
+        self.reclaim = Some(reclaim);
+    }
+
+    /// Sets the order in which frames are handed out. `Descending` and
+    /// `Ascending` make fragmentation scenarios reproducible in tests, they
+    /// aren't meant for normal operation.
+    pub fn set_allocation_order(&mut self, order: AllocationOrder) {
+        self.order = order;
+    }
+
+    /// Records that the given frames belong to NUMA node `node`.
+    pub fn add_node_range(&mut self, range: FrameRange, node: u8) {
+        assert!(self.node_ranges_len < MAX_NODE_RANGES,
+            "Area frame allocator can track only {} node ranges.", MAX_NODE_RANGES);
+        self.node_ranges[self.node_ranges_len] = (range, node);
+        self.node_ranges_len += 1;
+    }
+
+    /// Returns the NUMA node of the given frame, if it is known.
+    pub fn node_of(&self, frame: &Frame) -> Option<u8> {
+        self.node_ranges[..self.node_ranges_len].iter()
+            .find(|&&(range, _)| range.contains(frame))
+            .map(|&(_, node)| node)
+    }
+
+    /// Returns true if `frame` lies in a memory area and isn't reserved, i.e.
+    /// if this allocator hands it out.
+    pub fn manages(&self, frame: &Frame) -> bool {
+        !self.reserved.contains(frame) &&
+            self.areas.clone().any(|area| area_frame_range(area).contains(frame))
+    }
+
+    /// Returns the number of usable frames, i.e. the frames of all memory
+    /// areas that aren't reserved.
+    pub fn total_frames(&self) -> usize {
+        self.areas.clone().map(|area| {
+            let area_frames = area_frame_range(area);
+            area_frames.len() - self.reserved.count_in(area_frames)
+        }).sum()
+    }
+
+    /// Returns the number of frames that are currently allocated. Every frame
+    /// that leaves the allocator counts, no matter whether it is used for data
+    /// or a page table, or whether it is part of a huge page.
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    /// Returns the number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.total_frames() - self.used_frames
+    }
+
+    /// Returns the number of frames of the given zone that can still be
+    /// allocated.
+    pub fn free_frames_in_zone(&self, zone: FrameZone) -> usize {
+        let zone_frames = zone.frames();
+        let freed = self.freed.count_in(zone_frames);
+
+        // all frames at or above the cursor that aren't reserved are free
+        let cursor = ::core::cmp::max(self.next_free_frame.number, zone_frames.start);
+        let unused: usize = self.areas.clone().map(|area| {
+            let area_frames = area_frame_range(area);
+            let start = ::core::cmp::max(area_frames.start, cursor);
+            let end = ::core::cmp::min(::core::cmp::min(area_frames.end, zone_frames.end),
+                                       self.top);
+            if end <= start {
+                return 0;
+            }
+            let range = FrameRange { start: start, end: end };
+            end - start - self.reserved.count_in(range) - self.taken.count_in(range)
+        }).sum();
+
+        freed + unused
+    }
+
+    /// Returns the reserved frame ranges in ascending order.
+    pub fn reserved_ranges(&self) -> &[FrameRange] {
+        self.reserved.as_slice()
+    }
+
+    /// Prints the allocator state, to compare it before and after operations
+    /// that are suspected to leak frames.
+    #[cfg(feature = "diagnostics")]
+    pub fn dump_state(&self) {
+        use vga_buffer::WRITER;
+        self.write_state(&mut *WRITER.lock()).unwrap();
+    }
+
+    /// Writes the state printed by `dump_state` to `out`.
+    #[cfg(feature = "diagnostics")]
+    fn write_state<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        try!(writeln!(out, "area frame allocator: next free frame: {:?}", self.next_free_frame));
+        match self.current_area {
+            Some(area) => try!(writeln!(out, "  current area: {:#x}, length: {:#x}",
+                area.base_addr, area.length)),
+            None => try!(writeln!(out, "  current area: none")),
+        }
+        for range in self.reserved_ranges() {
+            try!(writeln!(out, "  reserved: {:?}..{:?}", range.start(), range.end()));
+        }
+        try!(writeln!(out, "  freed ({} frames):", self.freed.frame_count()));
+        for range in self.freed.as_slice() {
+            try!(writeln!(out, "    {:?}..{:?}", range.start(), range.end()));
+        }
+        for range in self.taken.as_slice() {
+            try!(writeln!(out, "  taken for a node: {:?}..{:?}", range.start(), range.end()));
+        }
+        writeln!(out, "  used: {}, free: {}, total: {}",
+            self.used_frames(), self.free_frames(), self.total_frames())
+    }
+
     fn choose_next_area(&mut self) {
-        self.current_area = self.areas.clone().filter(|area| {
-            let address = area.base_addr + area.length - 1;
-            Frame::containing_address(address as usize) >= self.next_free_frame
-        }).min_by_key(|area| area.base_addr);
+        self.current_area = self.area_containing_or_above(&self.next_free_frame);
 
         if let Some(area) = self.current_area {
-            let start_frame = Frame::containing_address(area.base_addr as usize);
+            let start_frame = area_frame_range(area).start();
             if self.next_free_frame < start_frame {
                 self.next_free_frame = start_frame;
             }
         }
     }
 
+    /// Returns the lowest memory area with a usable frame at or above `frame`.
+    fn area_containing_or_above(&self, frame: &Frame) -> Option<&'static MemoryArea> {
+        self.areas.clone().filter(|area| {
+            area_frame_range(area).end() > *frame
+        }).min_by_key(|area| area.base_addr)
+    }
+
+    /// Finds the frame the next call to `allocate_frame` hands out, together
+    /// with the memory area it lies in. The allocator state is not modified.
+    fn find_free_frame(&self) -> Option<(Frame, &'static MemoryArea)> {
+        let mut frame = self.next_free_frame.clone();
+        let mut current_area = self.current_area;
+
+        while let Some(area) = current_area {
+            if frame.number >= self.top {
+                // handed out by `Descending` already
+                return None;
+            } else if frame >= area_frame_range(area).end() {
+                // all frames of current area are used, switch to next area
+                current_area = self.area_containing_or_above(&frame);
+                if let Some(area) = current_area {
+                    let start_frame = area_frame_range(area).start();
+                    if frame < start_frame {
+                        frame = start_frame;
+                    }
+                }
+            } else if self.reserved.contains(&frame) {
+                // `frame` is reserved, e.g. used by the kernel or the multiboot
+                // information structure
+                frame = self.reserved.next_free_above(frame);
+            } else if self.taken.contains(&frame) {
+                frame = self.taken.next_free_above(frame);
+            } else {
+                // frame is unused
+                return Some((frame, area));
+            }
+        }
+
+        None // no free frames left
+    }
+
+    /// Finds the highest free frame below `top` and at or above the cursor.
+    /// The allocator state is not modified.
+    fn find_free_frame_below_top(&self) -> Option<Frame> {
+        let mut end = self.top;
+        loop {
+            let area = match self.areas.clone()
+                .filter(|area| area_frame_range(area).start < end)
+                .max_by_key(|area| area.base_addr)
+            {
+                Some(area) => area_frame_range(area),
+                None => return None,
+            };
+            end = ::core::cmp::min(end, area.end);
+            if end <= area.start {
+                end = area.start;
+                continue;
+            }
+
+            let frame = Frame { number: end - 1 };
+            if frame < self.next_free_frame {
+                return None;
+            }
+            match self.reserved.range_containing(&frame)
+                .or_else(|| self.taken.range_containing(&frame))
+            {
+                Some(range) => end = range.start,
+                None => return Some(frame),
+            }
+        }
+    }
+
+    /// Decides where the next frame comes from, depending on the allocation
+    /// order.
+    fn next_frame(&self) -> Option<NextFrame> {
+        match self.order {
+            AllocationOrder::Cursor => match self.freed.first() {
+                Some(frame) => Some(NextFrame::Freed(frame)),
+                None => self.find_free_frame().map(|(frame, _)| NextFrame::Cursor(frame)),
+            },
+            AllocationOrder::Ascending => {
+                let lowest = self.freed.first().map(|frame| frame.number);
+                let cursor = self.find_free_frame().map(|(frame, _)| frame);
+                match lowest {
+                    Some(number) if cursor.as_ref().map_or(true, |frame| frame.number > number) => {
+                        Some(NextFrame::Freed(Frame { number: number }))
+                    }
+                    _ => cursor.map(NextFrame::Cursor),
+                }
+            }
+            AllocationOrder::Descending => {
+                let highest = self.freed.last().map(|frame| frame.number);
+                let top = self.find_free_frame_below_top();
+                match highest {
+                    Some(number) if top.as_ref().map_or(true, |frame| frame.number < number) => {
+                        Some(NextFrame::Freed(Frame { number: number }))
+                    }
+                    _ => top.map(NextFrame::Top),
+                }
+            }
+        }
+    }
+
+    fn allocate_without_reclaim(&mut self) -> Option<Frame> {
+        match self.next_frame() {
+            Some(NextFrame::Freed(frame)) => {
+                self.take_freed(&frame);
+                Some(frame)
+            }
+            Some(NextFrame::Cursor(_)) | None => self.allocate_at_cursor(),
+            Some(NextFrame::Top(frame)) => {
+                self.top = frame.number;
+                self.taken.remove(FrameRange { start: self.top, end: usize::max_value() })
+                    .expect("removing a suffix split a range");
+                self.used_frames += 1;
+                Some(frame)
+            }
+        }
+    }
+
+    /// Hands out the lowest freed frame within `range`. Returns `None` if
+    /// there is none, or if taking it would split a freed range and none is
+    /// left for that.
+    fn take_freed_in(&mut self, range: FrameRange) -> Option<Frame> {
+        let number = self.freed.as_slice().iter()
+            .map(|freed| ::core::cmp::max(freed.start, range.start))
+            .find(|&number| range.contains(&Frame { number: number }) &&
+                            self.freed.contains(&Frame { number: number }));
+        let number = match number {
+            Some(number) => number,
+            None => return None,
+        };
+        match self.freed.remove(FrameRange { start: number, end: number + 1 }) {
+            Ok(()) => {
+                self.used_frames += 1;
+                Some(Frame { number: number })
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Hands out the lowest free frame at or above the cursor within `range`.
+    /// Returns `None` if there is none, or if the frame is above the cursor
+    /// and no range is left to record it.
+    fn take_free_in(&mut self, range: FrameRange) -> Option<Frame> {
+        let mut number = ::core::cmp::max(range.start, self.next_free_frame.number);
+        let end = ::core::cmp::min(range.end, self.top);
+        let frame = loop {
+            if number >= end {
+                return None;
+            }
+            let frame = Frame { number: number };
+            let area_start = match self.area_containing_or_above(&frame) {
+                Some(area) => area_frame_range(area).start,
+                None => return None,
+            };
+            if number < area_start {
+                number = area_start;
+            } else if self.reserved.contains(&frame) {
+                number = self.reserved.next_free_above(frame).number;
+            } else if self.taken.contains(&frame) {
+                number = self.taken.next_free_above(frame).number;
+            } else {
+                break frame;
+            }
+        };
+
+        if self.find_free_frame().map_or(false, |(at_cursor, _)| at_cursor == frame) {
+            return self.allocate_at_cursor();
+        }
+        match self.taken.insert(FrameRange { start: frame.number, end: frame.number + 1 }) {
+            Ok(()) => {
+                self.used_frames += 1;
+                Some(frame)
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Removes `frame` from the freed frames and counts it as used. The frame
+    /// must be the first or last freed frame of its range, so that the range
+    /// isn't split.
+    fn take_freed(&mut self, frame: &Frame) {
+        self.freed.remove(FrameRange { start: frame.number, end: frame.number + 1 })
+            .expect("taking a freed frame split its range");
+        self.used_frames += 1;
+    }
+
+    /// Returns `frame` to the allocator. Unlike `deallocate_frame`, this fails
+    /// without changing anything when `frame` doesn't belong to any memory
+    /// area, when it is reserved or isn't allocated, e.g. because it was
+    /// freed already, or when it can't be merged with other freed frames and
+    /// `MAX_RESERVED_RANGES` ranges of them exist already. Frames from
+    /// `carve_reserved` are accepted and stay reserved.
+    pub fn try_deallocate_frame(&mut self, frame: Frame) -> Result<(), DeallocError> {
+        let address = frame.start_address();
+        if self.carved.contains(&frame) {
+            return Ok(());
+        }
+        if self.reserved.contains(&frame) {
+            // reserved frames are released with `release_reserved`
+            return Err(DeallocError::Reserved(address));
+        }
+        if !self.areas.clone().any(|area| area_frame_range(area).contains(&frame)) {
+            return Err(DeallocError::NotManaged(address));
+        }
+        let range = FrameRange { start: frame.number, end: frame.number + 1 };
+        if self.taken.contains(&frame) {
+            // above the cursor, so it is free again once it isn't taken
+            try!(self.taken.remove(range)
+                .map_err(|_| DeallocError::TooManyFreedRanges(address)));
+            self.used_frames -= 1;
+            // the cursor gives up its area once everything above it is taken
+            if self.current_area.is_none() {
+                self.choose_next_area();
+            }
+            return Ok(());
+        }
+        let never_allocated = frame >= self.next_free_frame && frame.number < self.top;
+        if never_allocated || self.freed.contains(&frame) {
+            return Err(DeallocError::NotAllocated(address));
+        }
+
+        try!(self.freed.insert(range).map_err(|_| DeallocError::TooManyFreedRanges(address)));
+        debug_assert!(self.used_frames > 0, "deallocating more frames than allocated");
+        self.used_frames -= 1;
+        Ok(())
+    }
+
+    /// Returns true if the frame with the given number, which must lie in a
+    /// memory area, can be handed out: it is freed, or at or above the cursor
+    /// and below `top` and neither reserved nor taken.
+    fn is_free(&self, number: usize) -> bool {
+        let frame = Frame { number: number };
+        if self.freed.contains(&frame) {
+            return true;
+        }
+        frame >= self.next_free_frame && number < self.top &&
+            !self.reserved.contains(&frame) && !self.taken.contains(&frame)
+    }
+
+    /// Returns the lowest `count` free frames in a row that lie in one memory
+    /// area and end at or below `ceiling`. The allocator state is not
+    /// modified.
+    fn find_free_run(&self, count: usize, ceiling: &Frame) -> Option<FrameRange> {
+        let mut lowest: Option<FrameRange> = None;
+        for area in self.areas.clone() {
+            let area_frames = area_frame_range(area);
+            let end = ::core::cmp::min(area_frames.end, ceiling.number);
+            let mut start = area_frames.start;
+            while start + count <= end {
+                // continue after the last frame that is in use
+                match (start..start + count).rev().find(|&number| !self.is_free(number)) {
+                    Some(used) => start = used + 1,
+                    None => break,
+                }
+            }
+            if start + count <= end && lowest.map_or(true, |range| start < range.start) {
+                lowest = Some(FrameRange { start: start, end: start + count });
+            }
+        }
+        lowest
+    }
+
+    /// Hands out the next frame at or above the cursor and moves the cursor
+    /// past it.
+    fn allocate_at_cursor(&mut self) -> Option<Frame> {
+        match self.find_free_frame() {
+            Some((frame, area)) => {
+                // move the cursor past the returned frame
+                self.current_area = Some(area);
+                self.next_free_frame = Frame { number: frame.number + 1 };
+                self.taken.remove(FrameRange { start: 0, end: self.next_free_frame.number })
+                    .expect("removing a prefix split a range");
+                self.used_frames += 1;
+                Some(frame)
+            }
+            None => {
+                self.current_area = None;
+                None
+            }
+        }
+    }
+
 }
 
 impl FrameAllocator for AreaFrameAllocator {
     
     fn allocate_frame(&mut self) -> Option<Frame> {
-        if let Some(area) = self.current_area {
-            // "clone" the frame to return it if it's free. Frame doesn't
-            // implement Clone, but we can construct an identical frame.
-            let frame = Frame{ number: self.next_free_frame.number };
-
-            // the last frame of the current area
-            let current_area_last_frame = {
-                let address = area.base_addr + area.length - 1;
-                Frame::containing_address(address as usize)
-            };
+        if let Some(frame) = self.allocate_without_reclaim() {
+            return Some(frame);
+        }
 
-            if frame > current_area_last_frame {
-                // all frames of current area are used, switch to next area
-                self.choose_next_area();
-            } else if frame >= self.kernel_start && frame <= self.kernel_end {
-                // `frame` is used by the kernel
-                self.next_free_frame = Frame {
-                    number: self.kernel_end.number + 1
-                };
-            } else if frame >= self.multiboot_start && frame <= self.multiboot_end {
-                // `frame` is used by the multiboot information structure
-                self.next_free_frame = Frame {
-                    number: self.multiboot_end.number + 1
-                };
-            } else {
-                // frame is unused, increment `next_free_frame` and return it
-                self.next_free_frame.number += 1;
+        match self.reclaim {
+            Some(reclaim) if !self.reclaiming => {
+                self.reclaiming = true;
+                let freed = reclaim(self);
+                self.reclaiming = false;
+                if freed > 0 {
+                    self.allocate_without_reclaim()
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn peek_next_frame(&self) -> Option<Frame> {
+        self.next_frame().map(|next| match next {
+            NextFrame::Freed(frame) | NextFrame::Cursor(frame) | NextFrame::Top(frame) => frame,
+        })
+    }
+
+    /// Searches the ranges of `node` for a free frame, preferring freed ones,
+    /// and hands out the lowest one found. A frame above the cursor is
+    /// recorded, so that the cursor skips it later. Once the node is full,
+    /// this falls back to `allocate_frame`, so it never fails while any frame
+    /// is left.
+    fn allocate_frame_on_node(&mut self, node: u8) -> Option<Frame> {
+        let node_ranges = self.node_ranges;
+        for &(range, _) in node_ranges[..self.node_ranges_len].iter()
+            .filter(|&&(_, range_node)| range_node == node)
+        {
+            if let Some(frame) = self.take_freed_in(range) {
                 return Some(frame);
             }
-            // `frame` was not valid, try it again with the updated `next_free_frame`
-            self.allocate_frame()
-        } else {
-            None // no free frames left
+            if let Some(frame) = self.take_free_in(range) {
+                return Some(frame);
+            }
+        }
+        self.allocate_frame()
+    }
+
+    /// Searches all memory areas for the lowest run of `count` free frames
+    /// below `ceiling`, so unlike the default implementation, freed frames
+    /// don't get in the way. The frames of the run that were freed are taken
+    /// out of the freed ranges, the ones above the cursor are recorded as
+    /// taken. Returns `None` if there is no such run, or if no range is left
+    /// to record it.
+    fn allocate_contiguous(&mut self, count: usize, ceiling: &Frame) -> Option<FrameRange> {
+        assert!(count > 0, "can't allocate an empty frame range");
+
+        let run = match self.find_free_run(count, ceiling) {
+            Some(run) => run,
+            None => return None,
+        };
+        // freed frames lie below the cursor or at or above `top`, the other
+        // free frames in between
+        let mut freed = self.freed;
+        let mut taken = self.taken;
+        for range in self.freed.as_slice() {
+            let start = ::core::cmp::max(range.start, run.start);
+            let end = ::core::cmp::min(range.end, run.end);
+            if start < end && freed.remove(FrameRange { start: start, end: end }).is_err() {
+                return None;
+            }
         }
+        let start = ::core::cmp::max(run.start, self.next_free_frame.number);
+        let end = ::core::cmp::min(run.end, self.top);
+        if start < end && taken.insert(FrameRange { start: start, end: end }).is_err() {
+            return None;
+        }
+
+        self.freed = freed;
+        self.taken = taken;
+        self.used_frames += count;
+        Some(run)
     }
 
+    /// Panics if `try_deallocate_frame` fails.
     fn deallocate_frame(&mut self, frame: Frame) {
-        // TODO: Implement deallocate_frame.
-        unimplemented!()
+        if let Err(error) = self.try_deallocate_frame(frame) {
+            panic!("invalid frame deallocation: {:?}", error);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::reserved_ranges::MAX_RESERVED_RANGES;
+    use memory::test_support::small_allocator as allocator;
+
+    fn frame(number: usize) -> Frame {
+        Frame { number: number }
+    }
+
+    #[test]
+    fn freed_frames_are_reused() {
+        let mut allocator = allocator();
+        let first = allocator.allocate_frame().unwrap();
+        let second = allocator.allocate_frame().unwrap();
+        assert_eq!(first, frame(0x105));
+        assert_eq!(second, frame(0x106));
+
+        allocator.deallocate_frame(first);
+        assert_eq!(allocator.used_frames(), 1);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x105)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x107)));
+    }
+
+    #[test]
+    fn freeing_a_huge_page_worth_of_frames_merges_them() {
+        let mut allocator = allocator();
+        let frames: Vec<Frame> = (0..512).map(|_| allocator.allocate_frame().unwrap()).collect();
+        let free_before = allocator.free_frames();
+
+        for frame in frames {
+            allocator.deallocate_frame(frame);
+        }
+
+        assert_eq!(allocator.used_frames(), 0);
+        assert_eq!(allocator.free_frames(), free_before + 512);
+        assert_eq!(allocator.freed.as_slice(), &[FrameRange { start: 0x105, end: 0x305 }]);
+        for number in 0x105..0x305 {
+            assert_eq!(allocator.allocate_frame(), Some(frame(number)));
+        }
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut allocator = allocator();
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.allocate_frame().unwrap();
+        let address = frame.start_address();
+
+        allocator.try_deallocate_frame(frame.clone()).unwrap();
+        assert_eq!(allocator.try_deallocate_frame(frame), Err(DeallocError::NotAllocated(address)));
+        assert_eq!(allocator.used_frames(), 1);
+    }
+
+    #[test]
+    fn frames_that_were_never_handed_out_are_rejected() {
+        let mut allocator = allocator();
+        allocator.allocate_frame().unwrap();
+
+        assert_eq!(allocator.try_deallocate_frame(frame(0x200)),
+                   Err(DeallocError::NotAllocated(0x20_0000)));
+        assert_eq!(allocator.try_deallocate_frame(frame(0x10_0000)),
+                   Err(DeallocError::NotManaged(0x1_0000_0000)));
+        assert_eq!(allocator.used_frames(), 1);
+    }
+
+    #[test]
+    fn fragmented_frees_fail_once_freed_ranges_run_out() {
+        let mut allocator = allocator();
+        let count = 2 * MAX_RESERVED_RANGES + 2;
+        let frames: Vec<Frame> = (0..count).map(|_| allocator.allocate_frame().unwrap()).collect();
+
+        // every other frame, so that no two freed frames are adjacent
+        let mut frames = frames.into_iter().step_by(2);
+        for frame in frames.by_ref().take(MAX_RESERVED_RANGES) {
+            allocator.try_deallocate_frame(frame).unwrap();
+        }
+        let used = allocator.used_frames();
+        let frame = frames.next().unwrap();
+        let address = frame.start_address();
+
+        assert_eq!(allocator.try_deallocate_frame(frame),
+                   Err(DeallocError::TooManyFreedRanges(address)));
+        assert_eq!(allocator.used_frames(), used);
+        // a frame next to a freed one is merged
+        allocator.try_deallocate_frame(Frame { number: 0x106 }).unwrap();
+    }
+
+    #[test]
+    fn carved_frames_are_never_handed_out_again() {
+        let mut allocator = allocator();
+        let allocated = allocator.allocate_frame().unwrap();
+        let carved = allocator.carve_reserved(4).unwrap();
+        assert_eq!(carved, FrameRange { start: 0x106, end: 0x10a });
+        let free_before = allocator.free_frames();
+
+        for frame in carved.iter() {
+            allocator.deallocate_frame(frame);
+        }
+        allocator.deallocate_frame(allocated);
+
+        assert_eq!(allocator.free_frames(), free_before + 1);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x105)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x10a)));
+        assert!(allocator.is_reserved(&frame(0x106)));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be released")]
+    fn carved_frames_cant_be_released() {
+        let mut allocator = allocator();
+        let carved = allocator.carve_reserved(4).unwrap();
+        let _ = allocator.release_reserved(carved);
+    }
+
+    #[test]
+    fn reserved_frames_are_rejected_until_released() {
+        let mut allocator = allocator();
+        // part of the kernel image
+        assert_eq!(allocator.try_deallocate_frame(frame(0x101)),
+                   Err(DeallocError::Reserved(0x10_1000)));
+
+        let total_before = allocator.total_frames();
+        allocator.release_reserved(FrameRange { start: 0x101, end: 0x103 }).unwrap();
+
+        assert_eq!(allocator.total_frames(), total_before + 2);
+        assert_eq!(allocator.used_frames(), 0);
+        assert_eq!(allocator.reserved_ranges(),
+                   &[FrameRange { start: 0x100, end: 0x101 },
+                     FrameRange { start: 0x103, end: 0x105 }]);
+        // the cursor passed the released frames already
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x101)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x102)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x105)));
+    }
+
+    #[test]
+    #[cfg(feature = "diagnostics")]
+    fn state_dump_reports_the_freed_frames() {
+        let mut allocator = allocator();
+        let frames: Vec<Frame> = (0..6).map(|_| allocator.allocate_frame().unwrap()).collect();
+        for frame in frames.into_iter().step_by(2) {
+            allocator.deallocate_frame(frame);
+        }
+
+        let mut state = String::new();
+        allocator.write_state(&mut state).unwrap();
+        assert!(state.contains("  freed (3 frames):\n"));
+        assert_eq!(state.lines().filter(|line| line.starts_with("    ")).count(), 3);
+        assert!(state.contains("  used: 3, "));
     }
 
+    #[test]
+    fn peeking_skips_a_reserved_range_without_consuming_the_frame() {
+        let mut allocator = allocator();
+        allocator.reserve(FrameRange { start: 0x107, end: 0x10a }).unwrap();
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x105)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x106)));
+
+        assert_eq!(allocator.peek_next_frame(), Some(frame(0x10a)));
+        assert_eq!(allocator.peek_next_frame(), Some(frame(0x10a)));
+        assert_eq!(allocator.used_frames(), 2);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x10a)));
+        assert_eq!(allocator.peek_next_frame(), Some(frame(0x10b)));
+    }
+
+    #[test]
+    fn released_frames_below_the_cursor_are_reused() {
+        let mut allocator = allocator();
+        for _ in 0..3 {
+            allocator.allocate_frame().unwrap();
+        }
+
+        allocator.release_reserved(FrameRange { start: 0x101, end: 0x103 }).unwrap();
+
+        assert_eq!(allocator.used_frames(), 3);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x101)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x102)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x108)));
+    }
+
+    #[test]
+    fn reclaimed_boot_stack_in_the_kernel_image_becomes_allocatable() {
+        let mut allocator = allocator();
+        let free_before = allocator.free_frames();
+
+        // e.g. in the kernel's .bss
+        let stack = FrameRange { start: 0x102, end: 0x104 };
+        unsafe { ::memory::reclaim_old_stack(stack, &mut allocator) };
+
+        assert_eq!(allocator.free_frames(), free_before + 2);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x102)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x103)));
+    }
+
+    fn two_node_allocator() -> AreaFrameAllocator {
+        let mut allocator = allocator();
+        allocator.add_node_range(FrameRange { start: 0x100, end: 0x4f0 }, 0);
+        allocator.add_node_range(FrameRange { start: 0x4f0, end: 0x500 }, 1);
+        allocator
+    }
+
+    #[test]
+    fn node_allocation_stays_on_the_node_until_it_is_full() {
+        let mut allocator = two_node_allocator();
+        let free_before = allocator.free_frames();
+
+        for number in 0x4f0..0x500 {
+            assert_eq!(allocator.allocate_frame_on_node(1), Some(frame(number)));
+        }
+        // node 1 is full, fall back to the cursor
+        assert_eq!(allocator.allocate_frame_on_node(1), Some(frame(0x105)));
+        assert_eq!(allocator.allocate_frame_on_node(0), Some(frame(0x106)));
+
+        assert_eq!(allocator.used_frames(), 18);
+        assert_eq!(allocator.free_frames(), free_before - 18);
+        assert_eq!(allocator.free_frames_in_zone(FrameZone::Dma), free_before - 18);
+    }
+
+    #[test]
+    fn cursor_skips_frames_taken_for_a_node() {
+        let mut allocator = two_node_allocator();
+        allocator.allocate_frame_on_node(1).unwrap();
+
+        let mut count = 0;
+        while let Some(frame) = allocator.allocate_frame() {
+            assert!(frame != Frame { number: 0x4f0 });
+            count += 1;
+        }
+        assert_eq!(count, 0x500 - 0x105 - 1);
+        assert_eq!(allocator.free_frames(), 0);
+        assert!(allocator.taken.as_slice().is_empty());
+    }
+
+    #[test]
+    fn freed_node_frames_are_reused_on_the_node() {
+        let mut allocator = two_node_allocator();
+        let first = allocator.allocate_frame_on_node(1).unwrap();
+        allocator.allocate_frame_on_node(1).unwrap();
+        let node_0 = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(node_0);
+
+        assert_eq!(allocator.allocate_frame_on_node(1), Some(frame(0x4f0)));
+        assert_eq!(allocator.allocate_frame_on_node(0), Some(frame(0x105)));
+        assert_eq!(allocator.used_frames(), 3);
+        assert_eq!(allocator.try_deallocate_frame(frame(0x4f2)),
+                   Err(DeallocError::NotAllocated(0x4f_2000)));
+    }
+
+    #[test]
+    fn reclaimed_stack_frames_become_allocatable() {
+        let mut allocator = allocator();
+        let stack = FrameRange { start: 0x105, end: 0x109 };
+        for _ in stack.iter() {
+            allocator.allocate_frame().unwrap();
+        }
+        assert_eq!(allocator.used_frames(), 4);
+
+        unsafe { ::memory::reclaim_old_stack(stack, &mut allocator) };
+
+        assert_eq!(allocator.used_frames(), 0);
+        for number in 0x105..0x109 {
+            assert_eq!(allocator.allocate_frame(), Some(frame(number)));
+        }
+    }
+
+    fn free_first_frame(allocator: &mut AreaFrameAllocator) -> usize {
+        allocator.deallocate_frame(frame(0x105));
+        1
+    }
+
+    fn free_nothing(_: &mut AreaFrameAllocator) -> usize {
+        0
+    }
+
+    #[test]
+    fn allocation_is_retried_after_the_reclaim_callback_frees_a_frame() {
+        let mut allocator = allocator();
+        while allocator.allocate_frame().is_some() {}
+        allocator.set_reclaim(free_first_frame);
+
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x105)));
+    }
+
+    #[test]
+    fn allocation_fails_if_the_reclaim_callback_frees_nothing() {
+        let mut allocator = allocator();
+        while allocator.allocate_frame().is_some() {}
+        allocator.set_reclaim(free_nothing);
+
+        assert_eq!(allocator.allocate_frame(), None);
+    }
+
+    #[test]
+    fn descending_order_skips_reserved_frames() {
+        let mut allocator = allocator();
+        allocator.reserve(FrameRange { start: 0x4fc, end: 0x4fe }).unwrap();
+        allocator.set_allocation_order(AllocationOrder::Descending);
+
+        let frames: Vec<Frame> = (0..4).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert_eq!(frames, vec![frame(0x4ff), frame(0x4fe), frame(0x4fb), frame(0x4fa)]);
+    }
+
+    #[test]
+    fn descending_order_reuses_freed_frames() {
+        let mut allocator = allocator();
+        allocator.set_allocation_order(AllocationOrder::Descending);
+        let first = allocator.allocate_frame().unwrap();
+        let second = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(first);
+        assert_eq!(allocator.used_frames(), 1);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x4ff)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x4fd)));
+        allocator.deallocate_frame(second);
+        assert_eq!(allocator.used_frames(), 2);
+    }
+
+    #[test]
+    fn contiguous_frames_are_found_after_a_free() {
+        let mut allocator = allocator();
+        let ceiling = frame(0x500);
+        let first = allocator.allocate_frame().unwrap();
+        allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(first);
+
+        // the freed frame is too short a run, so the cursor frames are used
+        let range = allocator.allocate_contiguous(3, &ceiling).unwrap();
+        assert_eq!((range.start, range.end), (0x107, 0x10a));
+        assert_eq!(allocator.used_frames(), 4);
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x105)));
+        assert_eq!(allocator.allocate_frame(), Some(frame(0x10a)));
+    }
+
+    #[test]
+    fn contiguous_frames_reuse_a_long_enough_freed_run() {
+        let mut allocator = allocator();
+        let ceiling = frame(0x500);
+        let frames: Vec<Frame> = (0..4).map(|_| allocator.allocate_frame().unwrap()).collect();
+        for frame in frames.into_iter().take(3) {
+            allocator.deallocate_frame(frame);
+        }
+
+        let range = allocator.allocate_contiguous(3, &ceiling).unwrap();
+        assert_eq!((range.start, range.end), (0x105, 0x108));
+        assert_eq!(allocator.used_frames(), 4);
+        for frame in range.iter() {
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(allocator.used_frames(), 1);
+    }
+
+    #[test]
+    fn contiguous_frames_stay_below_the_ceiling() {
+        let mut allocator = allocator();
+        for _ in 0..4 {
+            allocator.allocate_frame().unwrap();
+        }
+        let range = allocator.allocate_contiguous(4, &frame(0x110)).unwrap();
+        assert_eq!((range.start, range.end), (0x109, 0x10d));
+        // only three frames are left below the ceiling
+        assert_eq!(allocator.allocate_contiguous(4, &frame(0x110)), None);
+        assert_eq!(allocator.used_frames(), 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "reclaiming the stack in use")]
+    fn reclaiming_the_current_stack_panics() {
+        let mut allocator = allocator();
+        let stack_frame = Frame::containing_address(::hw::stack_pointer());
+        let stack = FrameRange { start: stack_frame.number - 1, end: stack_frame.number + 1 };
+        unsafe { ::memory::reclaim_old_stack(stack, &mut allocator) };
+    }
 }
@@ -0,0 +1,92 @@
+use memory::{PAGE_SIZE, Frame, AreaFrameAllocator};
+use memory::paging::ActivePageTable;
+use hw;
+
+/// The frame counts compared by `audit_memory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditResult {
+    /// Allocatable frames that are mapped, counted once per mapping.
+    pub mapped_frames: usize,
+    /// Allocatable frames used for the active page tables, including P4.
+    pub table_frames: usize,
+    /// The allocator's `used_frames`.
+    pub used_frames: usize,
+    /// Frames that are allocated, but neither mapped nor used as page table.
+    pub externally_held: usize,
+}
+
+impl AuditResult {
+    /// Returns true if every allocated frame is accounted for exactly once.
+    pub fn is_consistent(&self) -> bool {
+        self.mapped_frames + self.table_frames + self.externally_held == self.used_frames
+    }
+}
+
+/// Compares the frames the allocator handed out with the frames the active
+/// page table references. Frames the allocator doesn't manage, e.g. the
+/// identity mapped kernel, are ignored. `externally_held` is the number of
+/// allocated frames that aren't reachable through the page tables, e.g. the
+/// frames a `TemporaryPage` holds back.
+///
+/// A mapped frame is counted once per page mapping it, so an inconsistent
+/// result means that a frame was leaked, freed while still mapped, or mapped
+/// twice. This walks all mappings and is meant for debug builds.
+pub fn audit_memory(active_table: &ActivePageTable,
+                    allocator: &AreaFrameAllocator,
+                    externally_held: usize)
+                    -> AuditResult
+{
+    let mapped_frames = active_table.iter_mappings().map(|mapping| {
+        let first = mapping.frame / PAGE_SIZE;
+        (first..first + mapping.size.bytes() / PAGE_SIZE)
+            .filter(|&number| allocator.manages(&Frame { number: number }))
+            .count()
+    }).sum();
+
+    let p4_frame = Frame::containing_address(hw::read_cr3());
+    let table_frames = active_table.count_table_frames(|frame| allocator.manages(frame))
+        + if allocator.manages(&p4_frame) { 1 } else { 0 };
+
+    AuditResult {
+        mapped_frames: mapped_frames,
+        table_frames: table_frames,
+        used_frames: allocator.used_frames(),
+        externally_held: externally_held,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::FrameAllocator;
+    use memory::paging::{Page, WRITABLE};
+    use memory::test_support::{small_allocator, active_table};
+
+    #[test]
+    fn leaked_and_unaccounted_frames_are_detected() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        active_table.map(Page::containing_address(0x40_0000), WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        let result = audit_memory(&active_table, &allocator, 0);
+        // the data frame, P4, P3, P2 and P1
+        assert_eq!((result.mapped_frames, result.table_frames), (1, 4));
+        assert!(result.is_consistent());
+
+        // allocated, but neither mapped nor known to be held
+        let leaked = allocator.allocate_frame().unwrap();
+        assert!(!audit_memory(&active_table, &allocator, 0).is_consistent());
+        assert!(audit_memory(&active_table, &allocator, 1).is_consistent());
+
+        // mapped, but never handed out by the allocator
+        let unaccounted = Frame { number: leaked.number + 1 };
+        assert!(allocator.manages(&unaccounted));
+        active_table.map_to(Page::containing_address(0x40_1000), unaccounted, WRITABLE,
+                            &mut allocator)
+            .flush(&active_table);
+        let result = audit_memory(&active_table, &allocator, 1);
+        assert_eq!(result.mapped_frames, 2);
+        assert!(!result.is_consistent());
+    }
+}
@@ -0,0 +1,114 @@
+use super::paging::{Page, ActivePageTable, VirtualAddress, NO_EXECUTE};
+use super::{FrameRange, FrameAllocator};
+use multiboot2::BootInformation;
+use core::str;
+
+
+/// The multiboot2 tags containing the kernel command line and the name of the
+/// boot loader as NUL terminated strings.
+const COMMAND_LINE_TAG: u32 = 1;
+const BOOT_LOADER_NAME_TAG: u32 = 2;
+
+/// The strings the boot loader passed in the multiboot information. They are
+/// empty if the boot loader didn't pass them.
+#[derive(Debug, Clone, Copy)]
+pub struct BootStrings {
+    pub cmdline: &'static str,
+    pub bootloader: &'static str,
+}
+
+/// Identity maps the command line and boot loader name tags read-only, unless
+/// they are mapped already, and returns the strings they contain. The
+/// multiboot information is never freed, so the strings live forever.
+pub fn map_boot_strings<A>(boot_info: &BootInformation,
+                           active_table: &mut ActivePageTable,
+                           allocator: &mut A)
+                           -> BootStrings
+    where A: FrameAllocator
+{
+    BootStrings {
+        cmdline: map_string_tag(boot_info, COMMAND_LINE_TAG, active_table, allocator),
+        bootloader: map_string_tag(boot_info, BOOT_LOADER_NAME_TAG, active_table, allocator),
+    }
+}
+
+fn map_string_tag<A>(boot_info: &BootInformation,
+                     typ: u32,
+                     active_table: &mut ActivePageTable,
+                     allocator: &mut A)
+                     -> &'static str
+    where A: FrameAllocator
+{
+    let (tag_address, size) = match find_tag(boot_info, typ) {
+        Some(tag) => tag,
+        None => return "",
+    };
+    identity_map_read_only(tag_address, size, active_table, allocator);
+    unsafe { tag_string(tag_address, size) }
+}
+
+/// Returns the NUL terminated string of the string tag at `tag_address`,
+/// which must be mapped.
+unsafe fn tag_string(tag_address: VirtualAddress, size: usize) -> &'static str {
+    // the string follows the type and size fields
+    let bytes = ::core::slice::from_raw_parts((tag_address + 8) as *const u8,
+                                              size.saturating_sub(8));
+    let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+/// Returns the address and size of the first tag of the given type.
+fn find_tag(boot_info: &BootInformation, typ: u32) -> Option<(VirtualAddress, usize)> {
+    // the tags follow the 8 byte header and are 8 byte aligned
+    let mut tag_address = boot_info.start_address() + 8;
+    while tag_address < boot_info.end_address() {
+        let (tag_typ, size) = unsafe {
+            (*(tag_address as *const u32), *((tag_address + 4) as *const u32) as usize)
+        };
+        match tag_typ {
+            0 => break, // end tag
+            _ if tag_typ == typ => return Some((tag_address, size)),
+            _ => {}
+        }
+        tag_address += (size + 7) & !7;
+    }
+    None
+}
+
+fn identity_map_read_only<A>(start: VirtualAddress,
+                             size: usize,
+                             active_table: &mut ActivePageTable,
+                             allocator: &mut A)
+    where A: FrameAllocator
+{
+    for frame in FrameRange::containing_addresses(start, start + size).iter() {
+        let page = Page::containing_address(frame.start_address());
+        if active_table.translate_page(page).is_none() {
+            active_table.identity_map(frame, NO_EXECUTE, allocator).flush(active_table);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::boot_information;
+
+    #[test]
+    fn command_line_tag_string_is_read() {
+        let boot_info = boot_information(&[(BOOT_LOADER_NAME_TAG, b"GRUB 2.02\0"),
+                                           (COMMAND_LINE_TAG, b"console=ttyS0 quiet\0")]);
+
+        let (address, size) = find_tag(boot_info, COMMAND_LINE_TAG).unwrap();
+        assert_eq!(unsafe { tag_string(address, size) }, "console=ttyS0 quiet");
+        let (address, size) = find_tag(boot_info, BOOT_LOADER_NAME_TAG).unwrap();
+        assert_eq!(unsafe { tag_string(address, size) }, "GRUB 2.02");
+    }
+
+    #[test]
+    fn missing_tags_are_not_found() {
+        let boot_info = boot_information(&[(BOOT_LOADER_NAME_TAG, b"GRUB 2.02\0")]);
+        assert_eq!(find_tag(boot_info, COMMAND_LINE_TAG), None);
+    }
+}
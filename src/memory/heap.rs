@@ -0,0 +1,455 @@
+use super::paging::{Page, ActivePageTable, WRITABLE, NO_EXECUTE, virtual_pointer};
+use super::{PAGE_SIZE, FrameAllocator};
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use spin::Mutex;
+
+
+pub const HEAP_START: usize = 0o_000_001_000_000_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+
+/// The interface the global allocator forwards to.
+pub trait KernelAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+/// Selects the allocator `init_kernel_heap` installs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapKind {
+    Bump,
+    LinkedList,
+}
+
+/// The installed kernel heap allocator. This is an enum instead of a trait
+/// object so that allocating doesn't go through a vtable.
+pub enum HeapAllocator {
+    Bump(BumpAllocator),
+    LinkedList(LinkedListAllocator),
+}
+
+impl HeapAllocator {
+    /// Creates an allocator of the given kind managing the memory from
+    /// `heap_start` to `heap_start + heap_size`. The memory must be mapped.
+    pub unsafe fn new(kind: HeapKind, heap_start: usize, heap_size: usize) -> HeapAllocator {
+        match kind {
+            HeapKind::Bump => HeapAllocator::Bump(BumpAllocator::new(heap_start, heap_size)),
+            HeapKind::LinkedList => {
+                HeapAllocator::LinkedList(LinkedListAllocator::new(heap_start, heap_size))
+            }
+        }
+    }
+}
+
+impl KernelAllocator for HeapAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match *self {
+            HeapAllocator::Bump(ref mut allocator) => allocator.alloc(layout),
+            HeapAllocator::LinkedList(ref mut allocator) => allocator.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match *self {
+            HeapAllocator::Bump(ref mut allocator) => allocator.dealloc(ptr, layout),
+            HeapAllocator::LinkedList(ref mut allocator) => allocator.dealloc(ptr, layout),
+        }
+    }
+}
+
+/// Forwards to the allocator installed by `init_kernel_heap`. Allocations
+/// fail until then.
+pub struct GlobalHeap(Mutex<Option<HeapAllocator>>);
+
+unsafe impl GlobalAlloc for GlobalHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match *self.0.lock() {
+            Some(ref mut allocator) => allocator.alloc(layout),
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match *self.0.lock() {
+            Some(ref mut allocator) => allocator.dealloc(ptr, layout),
+            None => panic!("deallocation before the kernel heap was initialized"),
+        }
+    }
+}
+
+#[cfg_attr(not(test), global_allocator)]
+static HEAP: GlobalHeap = GlobalHeap(Mutex::new(None));
+
+/// Maps the kernel heap and installs an allocator of the given kind for it.
+pub fn init_kernel_heap<A>(kind: HeapKind,
+                           active_table: &mut ActivePageTable,
+                           allocator: &mut A)
+    where A: FrameAllocator
+{
+    let heap_start_page = Page::containing_address(HEAP_START);
+    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+
+    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
+        active_table.map(page, WRITABLE | NO_EXECUTE, allocator).flush(active_table);
+    }
+
+    let heap = unsafe { HeapAllocator::new(kind, HEAP_START, HEAP_SIZE) };
+    let mut installed = HEAP.0.lock();
+    assert!(installed.is_none(), "kernel heap is already initialized");
+    *installed = Some(heap);
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    (addr + align - 1) & !(align - 1)
+}
+
+
+/// Hands out memory in increasing order and never reuses it. Allocation
+/// addresses only depend on the sequence of requests.
+pub struct BumpAllocator {
+    next: usize,
+    heap_end: usize,
+}
+
+impl BumpAllocator {
+    pub fn new(heap_start: usize, heap_size: usize) -> BumpAllocator {
+        BumpAllocator {
+            next: heap_start,
+            heap_end: heap_start + heap_size,
+        }
+    }
+}
+
+impl KernelAllocator for BumpAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = alloc_start.saturating_add(layout.size());
+
+        if alloc_end <= self.heap_end {
+            self.next = alloc_end;
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    unsafe fn dealloc(&mut self, _ptr: *mut u8, _layout: Layout) {
+        // memory is never reused
+    }
+}
+
+
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+/// A first fit allocator keeping the free regions in a singly linked list
+/// that is stored in the free memory itself. Freed regions aren't merged.
+pub struct LinkedListAllocator {
+    head: *mut ListNode,
+}
+
+// the list is only reachable through the allocator
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    /// Creates an allocator for the given memory, which must be mapped and
+    /// unused.
+    pub unsafe fn new(heap_start: usize, heap_size: usize) -> LinkedListAllocator {
+        let mut allocator = LinkedListAllocator { head: ptr::null_mut() };
+        allocator.add_free_region(heap_start, heap_size);
+        allocator
+    }
+
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        debug_assert!(align_up(addr, mem::align_of::<ListNode>()) == addr);
+        debug_assert!(size >= mem::size_of::<ListNode>());
+
+        let node = addr as *mut ListNode;
+        ptr::write(node, ListNode { size: size, next: self.head });
+        self.head = node;
+    }
+
+    /// Adjusts the layout so that every allocated region can hold a
+    /// `ListNode` once it is freed.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(mem::align_of::<ListNode>());
+        let size = align_up(layout.size().max(mem::size_of::<ListNode>()),
+                            mem::align_of::<ListNode>());
+        (size, align)
+    }
+
+    /// Returns whether a freed part of a region is either empty or large
+    /// enough for a `ListNode`.
+    fn fits_node(size: usize) -> bool {
+        size == 0 || size >= mem::size_of::<ListNode>()
+    }
+}
+
+impl KernelAllocator for LinkedListAllocator {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        let mut previous: *mut *mut ListNode = &mut self.head;
+        while !(*previous).is_null() {
+            let region = *previous;
+            let region_start = region as usize;
+            let region_end = region_start + (*region).size;
+
+            let alloc_start = align_up(region_start, align);
+            let alloc_end = alloc_start.saturating_add(size);
+            let front = alloc_start - region_start;
+
+            if alloc_end <= region_end &&
+               LinkedListAllocator::fits_node(front) &&
+               LinkedListAllocator::fits_node(region_end - alloc_end) {
+                *previous = (*region).next;
+                if front > 0 {
+                    self.add_free_region(region_start, front);
+                }
+                if alloc_end < region_end {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+                return alloc_start as *mut u8;
+            }
+
+            previous = &mut (*region).next;
+        }
+
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+}
+
+
+/// The object sizes the slab allocator has caches for. Every size is a power
+/// of two dividing the page size, so slots are aligned to their size.
+pub const SLAB_OBJECT_SIZES: [usize; 6] = [16, 32, 64, 128, 256, 512];
+
+/// The number of pages each slab cache can grow to.
+pub const MAX_SLAB_PAGES: usize = 64;
+
+pub const SLAB_START: usize = 0o_000_002_000_000_0000;
+pub const SLAB_REGION_SIZE: usize = 6 * MAX_SLAB_PAGES * PAGE_SIZE;
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+/// The slots of one object size. The pages of a cache are consecutive,
+/// starting at `start`.
+struct SlabCache {
+    object_size: usize,
+    start: usize,
+    pages: usize,
+    free: *mut FreeSlot,
+}
+
+impl SlabCache {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.start + self.pages * PAGE_SIZE
+    }
+
+    unsafe fn push(&mut self, addr: usize) {
+        ptr::write(virtual_pointer(addr), FreeSlot { next: self.free });
+        self.free = addr as *mut FreeSlot;
+    }
+}
+
+/// Allocates fixed size objects from slabs of whole pages. Each object size
+/// has its own cache, which maps another page whenever it runs out of slots.
+pub struct SlabAllocator {
+    caches: [SlabCache; 6],
+}
+
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub fn new() -> SlabAllocator {
+        let cache = |index: usize| {
+            SlabCache {
+                object_size: SLAB_OBJECT_SIZES[index],
+                start: SLAB_START + index * MAX_SLAB_PAGES * PAGE_SIZE,
+                pages: 0,
+                free: ptr::null_mut(),
+            }
+        };
+
+        SlabAllocator {
+            caches: [cache(0), cache(1), cache(2), cache(3), cache(4), cache(5)],
+        }
+    }
+
+    /// Allocates a slot for an object of the given size, mapping a new page
+    /// if the matching cache is full. Returns `None` if the size is larger
+    /// than the largest object size, if the cache can't grow any further or
+    /// if there is no frame for the new page or its page tables.
+    pub fn alloc<A>(&mut self,
+                    size: usize,
+                    active_table: &mut ActivePageTable,
+                    allocator: &mut A)
+                    -> Option<*mut u8>
+        where A: FrameAllocator
+    {
+        let cache = match self.caches.iter_mut().find(|cache| cache.object_size >= size) {
+            Some(cache) => cache,
+            None => return None,
+        };
+
+        if cache.free.is_null() {
+            if cache.pages == MAX_SLAB_PAGES {
+                return None;
+            }
+            let page_start = cache.start + cache.pages * PAGE_SIZE;
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return None,
+            };
+            match active_table.try_map_to(Page::containing_address(page_start), frame.clone(),
+                                          WRITABLE | NO_EXECUTE, allocator) {
+                Ok(flush) => flush.flush(active_table),
+                Err(_) => {
+                    allocator.deallocate_frame(frame);
+                    return None;
+                }
+            }
+            cache.pages += 1;
+
+            let object_size = cache.object_size;
+            for slot in (0..PAGE_SIZE / object_size).rev() {
+                unsafe { cache.push(page_start + slot * object_size) };
+            }
+        }
+
+        let slot = cache.free;
+        cache.free = unsafe { (*virtual_pointer::<FreeSlot>(slot as usize)).next };
+        Some(slot as *mut u8)
+    }
+
+    /// Returns the slot at `ptr` to the cache for the given size. `size` must
+    /// be the size that was passed to `alloc`.
+    pub fn dealloc(&mut self, ptr: *mut u8, size: usize) {
+        let addr = ptr as usize;
+        let cache = self.caches.iter_mut().find(|cache| cache.object_size >= size)
+            .expect("no slab cache for the object size");
+
+        debug_assert!(cache.contains(addr) && addr % cache.object_size == 0,
+                      "{:#x} is not a slot of the {} byte slab cache",
+                      addr, cache.object_size);
+        unsafe { cache.push(addr) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::{small_allocator, active_table};
+    use memory::Frame;
+
+    const TEST_HEAP_SIZE: usize = 4096;
+
+    /// Runs the same sequence of requests against an allocator of the given
+    /// kind on a host buffer and returns the offsets of the allocations from
+    /// the heap start. Checks that every allocation lies in the heap, is
+    /// aligned and doesn't overlap a live one.
+    fn run_sequence(kind: HeapKind) -> Vec<usize> {
+        // with room to align the start, so the offsets don't depend on it
+        let mut memory = vec![0u64; TEST_HEAP_SIZE / 8 + 8];
+        let heap_start = align_up(memory.as_mut_ptr() as usize, 64);
+        let mut heap = unsafe { HeapAllocator::new(kind, heap_start, TEST_HEAP_SIZE) };
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+        let mut live: Vec<(*mut u8, Layout)> = Vec::new();
+        let mut offsets = Vec::new();
+        let requests = [Some(layout(64, 8)), Some(layout(100, 16)), None,
+                        Some(layout(32, 8)), Some(layout(8, 64)), None, Some(layout(64, 8))];
+        for request in requests.iter() {
+            match *request {
+                Some(layout) => {
+                    let ptr = unsafe { heap.alloc(layout) };
+                    let start = ptr as usize;
+                    assert!(!ptr.is_null(), "{:?} failed", layout);
+                    assert!(start >= heap_start &&
+                            start + layout.size() <= heap_start + TEST_HEAP_SIZE);
+                    assert_eq!(start % layout.align(), 0);
+                    for &(other, other_layout) in &live {
+                        let other = other as usize;
+                        assert!(start + layout.size() <= other ||
+                                other + other_layout.size() <= start,
+                                "{:#x} overlaps {:#x}", start, other);
+                    }
+                    live.push((ptr, layout));
+                    offsets.push(start - heap_start);
+                }
+                // free the oldest live allocation
+                None => {
+                    let (ptr, layout) = live.remove(0);
+                    unsafe { heap.dealloc(ptr, layout) };
+                }
+            }
+        }
+        offsets
+    }
+
+    #[test]
+    fn bump_allocator_never_reuses_memory() {
+        assert_eq!(run_sequence(HeapKind::Bump), vec![0, 64, 168, 256, 264]);
+    }
+
+    #[test]
+    fn linked_list_allocator_reuses_freed_regions() {
+        // the freed regions are reused, the 64 byte aligned request can't
+        // use the rest of the first one
+        assert_eq!(run_sequence(HeapKind::LinkedList), vec![0, 64, 0, 192, 64]);
+    }
+
+    #[test]
+    fn slab_slots_are_reused_after_dealloc() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let mut slab = SlabAllocator::new();
+
+        // more than fit into one page
+        let slots: Vec<*mut u8> = (0..300)
+            .map(|_| slab.alloc(24, &mut active_table, &mut allocator).unwrap())
+            .collect();
+        for (i, &slot) in slots.iter().enumerate() {
+            assert_eq!(slot as usize % 32, 0);
+            assert!(slots[..i].iter().all(|&other| other != slot), "slot handed out twice");
+            unsafe { *virtual_pointer::<u64>(slot as usize) = i as u64 };
+        }
+        assert_eq!(slab.caches[1].pages, 3);
+
+        for &slot in slots.iter().rev() {
+            slab.dealloc(slot, 24);
+        }
+        let used_frames = allocator.used_frames();
+        for &slot in &slots {
+            assert_eq!(slab.alloc(24, &mut active_table, &mut allocator), Some(slot));
+        }
+        // no page was added
+        assert_eq!(allocator.used_frames(), used_frames);
+    }
+
+    #[test]
+    fn slab_alloc_fails_without_frames() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let mut slab = SlabAllocator::new();
+        while allocator.allocate_frame().is_some() {}
+
+        assert_eq!(slab.alloc(16, &mut active_table, &mut allocator), None);
+        assert_eq!(slab.caches[0].pages, 0);
+        allocator.deallocate_frame(Frame::containing_address(0x10_6000));
+        // the frame is enough for the page, not for its tables
+        assert_eq!(slab.alloc(16, &mut active_table, &mut allocator), None);
+        assert_eq!(allocator.free_frames(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+use memory::{Frame, FrameAllocator};
+
+/// Forwards to an inner allocator and records the largest number of frames
+/// that were allocated at the same time.
+pub struct HighWaterAllocator<A: FrameAllocator> {
+    inner: A,
+    used_frames: usize,
+    high_water_mark: usize,
+}
+
+impl<A> HighWaterAllocator<A> where A: FrameAllocator {
+    pub fn new(inner: A) -> HighWaterAllocator<A> {
+        HighWaterAllocator {
+            inner: inner,
+            used_frames: 0,
+            high_water_mark: 0,
+        }
+    }
+
+    /// Returns the largest number of frames allocated through this wrapper at
+    /// any one time.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    fn record_allocation(&mut self, frame: Option<Frame>) -> Option<Frame> {
+        if frame.is_some() {
+            self.used_frames += 1;
+            if self.used_frames > self.high_water_mark {
+                self.high_water_mark = self.used_frames;
+            }
+        }
+        frame
+    }
+}
+
+impl<A> FrameAllocator for HighWaterAllocator<A> where A: FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let frame = self.inner.allocate_frame();
+        self.record_allocation(frame)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        debug_assert!(self.used_frames > 0, "deallocating more frames than allocated");
+        self.used_frames -= 1;
+        self.inner.deallocate_frame(frame);
+    }
+
+    fn peek_next_frame(&self) -> Option<Frame> {
+        self.inner.peek_next_frame()
+    }
+
+    fn allocate_frame_on_node(&mut self, node: u8) -> Option<Frame> {
+        let frame = self.inner.allocate_frame_on_node(node);
+        self.record_allocation(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::small_allocator;
+
+    #[test]
+    fn mark_keeps_the_peak_after_frames_are_freed() {
+        let mut allocator = HighWaterAllocator::new(small_allocator());
+        let frames: Vec<Frame> = (0..5).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert_eq!(allocator.high_water_mark(), 5);
+
+        for frame in frames {
+            allocator.deallocate_frame(frame);
+        }
+        let frames: Vec<Frame> = (0..3).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert_eq!(allocator.high_water_mark(), 5);
+        assert_eq!(allocator.inner().used_frames(), frames.len());
+    }
+}
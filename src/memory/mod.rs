@@ -1,33 +1,706 @@
 use self::paging::PhysicalAddress;
+use hw;
+use cpu_features;
+use core::cmp;
+use core::fmt;
+use multiboot2::{BootInformation, MemoryArea, MemoryAreaIter};
 
-pub use self::area_frame_allocator::AreaFrameAllocator;
-pub use self::paging::remap_the_kernel;
+pub use self::acpi::{AcpiMapping, map_acpi_tables};
+pub use self::area_frame_allocator::{AreaFrameAllocator, AllocationOrder, DeallocError};
+pub use self::audit::{AuditResult, audit_memory};
+pub use self::boot_strings::{BootStrings, map_boot_strings};
+pub use self::reserved_ranges::{ReservedRanges, RangesError};
+pub use self::high_water_allocator::HighWaterAllocator;
+pub use self::pool_allocator::PoolAllocator;
+#[cfg(feature = "diagnostics")]
+pub use self::tagged_allocator::TaggedAllocator;
+pub use self::heap::{KernelAllocator, HeapAllocator, HeapKind, SlabAllocator,
+                     init_kernel_heap};
+pub use self::paging::{remap_the_kernel, init_page_attribute_table};
 
+mod acpi;
+mod align;
+#[cfg(test)]
+mod allocator_stress;
 mod area_frame_allocator;
+mod audit;
+mod boot_strings;
+mod high_water_allocator;
+mod heap;
 mod paging;
+mod pool_allocator;
+mod reserved_ranges;
+#[cfg(feature = "diagnostics")]
+mod tagged_allocator;
+#[cfg(test)]
+mod test_support;
 
 pub const PAGE_SIZE: usize = 4096;
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// The memory management state set up by `init`.
+pub struct MemoryController {
+    pub active_table: paging::ActivePageTable,
+    pub frame_allocator: AreaFrameAllocator,
+    pub regions: paging::VirtualRegionManager,
+}
+
+/// A summary of the physical memory, computed by `init`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryInfo {
+    /// The size of all available memory areas.
+    pub total_ram_bytes: usize,
+    /// The size of the frames the frame allocator can hand out.
+    pub usable_ram_bytes: usize,
+    /// The size of the frames in the memory areas that are reserved for the
+    /// kernel image and the multiboot information.
+    pub reserved_bytes: usize,
+    pub kernel_size_bytes: usize,
+    /// The frames of the largest available memory area.
+    pub largest_usable_region: FrameRange,
+}
+
+impl MemoryInfo {
+    fn new(memory_areas: MemoryAreaIter,
+           kernel_size_bytes: usize,
+           frame_allocator: &AreaFrameAllocator)
+           -> MemoryInfo
+    {
+        let total_ram_bytes: usize = memory_areas.clone()
+            .map(|area| area.length as usize).sum();
+        let area_bytes: usize = memory_areas.clone()
+            .map(|area| area_frame_range(area).len() * PAGE_SIZE).sum();
+        let usable_ram_bytes = frame_allocator.total_frames() * PAGE_SIZE;
+        let largest_usable_region = memory_areas.map(area_frame_range)
+            .max_by_key(|range| range.len())
+            .unwrap_or(FrameRange { start: 0, end: 0 });
+
+        MemoryInfo {
+            total_ram_bytes: total_ram_bytes,
+            usable_ram_bytes: usable_ram_bytes,
+            reserved_bytes: area_bytes - usable_ram_bytes,
+            kernel_size_bytes: kernel_size_bytes,
+            largest_usable_region: largest_usable_region,
+        }
+    }
+}
+
+/// The least amount of available memory the kernel needs to run, for its
+/// image, the heap and the page tables.
+pub const MIN_USABLE_RAM: usize = 2 * 1024 * 1024;
+
+/// The reasons the memory map can be rejected by `try_init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The available memory areas starting at the given addresses overlap.
+    OverlappingMemoryAreas(PhysicalAddress, PhysicalAddress),
+    /// The available memory area doesn't fit into the physical address width
+    /// of the processor.
+    AreaBeyondMaxPhysAddr {
+        base: PhysicalAddress,
+        length: usize,
+    },
+    /// The available memory areas only hold the given number of bytes, less
+    /// than `MIN_USABLE_RAM`.
+    InsufficientRam(usize),
+}
+
+pub fn init(boot_info: &BootInformation) -> (MemoryController, MemoryInfo) {
+    try_init(boot_info).expect("invalid memory map")
+}
+
+/// Like `init`, but returns an error instead of trusting a memory map whose
+/// areas overlap, lie beyond MAXPHYADDR or hold too little memory, e.g.
+/// because of a buggy boot loader or VM configuration.
+pub fn try_init(boot_info: &BootInformation) -> Result<(MemoryController, MemoryInfo), InitError> {
+    paging::assert_paging_features();
+
+    let memory_map_tag = boot_info.memory_map_tag()
+        .expect("Memory map tag required");
+    try!(validate_memory_map(memory_map_tag.memory_areas()));
+
+    let (mut frame_allocator, memory_info) = boot_frame_allocator(boot_info);
+
+    let mut active_table = remap_the_kernel(&mut frame_allocator, boot_info, &[]);
+
+    if cfg!(debug_assertions) {
+        paging::self_test(&mut active_table, &mut frame_allocator)
+            .expect("paging self test failed");
+    }
+
+    // all address spaces share the kernel half P3 tables, see
+    // `preallocate_kernel_tables`
+    active_table.preallocate_kernel_tables(&mut frame_allocator);
+
+    heap::init_kernel_heap(HeapKind::LinkedList, &mut active_table, &mut frame_allocator);
+
+    let mut regions = paging::VirtualRegionManager::new();
+    let kernel_end = kernel_frame_range(boot_info).end().start_address();
+    let identity_map_end = cmp::max(kernel_end, boot_info.end_address());
+    reserve_fixed_regions(&mut regions, identity_map_end)
+        .expect("fixed virtual regions overlap");
+
+    let memory_controller = MemoryController {
+        active_table: active_table,
+        frame_allocator: frame_allocator,
+        regions: regions,
+    };
+    Ok((memory_controller, memory_info))
+}
+
+/// Sets up the frame allocator for the memory map of `boot_info`, with the
+/// kernel image and the multiboot information reserved, and summarizes the
+/// memory it manages.
+fn boot_frame_allocator(boot_info: &BootInformation) -> (AreaFrameAllocator, MemoryInfo) {
+    let memory_map_tag = boot_info.memory_map_tag()
+        .expect("Memory map tag required");
+
+    let kernel_frames = kernel_frame_range(boot_info);
+    let kernel_start = kernel_frames.start().start_address();
+    let kernel_end = kernel_frames.end().start_address();
+
+    let multiboot_start = boot_info.start_address();
+    let multiboot_end = boot_info.end_address();
+
+    println!("kernel start: 0x{:x}, kernel end: 0x{:x}",
+        kernel_start, kernel_end);
+    println!("multiboot start: 0x{:x}, multiboot end: 0x{:x}",
+        multiboot_start, multiboot_end);
+
+    // The kernel image and the multiboot information may share frames, the
+    // allocator merges the two reserved ranges in that case.
+    let frame_allocator = AreaFrameAllocator::new(
+        kernel_start, kernel_end, multiboot_start,
+        multiboot_end, memory_map_tag.memory_areas());
+
+    let memory_info = MemoryInfo::new(memory_map_tag.memory_areas(),
+        kernel_end - kernel_start, &frame_allocator);
+    (frame_allocator, memory_info)
+}
+
+/// Checks that the available memory areas don't overlap, fit into the
+/// physical address width and hold at least `MIN_USABLE_RAM` bytes.
+pub fn validate_memory_map(memory_areas: MemoryAreaIter) -> Result<(), InitError> {
+    let bits = cpu_features::physical_address_bits() as usize;
+    let areas = || memory_areas.clone().filter(|area| area.length > 0);
+
+    for area in areas() {
+        let last = area.base_addr as usize + (area.length as usize - 1);
+        if bits < 64 && last >> bits != 0 {
+            return Err(InitError::AreaBeyondMaxPhysAddr {
+                base: area.base_addr as usize,
+                length: area.length as usize,
+            });
+        }
+    }
+
+    for (i, first) in areas().enumerate() {
+        for second in areas().skip(i + 1) {
+            if first.base_addr < second.base_addr + second.length &&
+               second.base_addr < first.base_addr + first.length {
+                return Err(InitError::OverlappingMemoryAreas(first.base_addr as usize,
+                                                             second.base_addr as usize));
+            }
+        }
+    }
+
+    let total: usize = areas().map(|area| area.length as usize).sum();
+    if total < MIN_USABLE_RAM {
+        return Err(InitError::InsufficientRam(total));
+    }
+    Ok(())
+}
+
+/// Reserves the virtual regions whose addresses are fixed, so that regions
+/// allocated later can't collide with them.
+fn reserve_fixed_regions(regions: &mut paging::VirtualRegionManager,
+                         identity_map_end: usize)
+                         -> Result<(), paging::RegionError>
+{
+    use self::paging::RegionPurpose;
+
+    try!(regions.reserve(0, identity_map_end, RegionPurpose::IdentityMap));
+    try!(regions.reserve(heap::HEAP_START, heap::HEAP_SIZE, RegionPurpose::Heap));
+    try!(regions.reserve(heap::SLAB_START, heap::SLAB_REGION_SIZE, RegionPurpose::Slab));
+    paging::reserve_paging_regions(regions)
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     number: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    /// The physical address doesn't fit into the physical address width of
+    /// the processor.
+    AboveMaxPhysicalAddress(PhysicalAddress),
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame({:#x})", self.start_address())
+    }
+}
+
 impl Frame {
     fn containing_address(address: usize) -> Frame {
         Frame { number: address / PAGE_SIZE }
     }
 
+    /// Like `containing_address`, but returns an error if the address has
+    /// bits set above the physical address width of the processor. Page table
+    /// entries pointing to such frames would cause reserved bit faults.
+    pub fn try_containing_address(address: PhysicalAddress) -> Result<Frame, AddressError> {
+        let bits = cpu_features::physical_address_bits() as usize;
+        if bits < 64 && address >> bits != 0 {
+            return Err(AddressError::AboveMaxPhysicalAddress(address));
+        }
+        Ok(Frame::containing_address(address))
+    }
+
     fn start_address(&self) -> PhysicalAddress {
         self.number * PAGE_SIZE
     }
 
+    /// Returns the frame number, i.e. the start address divided by the page
+    /// size. Together with `from_number` this allows storing frames compactly,
+    /// e.g. in swap metadata.
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    pub fn from_number(number: usize) -> Frame {
+        Frame { number: number }
+    }
+
     fn clone(&self) -> Frame {
         Frame { number: self.number }
     }
+
+    /// Returns whether the frame can start a huge page of the given size.
+    pub fn is_aligned_to(&self, size: paging::PageSize) -> bool {
+        self.start_address() % size.bytes() == 0
+    }
+
+    pub fn zone(&self) -> FrameZone {
+        if self.start_address() < DMA_ZONE_END {
+            FrameZone::Dma
+        } else if self.start_address() < DMA32_ZONE_END {
+            FrameZone::Dma32
+        } else {
+            FrameZone::Normal
+        }
+    }
+}
+
+const DMA_ZONE_END: PhysicalAddress = 16 * 1024 * 1024;
+const DMA32_ZONE_END: PhysicalAddress = 4 * 1024 * 1024 * 1024;
+
+/// The physical memory zones, by the devices that can address them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameZone {
+    /// Below 16MiB, reachable by ISA DMA.
+    Dma,
+    /// Below 4GiB, reachable by devices with 32 bit addressing.
+    Dma32,
+    Normal,
+}
+
+impl FrameZone {
+    /// Returns the frames of the zone.
+    pub fn frames(&self) -> FrameRange {
+        let (start, end) = match *self {
+            FrameZone::Dma => (0, DMA_ZONE_END),
+            FrameZone::Dma32 => (DMA_ZONE_END, DMA32_ZONE_END),
+            FrameZone::Normal => (DMA32_ZONE_END, usize::max_value()),
+        };
+        FrameRange { start: start / PAGE_SIZE, end: end / PAGE_SIZE }
+    }
+}
+
+/// A half-open range `[start, end)` of physical frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange {
+    start: usize,
+    end: usize,
+}
+
+impl FrameRange {
+    pub fn new(start: Frame, end: Frame) -> FrameRange {
+        assert!(start <= end, "frame range starts after its end");
+        FrameRange { start: start.number, end: end.number }
+    }
+
+    /// Returns the smallest frame range containing the bytes `[start, end)`.
+    pub fn containing_addresses(start: PhysicalAddress, end: PhysicalAddress)
+        -> FrameRange
+    {
+        if end <= start {
+            let frame = Frame::containing_address(start);
+            return FrameRange::new(frame.clone(), frame);
+        }
+        let end_frame = Frame::containing_address(end - 1);
+        FrameRange::new(Frame::containing_address(start),
+                        Frame { number: end_frame.number + 1 })
+    }
+
+    pub fn start(&self) -> Frame {
+        Frame { number: self.start }
+    }
+
+    pub fn end(&self) -> Frame {
+        Frame { number: self.end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn contains(&self, frame: &Frame) -> bool {
+        frame.number >= self.start && frame.number < self.end
+    }
+
+    pub fn iter(&self) -> FrameIter {
+        FrameIter { next: self.start, end: self.end }
+    }
+}
+
+pub struct FrameIter {
+    next: usize,
+    end: usize,
+}
+
+impl Iterator for FrameIter {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.next < self.end {
+            let frame = Frame { number: self.next };
+            self.next += 1;
+            Some(frame)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the frames that lie completely within the given memory area. A
+/// partial frame at either end of the area is not usable as RAM, so the start
+/// is rounded up and the end is rounded down to the next frame boundary.
+pub fn area_frame_range(area: &MemoryArea) -> FrameRange {
+    let start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+    let end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+    if end <= start {
+        FrameRange { start: start, end: start }
+    } else {
+        FrameRange { start: start, end: end }
+    }
+}
+
+/// Returns the frames of the kernel image, from the lowest start to the
+/// highest end of all allocated ELF sections. The section table isn't sorted
+/// by address, and sections that aren't loaded have address 0, so they are
+/// left out.
+pub fn kernel_frame_range(boot_info: &BootInformation) -> FrameRange {
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("Elf sections tag required");
+
+    let sections = || elf_sections_tag.sections().filter(|s| s.is_allocated());
+    let start = sections().map(|s| s.addr).min().expect("no allocated ELF sections");
+    let end = sections().map(|s| s.addr + s.size).max().unwrap();
+    FrameRange::containing_addresses(start as usize, end as usize)
+}
+
+/// The end of the low memory below 1MiB, which holds BIOS data and legacy
+/// device memory and is never handed out by `usable_frame_ranges`.
+const LOW_MEMORY_END: PhysicalAddress = 0x10_0000;
+
+/// Returns the usable frame ranges of the memory map, with the frames of low
+/// memory, the kernel image and the multiboot information removed. An area
+/// that one of them lies in the middle of is split into two ranges.
+pub fn usable_frame_ranges(boot_info: &BootInformation) -> UsableFrameRanges {
+    let memory_map_tag = boot_info.memory_map_tag()
+        .expect("Memory map tag required");
+
+    let mut reserved = ReservedRanges::new();
+    for &range in &[FrameRange::containing_addresses(0, LOW_MEMORY_END),
+                    kernel_frame_range(boot_info),
+                    FrameRange::containing_addresses(boot_info.start_address(),
+                                                     boot_info.end_address())] {
+        reserved.insert(range).expect("three reserved ranges always fit");
+    }
+
+    UsableFrameRanges {
+        areas: memory_map_tag.memory_areas(),
+        reserved: reserved,
+        remainder: None,
+    }
+}
+
+/// An iterator over the usable frame ranges, see `usable_frame_ranges`.
+pub struct UsableFrameRanges {
+    areas: MemoryAreaIter,
+    reserved: ReservedRanges,
+    // the part of the current area after the last yielded range
+    remainder: Option<FrameRange>,
+}
+
+impl Iterator for UsableFrameRanges {
+    type Item = FrameRange;
+
+    fn next(&mut self) -> Option<FrameRange> {
+        loop {
+            let mut range = match self.remainder.take() {
+                Some(range) => range,
+                None => match self.areas.next() {
+                    Some(area) => area_frame_range(area),
+                    None => return None,
+                },
+            };
+
+            // the reserved ranges are sorted, so they are cut off in order
+            for reserved in self.reserved.as_slice() {
+                if reserved.end <= range.start || range.is_empty() {
+                    continue;
+                }
+                if reserved.start >= range.end {
+                    break;
+                }
+                if reserved.start > range.start {
+                    if reserved.end < range.end {
+                        self.remainder = Some(FrameRange { start: reserved.end, end: range.end });
+                    }
+                    return Some(FrameRange { start: range.start, end: reserved.start });
+                }
+                range.start = cmp::min(reserved.end, range.end);
+            }
+
+            if !range.is_empty() {
+                return Some(range);
+            }
+        }
+    }
 }
 
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
+
+    /// Returns the frame the next `allocate_frame` call would return, without
+    /// allocating it. Allocators that can't predict this return `None`.
+    fn peek_next_frame(&self) -> Option<Frame> {
+        None
+    }
+
+    /// Allocates a frame, preferably one on the given NUMA node. Allocators
+    /// that don't know about nodes ignore the preference.
+    fn allocate_frame_on_node(&mut self, _node: u8) -> Option<Frame> {
+        self.allocate_frame()
+    }
+
+    /// Allocates `count` physically contiguous frames below `ceiling`. The
+    /// default implementation relies on consecutive `allocate_frame` calls
+    /// returning ascending frames, as a bump allocator does, and returns
+    /// `None` after giving the frames back if they don't.
+    fn allocate_contiguous(&mut self, count: usize, ceiling: &Frame) -> Option<FrameRange> {
+        assert!(count > 0, "can't allocate an empty frame range");
+
+        let start = match self.allocate_frame() {
+            Some(frame) => frame.number,
+            None => return None,
+        };
+        let mut end = start + 1;
+        while end - start < count {
+            match self.allocate_frame() {
+                Some(frame) => {
+                    if frame.number != end {
+                        self.deallocate_frame(frame);
+                        break;
+                    }
+                    end += 1;
+                }
+                None => break,
+            }
+        }
+
+        let range = FrameRange { start: start, end: end };
+        if range.len() < count || end > ceiling.number {
+            for frame in range.iter() {
+                self.deallocate_frame(frame);
+            }
+            return None;
+        }
+        Some(range)
+    }
+}
+
+/// Returns the frames of the bootstrap stack to the allocator once execution
+/// has moved to a new stack. Frames that are reserved, because the stack lies
+/// in the kernel image, are released instead.
+///
+/// # Safety
+/// The caller must guarantee that nothing references `old_stack` anymore. In
+/// particular the current stack must not lie within it; this is checked in
+/// debug builds. The bootstrap stack is identity mapped, so the stack pointer
+/// is compared directly against the frame addresses.
+pub unsafe fn reclaim_old_stack(old_stack: FrameRange, allocator: &mut AreaFrameAllocator) {
+    let stack_pointer = hw::stack_pointer();
+    debug_assert!(!old_stack.contains(&Frame::containing_address(stack_pointer)),
+        "reclaiming the stack in use (rsp: {:#x})", stack_pointer);
+
+    for frame in old_stack.iter() {
+        if allocator.is_reserved(&frame) {
+            let range = FrameRange { start: frame.number, end: frame.number + 1 };
+            allocator.release_reserved(range)
+                .expect("no frame range left to release the old stack");
+        } else {
+            allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::{MIB, memory_areas, elf_boot_information, kernel_boot_information,
+                               allocator_with_areas};
+    const ALLOCATED: u64 = 0x2;
+
+    #[test]
+    fn sane_memory_map_is_accepted() {
+        let areas = memory_areas(&[(0, 0x9_fc00), (MIB, 127 * MIB)]);
+        assert_eq!(validate_memory_map(areas), Ok(()));
+    }
+
+    #[test]
+    fn overlapping_areas_are_rejected() {
+        let areas = memory_areas(&[(MIB, 64 * MIB), (32 * MIB, 64 * MIB)]);
+        assert_eq!(validate_memory_map(areas),
+                   Err(InitError::OverlappingMemoryAreas(0x10_0000, 0x200_0000)));
+    }
+
+    #[test]
+    fn adjacent_areas_do_not_overlap() {
+        let areas = memory_areas(&[(MIB, 2 * MIB), (3 * MIB, 2 * MIB)]);
+        assert_eq!(validate_memory_map(areas), Ok(()));
+    }
+
+    #[test]
+    fn areas_beyond_the_physical_address_width_are_rejected() {
+        let bits = ::cpu_features::physical_address_bits();
+        let base = 1u64 << bits;
+        let areas = memory_areas(&[(MIB, 64 * MIB), (base - MIB, 2 * MIB)]);
+        assert_eq!(validate_memory_map(areas), Err(InitError::AreaBeyondMaxPhysAddr {
+            base: (base - MIB) as usize,
+            length: 2 * MIB as usize,
+        }));
+    }
+
+    fn frame_range_of_area(base: u64, length: u64) -> FrameRange {
+        area_frame_range(memory_areas(&[(base, length)]).next().unwrap())
+    }
+
+    fn range(start: usize, end: usize) -> FrameRange {
+        FrameRange { start: start, end: end }
+    }
+
+    #[test]
+    fn area_frame_range_leaves_out_partial_frames() {
+        assert_eq!(frame_range_of_area(0x10_0000, 0x4000), range(0x100, 0x104));
+        // unaligned base: the first partial frame is left out
+        assert_eq!(frame_range_of_area(0x10_0800, 0x4000), range(0x101, 0x104));
+        // unaligned length: the last partial frame is left out
+        assert_eq!(frame_range_of_area(0x10_0000, 0x3c00), range(0x100, 0x103));
+        assert_eq!(frame_range_of_area(0x10_0400, 0x3a00), range(0x101, 0x103));
+    }
+
+    #[test]
+    fn area_frame_range_of_an_area_without_a_full_frame_is_empty() {
+        assert_eq!(frame_range_of_area(0x10_0800, 0x1000), range(0x101, 0x101));
+        assert_eq!(frame_range_of_area(0x10_0400, 0x400), range(0x101, 0x101));
+    }
+
+    #[test]
+    fn memory_info_summarizes_the_memory_map() {
+        // the kernel takes five frames of the second area, the boot
+        // information lives on the host heap, outside the areas
+        let boot_info = kernel_boot_information(&[(0, 0x9_fc00), (MIB, 7 * MIB), (16 * MIB, MIB)],
+                                                &[(".text", 2 * MIB, 0x4000, ALLOCATED),
+                                                  (".data", 2 * MIB + 0x4000, 0x800, ALLOCATED)]);
+        let (frame_allocator, memory_info) = boot_frame_allocator(boot_info);
+
+        assert_eq!(memory_info.total_ram_bytes, 0x9_fc00 + 8 * MIB as usize);
+        assert_eq!(memory_info.usable_ram_bytes, 0x9_f000 + 8 * MIB as usize - 0x5000);
+        assert_eq!(memory_info.usable_ram_bytes, frame_allocator.total_frames() * PAGE_SIZE);
+        assert_eq!(memory_info.reserved_bytes, 0x5000);
+        assert_eq!(memory_info.kernel_size_bytes, 0x5000);
+        assert_eq!(memory_info.largest_usable_region, FrameRange { start: 0x100, end: 0x800 });
+    }
+
+    #[test]
+    fn addresses_above_maxphyaddr_have_no_frame() {
+        ::hw::with_mock(|hw| hw.physical_address_bits.set(40));
+        let limit = 1 << 40;
+
+        assert_eq!(Frame::try_containing_address(limit - 1),
+                   Ok(Frame::containing_address(limit - 1)));
+        assert_eq!(Frame::try_containing_address(limit),
+                   Err(AddressError::AboveMaxPhysicalAddress(limit)));
+        assert_eq!(Frame::try_containing_address(1 << 51),
+                   Err(AddressError::AboveMaxPhysicalAddress(1 << 51)));
+    }
+
+    #[test]
+    fn kernel_frame_range_spans_out_of_order_sections() {
+        // a section that isn't loaded has address 0 and must not count
+        let boot_info = elf_boot_information(&[(".data", 2 * MIB + 0x5000, 0x800, ALLOCATED),
+                                               (".comment", 0, 0x100, 0),
+                                               (".text", 2 * MIB, 0x3000, ALLOCATED),
+                                               (".rodata", 2 * MIB + 0x3000, 0x1000, ALLOCATED)]);
+        assert_eq!(kernel_frame_range(boot_info), FrameRange { start: 0x200, end: 0x206 });
+    }
+
+    #[test]
+    fn too_little_ram_is_rejected() {
+        let total = MIN_USABLE_RAM as u64 - 0x1000;
+        let areas = memory_areas(&[(0, 0x9_f000), (MIB, total - 0x9_f000)]);
+        assert_eq!(validate_memory_map(areas), Err(InitError::InsufficientRam(total as usize)));
+    }
+
+    #[test]
+    fn usable_frame_ranges_leave_out_low_memory_and_the_kernel() {
+        // the kernel lies in the middle of the second area
+        let boot_info = kernel_boot_information(&[(0, 0x9_fc00), (MIB, 7 * MIB)],
+                                                &[(".text", 2 * MIB, 0x3000, ALLOCATED),
+                                                  (".data", 2 * MIB + 0x3000, 0x800, ALLOCATED)]);
+        // the boot information itself lives on the host heap, outside the areas
+        assert!(boot_info.start_address() as u64 >= 8 * MIB);
+
+        let ranges: Vec<FrameRange> = usable_frame_ranges(boot_info).collect();
+        assert_eq!(ranges, vec![FrameRange { start: 0x100, end: 0x200 },
+                                FrameRange { start: 0x204, end: 0x800 }]);
+    }
+
+    #[test]
+    fn frame_zones_are_adjacent() {
+        assert_eq!(FrameZone::Dma.frames(), FrameRange { start: 0, end: 0x1000 });
+        assert_eq!(FrameZone::Dma32.frames(), FrameRange { start: 0x1000, end: 0x10_0000 });
+        assert_eq!(FrameZone::Normal.frames().start, 0x10_0000);
+    }
+
+    #[test]
+    fn free_frames_are_counted_per_zone() {
+        // 1MiB to 20MiB, the kernel and multiboot information take 5 frames
+        let mut allocator = allocator_with_areas(&[(MIB, 19 * MIB)]);
+        assert_eq!(allocator.free_frames_in_zone(FrameZone::Dma), 0xf00 - 5);
+        assert_eq!(allocator.free_frames_in_zone(FrameZone::Dma32), 0x400);
+        assert_eq!(allocator.free_frames_in_zone(FrameZone::Normal), 0);
+
+        allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_frames_in_zone(FrameZone::Dma), 0xf00 - 6);
+    }
 }
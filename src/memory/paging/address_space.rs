@@ -0,0 +1,365 @@
+use super::{Page, ActivePageTable, InactivePageTable, MapError, VirtualAddress,
+            PhysicalAddress, free_page_tables};
+use super::entry::EntryFlags;
+use super::temporary_page::TemporaryPage;
+use memory::{Frame, FrameAllocator};
+
+
+/// The temporary page used to edit address spaces. Only one of them can be
+/// edited at a time, so they all share the same page.
+pub const TEMPORARY_PAGE: Page = Page { number: 0xcafec000 };
+
+/// An inactive page table together with the temporary page needed to edit it.
+///
+/// The frames of an address space aren't freed when it is dropped, because
+/// that needs the active table and a frame allocator. Use `destroy` instead;
+/// dropping an address space that wasn't destroyed panics in debug builds.
+#[must_use = "the frames of an address space leak unless it is destroyed"]
+pub struct AddressSpace {
+    // both are only `None` once `destroy` took them
+    table: Option<InactivePageTable>,
+    temporary_page: Option<TemporaryPage>,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space.
+    pub fn new<A>(active_table: &mut ActivePageTable, allocator: &mut A) -> AddressSpace
+        where A: FrameAllocator
+    {
+        let mut temporary_page = TemporaryPage::new(TEMPORARY_PAGE, active_table, allocator);
+        let frame = allocator.allocate_frame().expect("no more frames");
+        let mut table = InactivePageTable::new(frame, active_table, &mut temporary_page);
+        table.share_kernel_mappings(active_table, &mut temporary_page);
+
+        AddressSpace {
+            table: Some(table),
+            temporary_page: Some(temporary_page),
+        }
+    }
+
+    /// Maps `page` to a newly allocated frame.
+    pub fn map<A>(&mut self,
+                  page: Page,
+                  flags: EntryFlags,
+                  active_table: &mut ActivePageTable,
+                  allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let mut session = active_table.edit(self.table.as_mut().unwrap(),
+                                            self.temporary_page.as_mut().unwrap());
+        session.map(page, flags, allocator).ignore_in_session(&session);
+    }
+
+    /// Unmaps `page` and frees the frame it was mapped to. Fails like
+    /// `Mapper::unmap`, e.g. if the page is pinned.
+    pub fn unmap<A>(&mut self,
+                    page: Page,
+                    active_table: &mut ActivePageTable,
+                    allocator: &mut A)
+                    -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let mut session = active_table.edit(self.table.as_mut().unwrap(),
+                                            self.temporary_page.as_mut().unwrap());
+        let flush = try!(session.unmap_and_free(page, allocator));
+        flush.ignore_in_session(&session);
+        Ok(())
+    }
+
+    pub fn translate(&mut self,
+                     address: VirtualAddress,
+                     active_table: &mut ActivePageTable)
+                     -> Option<PhysicalAddress>
+    {
+        let session = active_table.edit(self.table.as_mut().unwrap(),
+                                        self.temporary_page.as_mut().unwrap());
+        session.translate(address)
+    }
+
+    /// Counts the data frames mapped in the user half of the address space.
+    pub fn resident_frames(&mut self, active_table: &mut ActivePageTable) -> usize {
+        self.table.as_mut().unwrap()
+            .resident_frames(active_table, self.temporary_page.as_mut().unwrap())
+    }
+
+    /// Frees all user space frames and page tables of the address space, its
+    /// P4 table and the frames held by its temporary page.
+    pub fn destroy<A>(mut self, active_table: &mut ActivePageTable, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let mut table = self.table.take().unwrap();
+        let mut temporary_page = self.temporary_page.take().unwrap();
+        table.free_user_space(active_table, &mut temporary_page, allocator);
+
+        temporary_page.release(allocator);
+        allocator.deallocate_frame(table.p4_frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert!(self.table.is_none(),
+                      "address space dropped without calling `destroy`, its frames leak");
+    }
+}
+
+
+/// The number of frames an `AddressSpaceBuilder` can allocate for mappings.
+pub const MAX_BUILDER_FRAMES: usize = 64;
+
+/// The frames an `AddressSpaceBuilder` allocated for mappings, so that they
+/// can be returned to the allocator if the build is rolled back.
+struct AllocatedFrames {
+    numbers: [usize; MAX_BUILDER_FRAMES],
+    len: usize,
+}
+
+impl AllocatedFrames {
+    fn new() -> AllocatedFrames {
+        AllocatedFrames {
+            numbers: [0; MAX_BUILDER_FRAMES],
+            len: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == MAX_BUILDER_FRAMES
+    }
+
+    fn push(&mut self, frame: &Frame) {
+        assert!(!self.is_full(), "check `is_full` before allocating a frame to record");
+        self.numbers[self.len] = frame.number;
+        self.len += 1;
+    }
+
+    /// Returns all recorded frames to `allocator`.
+    fn release<A>(&mut self, allocator: &mut A) where A: FrameAllocator {
+        for &number in &self.numbers[..self.len] {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+        self.len = 0;
+    }
+}
+
+/// Builds a new page table step by step. If the builder is dropped without
+/// calling `commit`, e.g. because a mapping failed, all frames it allocated
+/// are returned to the allocator: the page tables of the user half and the P4
+/// table as well as the frames allocated by `map`. Frames passed to `map_to`
+/// are left alone, and so is the kernel half, which is shared.
+pub struct AddressSpaceBuilder<'a, A: FrameAllocator + 'a> {
+    active_table: &'a mut ActivePageTable,
+    allocator: &'a mut A,
+    table: Option<InactivePageTable>,
+    temporary_page: Option<TemporaryPage>,
+    // the frames allocated by `map`
+    frames: AllocatedFrames,
+}
+
+impl<'a, A> AddressSpaceBuilder<'a, A> where A: FrameAllocator {
+    pub fn new(active_table: &'a mut ActivePageTable, allocator: &'a mut A)
+        -> Result<AddressSpaceBuilder<'a, A>, MapError>
+    {
+        let mut temporary_page = TemporaryPage::new(TEMPORARY_PAGE, active_table, allocator);
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                temporary_page.release(allocator);
+                return Err(MapError::FrameAllocationFailed);
+            }
+        };
+        let mut table = InactivePageTable::new(frame, active_table, &mut temporary_page);
+        table.share_kernel_mappings(active_table, &mut temporary_page);
+
+        Ok(AddressSpaceBuilder {
+            active_table: active_table,
+            allocator: allocator,
+            table: Some(table),
+            temporary_page: Some(temporary_page),
+            frames: AllocatedFrames::new(),
+        })
+    }
+
+    /// Maps `page` to `frame` in the new table.
+    pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags)
+        -> Result<(), MapError>
+    {
+        let allocator = &mut *self.allocator;
+        let mut session = self.active_table.edit(self.table.as_mut().unwrap(),
+                                                 self.temporary_page.as_mut().unwrap());
+        let flush = try!(session.try_map_to(page, frame, flags, allocator));
+        flush.ignore_in_session(&session);
+        Ok(())
+    }
+
+    /// Maps `page` to a newly allocated frame in the new table. Fails with
+    /// `MapError::FrameAllocationFailed` if no frame is left, or if the
+    /// builder already allocated `MAX_BUILDER_FRAMES` frames and can't record
+    /// another one for the rollback.
+    pub fn map(&mut self, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+        if self.frames.is_full() {
+            return Err(MapError::FrameAllocationFailed);
+        }
+        let frame = match self.allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return Err(MapError::FrameAllocationFailed),
+        };
+        let number = frame.number;
+
+        match self.map_to(page, frame, flags) {
+            Ok(()) => {
+                self.frames.push(&Frame { number: number });
+                Ok(())
+            }
+            Err(error) => {
+                self.allocator.deallocate_frame(Frame { number: number });
+                Err(error)
+            }
+        }
+    }
+
+    /// Finishes the table and returns it.
+    pub fn commit(mut self) -> InactivePageTable {
+        self.temporary_page.take().unwrap().release(self.allocator);
+        self.table.take().unwrap()
+    }
+}
+
+impl<'a, A> Drop for AddressSpaceBuilder<'a, A> where A: FrameAllocator {
+    fn drop(&mut self) {
+        let mut table = match self.table.take() {
+            Some(table) => table,
+            None => return, // committed
+        };
+        let mut temporary_page = self.temporary_page.take().unwrap();
+
+        {
+            let mut session = self.active_table.edit(&mut table, &mut temporary_page);
+            free_page_tables(session.p4_mut(), self.allocator);
+        }
+        self.frames.release(self.allocator);
+        temporary_page.release(self.allocator);
+        self.allocator.deallocate_frame(table.p4_frame);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::AreaFrameAllocator;
+    use memory::test_support::small_allocator;
+    use memory::paging::entry::WRITABLE;
+
+    /// Hands out at most `remaining` frames, to simulate running out of
+    /// memory.
+    struct LimitedAllocator {
+        inner: AreaFrameAllocator,
+        remaining: usize,
+    }
+
+    impl FrameAllocator for LimitedAllocator {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            if self.remaining == 0 {
+                return None;
+            }
+            self.remaining -= 1;
+            self.inner.allocate_frame()
+        }
+
+        fn deallocate_frame(&mut self, frame: Frame) {
+            self.inner.deallocate_frame(frame)
+        }
+    }
+
+    #[test]
+    fn destroying_an_address_space_frees_its_frames() {
+        let mut allocator = small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let used_frames = allocator.used_frames();
+
+        let mut address_space = AddressSpace::new(&mut active_table, &mut allocator);
+        for i in 0..3 {
+            let page = Page::containing_address(0x40_0000 + i * 0x1000);
+            address_space.map(page, WRITABLE, &mut active_table, &mut allocator);
+        }
+        let page = Page::containing_address(0x40_1000);
+        address_space.unmap(page, &mut active_table, &mut allocator).unwrap();
+
+        assert!(address_space.translate(0x40_0000, &mut active_table).is_some());
+        assert_eq!(address_space.translate(0x40_1000, &mut active_table), None);
+        assert_eq!(address_space.resident_frames(&mut active_table), 2);
+        assert_eq!(active_table.translate(0x40_0000), None);
+
+        address_space.destroy(&mut active_table, &mut allocator);
+        // only the tables mapping the temporary page in the active table stay
+        assert_eq!(allocator.used_frames(), used_frames + 3);
+    }
+
+    #[test]
+    fn kernel_mappings_made_later_appear_in_existing_address_spaces() {
+        use memory::paging::region::DYNAMIC_REGION_START;
+
+        let mut allocator = small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        assert_eq!(active_table.preallocate_kernel_tables(&mut allocator), 2);
+        let mut address_space = AddressSpace::new(&mut active_table, &mut allocator);
+
+        // e.g. the MMIO window of a late driver
+        let page = Page::containing_address(DYNAMIC_REGION_START + 0x1000);
+        active_table.map_to(page, Frame { number: 0xfee00 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+        // a slot without a preallocated P3 table isn't shared
+        let unshared_page = Page::containing_address(0xffff_9000_0000_0000);
+        active_table.map_to(unshared_page, Frame { number: 0x1000 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        assert_eq!(address_space.translate(page.start_address() + 0x20, &mut active_table),
+                   Some(0xfee0_0020));
+        assert_eq!(address_space.translate(unshared_page.start_address(), &mut active_table),
+                   None);
+        address_space.destroy(&mut active_table, &mut allocator);
+    }
+
+    #[test]
+    fn frames_allocated_before_running_out_are_returned() {
+        let mut allocator = LimitedAllocator { inner: small_allocator(), remaining: 10 };
+        let mut frames = AllocatedFrames::new();
+
+        // like `AddressSpaceBuilder::map` until the allocator runs dry
+        while let Some(frame) = allocator.allocate_frame() {
+            frames.push(&frame);
+        }
+        assert_eq!(allocator.inner.used_frames(), 10);
+
+        frames.release(&mut allocator);
+        assert_eq!(allocator.inner.used_frames(), 0);
+        assert_eq!(frames.len, 0);
+    }
+
+    #[test]
+    fn a_full_record_is_detected_before_allocating() {
+        let mut allocator = small_allocator();
+        let mut frames = AllocatedFrames::new();
+        for _ in 0..MAX_BUILDER_FRAMES {
+            assert!(!frames.is_full());
+            frames.push(&allocator.allocate_frame().unwrap());
+        }
+        assert!(frames.is_full());
+
+        frames.release(&mut allocator);
+        assert!(!frames.is_full());
+        assert_eq!(allocator.used_frames(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "without calling `destroy`")]
+    fn dropping_an_address_space_without_destroying_it_panics() {
+        let mut allocator = small_allocator();
+        let p4_frame = allocator.allocate_frame().unwrap();
+        let temporary_page = TemporaryPage::unchecked(TEMPORARY_PAGE, &mut allocator);
+        let _space = AddressSpace {
+            table: Some(InactivePageTable { p4_frame: p4_frame }),
+            temporary_page: Some(temporary_page),
+        };
+    }
+}
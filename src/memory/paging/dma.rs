@@ -0,0 +1,164 @@
+use super::{Page, ActivePageTable, VirtualAddress, PhysicalAddress, virtual_pointer};
+use super::entry::*;
+use super::region::{VirtualRegionManager, RegionPurpose};
+use memory::{PAGE_SIZE, FrameAllocator, FrameRange, FrameZone};
+use core::slice;
+
+
+/// A zeroed, physically contiguous buffer for device DMA. It lies below 4GiB,
+/// so that devices with 32 bit addressing can reach it, and is mapped
+/// uncached into a device window of its own. Its pages are pinned, so they
+/// can't be swapped out or moved.
+///
+/// There's no global allocator to return the frames to, so the buffer must
+/// be freed explicitly with `free`; dropping a buffer that wasn't freed
+/// panics in debug builds.
+#[must_use = "the frames of a DMA buffer leak unless it is freed"]
+pub struct DmaBuffer {
+    // only `None` once `free` took them
+    frames: Option<FrameRange>,
+    start: VirtualAddress,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `size` bytes. Returns `None` if the
+    /// allocator can't provide enough contiguous frames below 4GiB or there
+    /// is no virtual region left to map them.
+    pub fn alloc<A>(size: usize,
+                    regions: &mut VirtualRegionManager,
+                    active_table: &mut ActivePageTable,
+                    allocator: &mut A)
+                    -> Option<DmaBuffer>
+        where A: FrameAllocator
+    {
+        assert!(size > 0, "cannot allocate an empty DMA buffer");
+        let count = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        let ceiling = FrameZone::Dma32.frames().end();
+        let frames = match allocator.allocate_contiguous(count, &ceiling) {
+            Some(frames) => frames,
+            None => return None,
+        };
+        let start = match regions.alloc_region(count * PAGE_SIZE, RegionPurpose::DeviceWindow) {
+            Ok(start) => start,
+            Err(_) => {
+                for frame in frames.iter() {
+                    allocator.deallocate_frame(frame);
+                }
+                return None;
+            }
+        };
+
+        let flags = WRITABLE | NO_CACHE | NO_EXECUTE | GLOBAL;
+        for (i, frame) in frames.iter().enumerate() {
+            let page = Page::containing_address(start + i * PAGE_SIZE);
+            active_table.map_to(page, frame, flags, allocator).flush(active_table);
+            // the device holds the physical address
+            active_table.set_page_state(page, PageState::Pinned);
+        }
+
+        let mut buffer = DmaBuffer {
+            frames: Some(frames),
+            start: start,
+            size: size,
+        };
+        for byte in buffer.as_mut_slice() {
+            *byte = 0;
+        }
+        Some(buffer)
+    }
+
+    /// Returns the physical address of the buffer, to program the device with.
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.frames().start().start_address()
+    }
+
+    /// Returns the virtual address of the buffer.
+    pub fn virtual_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    pub fn frames(&self) -> FrameRange {
+        self.frames.unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(virtual_pointer(self.start), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(virtual_pointer(self.start), self.size) }
+    }
+
+    /// Unpins and unmaps the buffer, releases its device window and returns
+    /// its frames to `allocator`. The device must not access the buffer
+    /// anymore.
+    pub fn free<A>(mut self,
+                   regions: &mut VirtualRegionManager,
+                   active_table: &mut ActivePageTable,
+                   allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let frames = self.frames.take().unwrap();
+        for i in 0..frames.len() {
+            let page = Page::containing_address(self.start + i * PAGE_SIZE);
+            active_table.set_page_state(page, PageState::Normal);
+            active_table.unmap_and_free(page, allocator)
+                .expect("failed to unmap the DMA buffer").flush(active_table);
+        }
+        regions.release(self.start);
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        debug_assert!(self.frames.is_none(),
+                      "DMA buffer dropped without calling `free`, its frames leak");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::{small_allocator, active_table, physical_pointer};
+
+    #[test]
+    fn buffer_frames_are_contiguous_below_4gib_and_freed_again() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let mut regions = VirtualRegionManager::new();
+        let mut buffer = DmaBuffer::alloc(3 * PAGE_SIZE, &mut regions, &mut active_table,
+                                          &mut allocator).unwrap();
+
+        let frames = buffer.frames();
+        assert_eq!(frames.len(), 3);
+        assert!(frames.end() <= FrameZone::Dma32.frames().end());
+        assert_eq!(buffer.physical_address(), frames.start().start_address());
+        for (i, frame) in frames.iter().enumerate() {
+            let page = Page::containing_address(buffer.virtual_address() + i * PAGE_SIZE);
+            assert_eq!(active_table.translate_page(page), Some(frame));
+        }
+
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+        for (i, byte) in buffer.as_mut_slice().iter_mut().enumerate() {
+            *byte = (i / PAGE_SIZE) as u8 + 1;
+        }
+        // what the device reads
+        for (i, frame) in frames.iter().enumerate() {
+            let byte = unsafe { *physical_pointer::<u8>(frame.start_address() + 0x123) };
+            assert_eq!(byte, i as u8 + 1);
+        }
+
+        let used_frames = allocator.used_frames();
+        buffer.free(&mut regions, &mut active_table, &mut allocator);
+        assert_eq!(allocator.used_frames(), used_frames - 3);
+        assert!(regions.regions().is_empty());
+        let ceiling = FrameZone::Dma32.frames().end();
+        assert_eq!(allocator.allocate_contiguous(3, &ceiling), Some(frames));
+    }
+}
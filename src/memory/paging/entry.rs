@@ -1,5 +1,8 @@
 use memory::Frame; // needed later
 use multiboot2::ElfSection;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use hw;
+use cpu_features;
 
 pub struct Entry(u64);
 
@@ -24,12 +27,114 @@ impl Entry {
         }
     }
 
+    /// Points the entry to `frame`. In debug builds, present entries are
+    /// checked for reserved bits, which would cause a page fault with
+    /// `MALFORMED_TABLE` on every access that is much harder to trace back.
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
         self.0 = (frame.start_address() as u64) | flags.bits();
+        if cfg!(debug_assertions) && flags.contains(PRESENT) {
+            self.check_reserved_bits();
+        }
+    }
+
+    fn check_reserved_bits(&self) {
+        assert!(!self.flags().contains(NO_EXECUTE) || hw::nxe_enabled(),
+            "NX requested but NXE disabled");
+
+        let bits = cpu_features::physical_address_bits() as u64;
+        let reserved_address_bits = (1 << 52) - (1 << bits);
+        assert!(self.0 & reserved_address_bits == 0,
+            "frame address {:#x} exceeds MAXPHYADDR ({} bits), reserved bits {:#x} are set",
+            self.0 & 0x000fffff_fffff000, bits, self.0 & reserved_address_bits);
+    }
+
+    /// Returns the flags a demand paged entry is populated with.
+    pub fn demand_flags(&self) -> Option<EntryFlags> {
+        let flags = self.flags();
+        if flags.contains(DEMAND_PAGED) && !flags.contains(PRESENT) {
+            Some(flags - DEMAND_PAGED)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the entry as reserved but not yet backed by a frame. It is
+    /// populated with `flags` on the first access.
+    pub fn set_demand_paged(&mut self, flags: EntryFlags) {
+        self.0 = ((flags - PRESENT) | DEMAND_PAGED).bits();
+    }
+
+    /// Returns the swap slot of a swapped out entry.
+    pub fn swap_slot(&self) -> Option<usize> {
+        let flags = self.flags();
+        if flags.contains(SWAPPED) && !flags.contains(PRESENT) {
+            Some(((self.0 & 0x000fffff_fffff000) >> 12) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the entry as swapped out to `slot`. The slot is stored in the
+    /// address bits, `flags` are kept so that the mapping can be restored.
+    pub fn set_swapped(&mut self, slot: usize, flags: EntryFlags) {
+        assert!(slot <= MAX_SWAP_SLOT, "swap slot {:#x} is too large", slot);
+        self.0 = ((slot as u64) << 12) | ((flags - PRESENT) | SWAPPED).bits();
+    }
+
+    /// Returns the state the software bits of the entry encode, or `None` if
+    /// the entry is unused.
+    pub fn page_state(&self) -> Option<PageState> {
+        let flags = self.flags();
+        if self.is_unused() {
+            None
+        } else if self.swap_slot().is_some() {
+            Some(PageState::Swapped)
+        } else if self.demand_flags().is_some() {
+            Some(PageState::DemandPaged)
+        } else if flags.contains(COPY_ON_WRITE) {
+            Some(PageState::CopyOnWrite)
+        } else if flags.contains(PINNED) {
+            Some(PageState::Pinned)
+        } else {
+            Some(PageState::Normal)
+        }
+    }
+
+    /// Sets the state of a present entry, keeping its frame and all other
+    /// flags. Swapped out and demand paged entries aren't present, they are
+    /// created with `set_swapped` and `set_demand_paged`.
+    pub fn set_page_state(&mut self, state: PageState) {
+        assert!(self.flags().contains(PRESENT), "page state of a non-present entry");
+        let state_flags = match state {
+            PageState::Normal => EntryFlags::empty(),
+            PageState::CopyOnWrite => COPY_ON_WRITE,
+            PageState::Pinned => PINNED,
+            PageState::Swapped | PageState::DemandPaged => {
+                panic!("{:?} can't be set on a present entry", state)
+            }
+        };
+        self.0 = (self.0 & !(COPY_ON_WRITE | PINNED).bits()) | state_flags.bits();
     }
 }
 
+/// The software defined state of a page, stored in bits of its entry that the
+/// CPU ignores. The states are mutually exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageState {
+    /// A present page without special handling.
+    Normal,
+    /// A present page shared read-only, which is copied on the first write.
+    CopyOnWrite,
+    /// A present page whose frame must not be swapped out or moved, e.g.
+    /// because a device accesses it.
+    Pinned,
+    /// A page swapped out to a swap slot, see `Entry::set_swapped`.
+    Swapped,
+    /// A page populated on the first access, see `Entry::set_demand_paged`.
+    DemandPaged,
+}
+
 bitflags! {
     flags EntryFlags: u64 {
         const PRESENT =         1 << 0,
@@ -41,10 +146,20 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        // ignored by the CPU, marks a non-present page as swapped out
+        const SWAPPED =         1 << 9,
+        // ignored by the CPU, marks a non-present page as populated on demand
+        const DEMAND_PAGED =    1 << 10,
+        // ignored by the CPU, encode the `PageState` of a present page
+        const COPY_ON_WRITE =   1 << 52,
+        const PINNED =          1 << 53,
         const NO_EXECUTE =      1 << 63,
     }
 }
 
+/// The largest swap slot that fits into the address bits of an entry.
+pub const MAX_SWAP_SLOT: usize = (1 << 40) - 1;
+
 impl EntryFlags {
     pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
         use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE,
@@ -66,3 +181,168 @@ impl EntryFlags {
         flags
     }
 }
+
+/// In P1 entries bit 7 doesn't mark a huge page but selects the upper half of
+/// the page attribute table.
+pub const PAT_4KIB: EntryFlags = HUGE_PAGE;
+
+/// The PAT layout programmed by `init_page_attribute_table`. Entries 0-3 keep
+/// their power-on defaults (WB, WT, UC-, UC) so that PWT/PCD alone behave as
+/// usual, entry 4 is changed from WB to WC.
+const PAT_LAYOUT: u64 = 0x0007_0401_0007_0406;
+
+static WRITE_COMBINING_AVAILABLE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Programs the page attribute table so that `CacheType::WriteCombining` is
+/// available. Without it write-combining mappings fall back to uncached ones.
+pub fn init_page_attribute_table() {
+    if ::cpu_features::has_pat() {
+        hw::write_pat(PAT_LAYOUT);
+        WRITE_COMBINING_AVAILABLE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The memory type of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    WriteCombining,
+    Uncached,
+}
+
+impl CacheType {
+    /// Returns the PWT, PCD and PAT bits selecting this memory type in a P1
+    /// entry, according to the layout set up by `init_page_attribute_table`.
+    pub fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheType::WriteBack => EntryFlags::empty(),
+            CacheType::WriteThrough => WRITE_THROUGH,
+            CacheType::WriteCombining => {
+                if WRITE_COMBINING_AVAILABLE.load(Ordering::Relaxed) {
+                    PAT_4KIB
+                } else {
+                    NO_CACHE | WRITE_THROUGH
+                }
+            }
+            CacheType::Uncached => NO_CACHE | WRITE_THROUGH,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_NUMBER: usize = 0x1234;
+
+    fn present_entry() -> Entry {
+        let mut entry = Entry(0);
+        entry.set(Frame { number: FRAME_NUMBER }, PRESENT | WRITABLE | USER_ACCESSIBLE);
+        entry
+    }
+
+    #[test]
+    fn present_states_round_trip() {
+        for &state in &[PageState::Normal, PageState::CopyOnWrite, PageState::Pinned] {
+            let mut entry = present_entry();
+            entry.set_page_state(state);
+
+            assert_eq!(entry.page_state(), Some(state));
+            assert_eq!(entry.pointed_frame(), Some(Frame { number: FRAME_NUMBER }));
+            assert!(entry.flags().contains(PRESENT | WRITABLE | USER_ACCESSIBLE));
+        }
+    }
+
+    #[test]
+    fn present_states_replace_each_other() {
+        let mut entry = present_entry();
+        entry.set_page_state(PageState::CopyOnWrite);
+        entry.set_page_state(PageState::Pinned);
+        assert_eq!(entry.page_state(), Some(PageState::Pinned));
+        assert!(!entry.flags().contains(COPY_ON_WRITE));
+
+        entry.set_page_state(PageState::Normal);
+        assert_eq!(entry.page_state(), Some(PageState::Normal));
+        assert_eq!(entry.0, present_entry().0);
+    }
+
+    #[test]
+    fn swapped_and_demand_paged_states_are_decoded() {
+        let mut entry = Entry(0);
+        assert_eq!(entry.page_state(), None);
+
+        entry.set_swapped(7, WRITABLE);
+        assert_eq!(entry.page_state(), Some(PageState::Swapped));
+        assert_eq!(entry.swap_slot(), Some(7));
+        assert_eq!(entry.pointed_frame(), None);
+
+        entry.set_demand_paged(PRESENT | WRITABLE);
+        assert_eq!(entry.page_state(), Some(PageState::DemandPaged));
+        assert_eq!(entry.demand_flags(), Some(WRITABLE));
+        assert_eq!(entry.swap_slot(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be set on a present entry")]
+    fn swapped_state_cannot_be_set_on_a_present_entry() {
+        present_entry().set_page_state(PageState::Swapped);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds MAXPHYADDR")]
+    fn frame_addresses_beyond_maxphyaddr_are_rejected() {
+        Entry(0).set(Frame { number: 1 << (51 - 12) }, PRESENT);
+    }
+
+    #[test]
+    #[should_panic(expected = "NX requested but NXE disabled")]
+    fn no_execute_is_rejected_while_nxe_is_disabled() {
+        assert!(!hw::nxe_enabled());
+        Entry(0).set(Frame { number: FRAME_NUMBER }, PRESENT | NO_EXECUTE);
+    }
+
+    /// Returns the memory type `PAT_LAYOUT` assigns to the PAT entry that the
+    /// PWT, PCD and PAT bits of `flags` select.
+    fn memory_type(flags: EntryFlags) -> u8 {
+        let index = (flags.contains(PAT_4KIB) as u64) << 2
+            | (flags.contains(NO_CACHE) as u64) << 1
+            | flags.contains(WRITE_THROUGH) as u64;
+        (PAT_LAYOUT >> (index * 8)) as u8
+    }
+
+    #[test]
+    fn write_back_sets_no_cache_bits() {
+        let flags = CacheType::WriteBack.flags();
+        assert_eq!(flags, EntryFlags::empty());
+        assert_eq!(memory_type(flags), 6);
+    }
+
+    #[test]
+    fn write_through_sets_pwt() {
+        let flags = CacheType::WriteThrough.flags();
+        assert_eq!(flags, WRITE_THROUGH);
+        assert_eq!(memory_type(flags), 4);
+    }
+
+    #[test]
+    fn uncached_sets_pcd_and_pwt() {
+        let flags = CacheType::Uncached.flags();
+        assert_eq!(flags, NO_CACHE | WRITE_THROUGH);
+        assert_eq!(memory_type(flags), 0);
+    }
+
+    #[test]
+    fn write_combining_selects_pat_entry_4_once_the_pat_is_programmed() {
+        // the only test touching the PAT, so nothing else races on the flag
+        let flags = CacheType::WriteCombining.flags();
+        assert_eq!(flags, CacheType::Uncached.flags());
+
+        init_page_attribute_table();
+        if ::cpu_features::has_pat() {
+            let flags = CacheType::WriteCombining.flags();
+            assert_eq!(flags, PAT_4KIB);
+            assert_eq!(memory_type(flags), 1);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use super::{Page, Mapper, VirtualAddress};
+use hw;
+
+
+bitflags! {
+    /// The error code the CPU pushes for a page fault.
+    flags PageFaultErrorCode: u64 {
+        const PROTECTION_VIOLATION = 1 << 0,
+        const CAUSED_BY_WRITE =      1 << 1,
+        const USER_MODE =            1 << 2,
+        const MALFORMED_TABLE =      1 << 3,
+        const INSTRUCTION_FETCH =    1 << 4,
+    }
+}
+
+/// Why a page fault happened, as far as the page tables can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultKind {
+    /// The page is not mapped at all.
+    NotMapped,
+    /// The page was swapped out to the given swap slot.
+    Swapped(usize),
+    /// The page is reserved and populated on demand.
+    DemandPaged,
+    /// The page is present but the access violated its flags.
+    ProtectionViolation,
+    /// The page is the guard page below the given stack.
+    StackOverflow { stack_id: usize },
+}
+
+/// The number of guard pages a `GuardPages` registry can hold.
+pub const MAX_GUARD_PAGES: usize = 16;
+
+/// The guard pages below kernel stacks, so that faults on them can be
+/// reported as stack overflows. Entries are kept sorted by page number.
+pub struct GuardPages {
+    // (page number, stack id)
+    entries: [(usize, usize); MAX_GUARD_PAGES],
+    len: usize,
+}
+
+impl GuardPages {
+    pub fn new() -> GuardPages {
+        GuardPages {
+            entries: [(0, 0); MAX_GUARD_PAGES],
+            len: 0,
+        }
+    }
+
+    /// Registers `page` as the guard page of the stack `stack_id`.
+    pub fn register(&mut self, page: Page, stack_id: usize) {
+        match self.position(page) {
+            Ok(index) => self.entries[index].1 = stack_id,
+            Err(index) => {
+                assert!(self.len < MAX_GUARD_PAGES,
+                    "Guard page registry can hold only {} pages.", MAX_GUARD_PAGES);
+                for i in (index..self.len).rev() {
+                    self.entries[i + 1] = self.entries[i];
+                }
+                self.entries[index] = (page.number, stack_id);
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Removes `page`, e.g. when its stack is deallocated.
+    pub fn unregister(&mut self, page: Page) {
+        if let Ok(index) = self.position(page) {
+            for i in index..(self.len - 1) {
+                self.entries[i] = self.entries[i + 1];
+            }
+            self.len -= 1;
+        }
+    }
+
+    /// Returns the id of the stack `page` is the guard page of.
+    pub fn stack_of(&self, page: Page) -> Option<usize> {
+        self.position(page).ok().map(|index| self.entries[index].1)
+    }
+
+    fn position(&self, page: Page) -> Result<usize, usize> {
+        self.entries[..self.len].binary_search_by(|&(number, _)| number.cmp(&page.number))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultInfo {
+    pub address: VirtualAddress,
+    pub error_code: PageFaultErrorCode,
+    pub kind: PageFaultKind,
+}
+
+/// Decodes a page fault with the given error code. The faulting address is
+/// read from CR2, so this must be called before another page fault can occur.
+/// Faults on one of the `guards` are reported as stack overflows.
+pub fn decode_page_fault(mapper: &Mapper, guards: &GuardPages, error_code: u64)
+    -> PageFaultInfo
+{
+    decode_page_fault_at(mapper, guards, hw::read_cr2(), error_code)
+}
+
+/// Like `decode_page_fault`, but takes the faulting address instead of
+/// reading CR2. This allows classifying faults that were recorded earlier.
+pub fn decode_page_fault_at(mapper: &Mapper,
+                            guards: &GuardPages,
+                            address: VirtualAddress,
+                            error_code: u64)
+                            -> PageFaultInfo
+{
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    let kind = if error_code.contains(PROTECTION_VIOLATION) {
+        PageFaultKind::ProtectionViolation
+    } else {
+        let page = Page::containing_address(address);
+        if let Some(stack_id) = guards.stack_of(page) {
+            PageFaultKind::StackOverflow { stack_id: stack_id }
+        } else {
+            match mapper.swap_slot(page) {
+                Some(slot) => PageFaultKind::Swapped(slot),
+                None if mapper.demand_flags(page).is_some() => PageFaultKind::DemandPaged,
+                None => PageFaultKind::NotMapped,
+            }
+        }
+    };
+
+    PageFaultInfo {
+        address: address,
+        error_code: error_code,
+        kind: kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Mapper;
+    use hw;
+
+    // decoding these faults doesn't read the page tables, so the mapper is
+    // never dereferenced
+    fn mapper() -> Mapper {
+        unsafe { Mapper::new() }
+    }
+
+    #[test]
+    fn fault_on_guard_page_is_a_stack_overflow() {
+        let mut guards = GuardPages::new();
+        guards.register(Page::containing_address(0x7000), 3);
+        hw::with_mock(|hw| hw.cr2.set(0x7ff8));
+
+        let info = decode_page_fault(&mapper(), &guards, CAUSED_BY_WRITE.bits());
+
+        assert_eq!(info.address, 0x7ff8);
+        assert_eq!(info.error_code, CAUSED_BY_WRITE);
+        assert_eq!(info.kind, PageFaultKind::StackOverflow { stack_id: 3 });
+    }
+
+    #[test]
+    fn fault_on_present_page_is_a_protection_violation() {
+        let mut guards = GuardPages::new();
+        guards.register(Page::containing_address(0x7000), 3);
+        hw::with_mock(|hw| hw.cr2.set(0x7000));
+
+        let error_code = (PROTECTION_VIOLATION | INSTRUCTION_FETCH).bits();
+        let info = decode_page_fault(&mapper(), &guards, error_code);
+
+        assert_eq!(info.address, 0x7000);
+        assert_eq!(info.kind, PageFaultKind::ProtectionViolation);
+    }
+
+    #[test]
+    fn unregistered_guard_page_is_forgotten() {
+        let mut guards = GuardPages::new();
+        guards.register(Page::containing_address(0x7000), 3);
+        guards.register(Page::containing_address(0x3000), 1);
+        guards.unregister(Page::containing_address(0x7000));
+
+        assert_eq!(guards.stack_of(Page::containing_address(0x7000)), None);
+        assert_eq!(guards.stack_of(Page::containing_address(0x3000)), Some(1));
+    }
+}
@@ -1,31 +1,107 @@
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, Page, PageRangeInclusive, ActivePageTable,
+            Session, ENTRY_COUNT, RECURSIVE_INDEX, is_canonical, virtual_pointer};
 use super::entry::*;
+use super::walk::{Mapping, MappingIter, PageSize};
+use super::mmio::DIRECT_MAP_BASE;
 use super::table::{self, Table, Level4, Level1};
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
-use core::ptr::Unique;
+use memory::{PAGE_SIZE, Frame, FrameAllocator, FrameRange};
+use hw;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 
 
 pub struct Mapper {
-    p4: Unique<Table<Level4>>,
+    // the frames mapped at `DIRECT_MAP_BASE`, see `set_direct_map`
+    direct_map: Option<FrameRange>,
 }
 
 impl Mapper {
 
     pub unsafe fn new() -> Mapper {
         Mapper {
-            p4: Unique::new(table::P4),
+            direct_map: None,
         }
     }
 
+    /// Returns the frames mapped at `DIRECT_MAP_BASE`, if the direct map is
+    /// set up.
+    pub fn direct_map(&self) -> Option<FrameRange> {
+        self.direct_map
+    }
+
+    /// Records that `frames` are mapped at `DIRECT_MAP_BASE` plus their
+    /// physical address, so that `translate` can read page tables through the
+    /// direct map.
+    ///
+    /// # Safety
+    /// The frames must really be mapped there, in every address space this
+    /// mapper is used with.
+    pub unsafe fn set_direct_map(&mut self, frames: FrameRange) {
+        self.direct_map = Some(frames);
+    }
+
     pub fn p4(&self) -> &Table<Level4> {
-        unsafe { self.p4.get() }
+        unsafe { &*virtual_pointer(table::P4 as VirtualAddress) }
     }
 
     pub fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { self.p4.get_mut() }
+        unsafe { &mut *virtual_pointer(table::P4 as VirtualAddress) }
     }
 
+    /// Translates the given virtual address. Non-canonical addresses aren't
+    /// mapped anywhere, so `None` is returned for them.
+    /// Once the direct map is set up, the tables are read through it.
     pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        if !is_canonical(virtual_address) {
+            return None;
+        }
+        if self.direct_map.is_some() {
+            return self.translate_via_direct_map(virtual_address);
+        }
+        self.translate_recursive(virtual_address)
+    }
+
+    /// Like `translate`, but reads the P3, P2 and P1 tables through the
+    /// direct map instead of the recursive mapping, which saves the recursive
+    /// address lookups. Falls back to the recursive walk if the direct map
+    /// isn't set up or a table lies outside it.
+    pub fn translate_via_direct_map(&self, virtual_address: VirtualAddress)
+        -> Option<PhysicalAddress>
+    {
+        let direct_map = match self.direct_map {
+            Some(frames) if is_canonical(virtual_address) => frames,
+            _ => return self.translate_recursive(virtual_address),
+        };
+
+        let page = Page::containing_address(virtual_address);
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+        // the recursive entry points to the P4 table this mapper accesses
+        let mut table_frame = self.p4()[RECURSIVE_INDEX].pointed_frame().unwrap();
+        for (level, &index) in indices.iter().enumerate() {
+            if !direct_map.contains(&table_frame) {
+                return self.translate_recursive(virtual_address);
+            }
+            let table = unsafe {
+                &*virtual_pointer::<Table<Level1>>(DIRECT_MAP_BASE + table_frame.start_address())
+            };
+            let entry = &table[index];
+            let frame = match entry.pointed_frame() {
+                Some(frame) => frame,
+                None => return None,
+            };
+            if level == 3 || (level > 0 && entry.flags().contains(HUGE_PAGE)) {
+                // a P1 entry maps 4KiB, P2 and P3 huge entries 2MiB and 1GiB
+                let page_size = PAGE_SIZE << (9 * (3 - level));
+                return Some(frame.start_address() + virtual_address % page_size);
+            }
+            table_frame = frame;
+        }
+        unreachable!()
+    }
+
+    fn translate_recursive(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        if !is_canonical(virtual_address) {
+            return None;
+        }
         let offset = virtual_address % PAGE_SIZE;
         self.translate_page(Page::containing_address(virtual_address))
             .map(|frame| frame.number * PAGE_SIZE + offset)
@@ -70,43 +146,431 @@ impl Mapper {
           .or_else(huge_page)
     }
 
+    /// Translates the given virtual address and returns the flags of the entry
+    /// mapping it, in a single walk. For huge pages the flags are those of the
+    /// huge entry.
+    pub fn translate_with_flags(&self, virtual_address: VirtualAddress)
+        -> Option<(PhysicalAddress, EntryFlags)>
+    {
+        self.translate_level(virtual_address).map(|(frame, flags, size)| {
+            (frame.start_address() + virtual_address % size.bytes(), flags)
+        })
+    }
+
+    /// Returns the entry mapping the given virtual address: the first frame
+    /// it maps, its flags and the size of the page, i.e. whether it's a P1
+    /// entry or a huge P2 or P3 entry.
+    pub fn translate_level(&self, virtual_address: VirtualAddress)
+        -> Option<(Frame, EntryFlags, PageSize)>
+    {
+        if !is_canonical(virtual_address) {
+            return None;
+        }
+        let page = Page::containing_address(virtual_address);
+
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            let frame = p3_entry.pointed_frame().unwrap();
+            return Some((frame, p3_entry.flags(), PageSize::Size1GiB));
+        }
+
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return None,
+        };
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            let frame = p2_entry.pointed_frame().unwrap();
+            return Some((frame, p2_entry.flags(), PageSize::Size2MiB));
+        }
+
+        p2.next_table(page.p2_index())
+          .and_then(|p1| {
+              let p1_entry = &p1[page.p1_index()];
+              p1_entry.pointed_frame().map(|frame| (frame, p1_entry.flags(), PageSize::Size4KiB))
+          })
+    }
+
+    /// Iterates over all present mappings, see `MappingIter`.
+    pub fn iter_mappings(&self) -> MappingIter {
+        MappingIter::new(self.p4())
+    }
+
+    /// Sums the mapped bytes per protection class, counting huge pages with
+    /// their full size. Only the flags of the leaf entries are considered.
+    pub fn protection_summary(&self) -> ProtectionSummary {
+        let mut summary = ProtectionSummary::default();
+        for mapping in self.iter_mappings() {
+            summary.add(&mapping);
+        }
+        summary
+    }
+
+    /// Returns the number of P3, P2 and P1 table frames for which `filter`
+    /// returns true. The P4 table and the recursive entry aren't included.
+    pub fn count_table_frames<F>(&self, mut filter: F) -> usize
+        where F: FnMut(&Frame) -> bool
+    {
+        let mut count = 0;
+        let p4 = self.p4();
+        for p4_index in (0..ENTRY_COUNT).filter(|&index| index != RECURSIVE_INDEX) {
+            let p3 = match p4.next_table(p4_index) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            if filter(&p4[p4_index].pointed_frame().unwrap()) {
+                count += 1;
+            }
+            for p3_index in 0..ENTRY_COUNT {
+                let p2 = match p3.next_table(p3_index) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+                if filter(&p3[p3_index].pointed_frame().unwrap()) {
+                    count += 1;
+                }
+                for p2_index in 0..ENTRY_COUNT {
+                    if p2.next_table(p2_index).is_some() &&
+                       filter(&p2[p2_index].pointed_frame().unwrap()) {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    }
+
+    /// Returns true if `frame` holds the P4 table or one of the P3, P2 or P1
+    /// tables. This walks all tables.
+    pub fn is_table_frame(&self, frame: &Frame) -> bool {
+        self.p4()[RECURSIVE_INDEX].pointed_frame().as_ref() == Some(frame) ||
+            self.count_table_frames(|table_frame| table_frame == frame) > 0
+    }
+
+    /// Panics in debug builds if `frame` holds a page table. Paths that free
+    /// frames obtained from somewhere else than the allocator should call
+    /// this first, since freeing a table frame corrupts the address space.
+    pub fn assert_frame_not_table(&self, frame: &Frame) {
+        if cfg!(debug_assertions) {
+            assert!(!self.is_table_frame(frame),
+                    "frame {:?} is still used as a page table", frame);
+        }
+    }
+
+    /// Returns the first page that is mapped to `frame`, in address order.
+    /// This walks all mappings and is meant for debugging, don't use it on hot
+    /// paths.
+    pub fn find_page_mapping(&self, frame: &Frame) -> Option<Page> {
+        self.iter_mappings()
+            .filter_map(|mapping| mapping_page(&mapping, frame))
+            .next()
+    }
+
+    /// Returns the number of pages mapped to `frame`. Like
+    /// `find_page_mapping`, this walks all mappings.
+    pub fn count_page_mappings(&self, frame: &Frame) -> usize {
+        self.iter_mappings()
+            .filter(|mapping| mapping_page(mapping, frame).is_some())
+            .count()
+    }
+
+    /// Returns true if any page is mapped to `frame`, e.g. to check that a
+    /// frame reclaimed from outside the allocator can be freed safely. This
+    /// walks all mappings, so it takes O(mappings) time.
+    pub fn is_frame_mapped(&self, frame: &Frame) -> bool {
+        self.find_page_mapping(frame).is_some()
+    }
+
+    /// Prints all mappings, merging runs of pages that are contiguous in both
+    /// virtual and physical memory and have the same flags.
+    pub fn dump_mappings(&self) {
+        let mut run: Option<Mapping> = None;
+        let mut run_len = 0;
+
+        for mapping in self.iter_mappings() {
+            if let Some(start) = run {
+                if start.start + run_len == mapping.start &&
+                   start.frame + run_len == mapping.frame && start.flags == mapping.flags {
+                    run_len += mapping.size.bytes();
+                    continue;
+                }
+                print_mapping_run(start, run_len);
+            }
+            run = Some(mapping);
+            run_len = mapping.size.bytes();
+        }
+        if let Some(start) = run {
+            print_mapping_run(start, run_len);
+        }
+    }
+
+    /// Returns the flags of the entry that maps the given page, following
+    /// huge page entries in the P3 and P2 tables.
+    pub fn get_flags(&self, page: Page) -> Option<EntryFlags> {
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(p3_entry.flags());
+        }
+
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return None,
+        };
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(p2_entry.flags());
+        }
+
+        p2.next_table(page.p2_index())
+          .map(|p1| p1[page.p1_index()].flags())
+          .and_then(|flags| if flags.contains(PRESENT) { Some(flags) } else { None })
+    }
+
     pub fn map_to<A>(&mut self, 
                     page: Page, 
                     frame: Frame, 
                     flags: EntryFlags, 
                     allocator: &mut A)
+                    -> MapperFlush
         where A: FrameAllocator
     {
-        //let p4 = unsafe { &mut *P4 };
-        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+        match self.try_map_to(page, frame, flags, allocator) {
+            Ok(flush) => flush,
+            Err(error) => panic!("failed to map page at {:#x}: {:?}",
+                page.start_address(), error),
+        }
+    }
 
-        assert!(p1[page.p1_index()].is_unused());
-        p1[page.p1_index()].set(frame, flags | PRESENT);
+    /// Like `map_to`, but returns an error instead of panicking if a page
+    /// table can't be allocated or the page is already mapped.
+    pub fn try_map_to<A>(&mut self,
+                         page: Page,
+                         frame: Frame,
+                         flags: EntryFlags,
+                         allocator: &mut A)
+                         -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        debug_assert!(page.p4_index() != RECURSIVE_INDEX,
+            "page at {:#x} is in the recursive mapping region; mapping it would \
+             corrupt the page tables", page.start_address());
+
+        let p3 = try!(self.p4_mut().try_next_table_create(page.p4_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+        let p2 = try!(p3.try_next_table_create(page.p3_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+        let p1 = try!(p2.try_next_table_create(page.p2_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+
+        if !p1[page.p1_index()].is_unused() {
+            return Err(MapError::PageAlreadyMapped);
+        }
+        p1[page.p1_index()].set(frame, supported_flags(flags) | PRESENT);
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Maps a page of the given size starting at `page` to the frames starting
+    /// at `frame`. Both must be aligned to the page size. 1GiB pages are only
+    /// available if the CPU supports them.
+    pub fn try_map_to_huge<A>(&mut self,
+                              page: Page,
+                              frame: Frame,
+                              size: PageSize,
+                              flags: EntryFlags,
+                              allocator: &mut A)
+                              -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        if size == PageSize::Size4KiB {
+            return self.try_map_to(page, frame, flags, allocator);
+        }
+        if !page.is_aligned_to(size) || !frame.is_aligned_to(size) {
+            return Err(MapError::MisalignedForHugePage);
+        }
+        debug_assert!(page.p4_index() != RECURSIVE_INDEX,
+            "page at {:#x} is in the recursive mapping region; mapping it would \
+             corrupt the page tables", page.start_address());
+
+        let p3 = try!(self.p4_mut().try_next_table_create(page.p4_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+        let entry = match size {
+            PageSize::Size4KiB => unreachable!(),
+            PageSize::Size1GiB => &mut p3[page.p3_index()],
+            PageSize::Size2MiB => {
+                let p2 = try!(p3.try_next_table_create(page.p3_index(), allocator)
+                    .ok_or(MapError::FrameAllocationFailed));
+                &mut p2[page.p2_index()]
+            }
+        };
+
+        if !entry.is_unused() {
+            return Err(MapError::PageAlreadyMapped);
+        }
+        entry.set(frame, supported_flags(flags) | PRESENT | HUGE_PAGE);
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Maps `frames` to the virtual range starting at `virt_base`, using the
+    /// largest page size that both addresses are aligned to and that fits
+    /// into the rest of the range: 1GiB pages if the CPU supports them, then
+    /// 2MiB and 4KiB pages. On error the pages mapped so far stay mapped.
+    ///
+    /// The pages must not be mapped yet, so no TLB flush is needed.
+    pub fn map_range_best_fit<A>(&mut self,
+                                 frames: FrameRange,
+                                 virt_base: VirtualAddress,
+                                 flags: EntryFlags,
+                                 allocator: &mut A)
+                                 -> Result<PageSizeCounts, MapError>
+        where A: FrameAllocator
+    {
+        self.map_range_with_max_size(frames, virt_base, PageSize::Size1GiB, flags, allocator)
+    }
+
+    /// Like `map_range_best_fit`, but uses no pages larger than `max_size`.
+    /// Returns how many pages of each size were mapped.
+    pub fn map_range_with_max_size<A>(&mut self,
+                                      frames: FrameRange,
+                                      virt_base: VirtualAddress,
+                                      max_size: PageSize,
+                                      flags: EntryFlags,
+                                      allocator: &mut A)
+                                      -> Result<PageSizeCounts, MapError>
+        where A: FrameAllocator
+    {
+        assert!(virt_base % PAGE_SIZE == 0, "virtual base {:#x} is not page aligned", virt_base);
+
+        let end = frames.end().number;
+        let mut frame = frames.start();
+        let mut page = Page::containing_address(virt_base);
+        let mut counts = PageSizeCounts::default();
+        while frame.number < end {
+            let remaining = end - frame.number;
+            let size = [PageSize::Size1GiB, PageSize::Size2MiB].iter()
+                .cloned()
+                .filter(|&size| size.bytes() <= max_size.bytes())
+                .filter(|&size| size != PageSize::Size1GiB || ::cpu_features::has_1gib_pages())
+                .find(|&size| {
+                    remaining >= size.bytes() / PAGE_SIZE
+                        && frame.is_aligned_to(size) && page.is_aligned_to(size)
+                })
+                .unwrap_or(PageSize::Size4KiB);
+
+            let next_frame = Frame { number: frame.number + size.bytes() / PAGE_SIZE };
+            // unused entries are never cached, so there is nothing to flush
+            try!(self.try_map_to_huge(page, frame, size, flags, allocator)).ignore();
+            page.number += size.bytes() / PAGE_SIZE;
+            frame = next_frame;
+            counts.add(size);
+        }
+        Ok(counts)
+    }
+
+    /// Maps `page` to `frame` with the given memory type. Any cache control
+    /// bits in `flags` are replaced by the ones selecting `cache`.
+    pub fn map_to_with_cache<A>(&mut self,
+                                page: Page,
+                                frame: Frame,
+                                flags: EntryFlags,
+                                cache: CacheType,
+                                allocator: &mut A)
+                                -> MapperFlush
+        where A: FrameAllocator
+    {
+        let flags = flags - (WRITE_THROUGH | NO_CACHE | PAT_4KIB) | cache.flags();
+        self.map_to(page, frame, flags, allocator)
     }
 
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+        -> MapperFlush
         where A: FrameAllocator
     {
         let frame = allocator.allocate_frame().expect("out of memory");
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// Like `map`, but takes the data frame from `data_allocator` and the
+    /// frames for new page tables from `table_allocator`. This allows e.g.
+    /// taking the frames of a DMA buffer from a constrained allocator while the
+    /// page tables can lie anywhere.
+    pub fn map_with<D, T>(&mut self,
+                          page: Page,
+                          flags: EntryFlags,
+                          data_allocator: &mut D,
+                          table_allocator: &mut T)
+                          -> MapperFlush
+        where D: FrameAllocator, T: FrameAllocator
+    {
+        let frame = data_allocator.allocate_frame().expect("out of memory");
+        self.map_to(page, frame, flags, table_allocator)
+    }
+
     pub fn identity_map<A>(&mut self,
                        frame: Frame,
                        flags: EntryFlags,
                        allocator: &mut A)
+                       -> MapperFlush
         where A: FrameAllocator
     {
         let page = Page::containing_address(frame.start_address());
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// Changes the flags of an existing 4KiB mapping, keeping its frame.
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
+        let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("mapping code does not support huge pages");
+        let frame = p1[page.p1_index()].pointed_frame().expect("page is not mapped");
+        // the page state is kept, see `set_page_state`
+        let state = p1[page.p1_index()].flags() & (COPY_ON_WRITE | PINNED);
+        p1[page.p1_index()].set(frame, supported_flags(flags) | state | PRESENT);
+        MapperFlush::new(page)
+    }
+
+    /// Points an existing 4KiB mapping at `frame`, keeping its flags, and
+    /// returns the frame it was mapped to. The contents aren't copied, see
+    /// `ActivePageTable::remap` for that. Pinned pages can't be moved.
+    pub fn replace_frame(&mut self, page: Page, frame: Frame)
+        -> Result<(Frame, MapperFlush), MapError>
+    {
+        try!(self.check_not_pinned(page));
+        let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("mapping code does not support huge pages");
+        let entry = &mut p1[page.p1_index()];
+        let old_frame = entry.pointed_frame().expect("page is not mapped");
+        let flags = entry.flags();
+        entry.set(frame, flags);
+        Ok((old_frame, MapperFlush::new(page)))
+    }
+
+    /// Unmaps `page` and returns the frame it was mapped to. The frame isn't
+    /// freed, so it can be mapped elsewhere or handed on; use
+    /// `unmap_and_free` to return it to the allocator. Fails with
+    /// `MapError::PageNotMapped` if the page isn't mapped and with
+    /// `PagePinned` if it's pinned; pinned pages have to be unpinned with
+    /// `set_page_state` first. The mapping is left alone in both cases.
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+        -> Result<(Frame, MapperFlush), MapError>
         where A: FrameAllocator
     {
-        assert!(self.translate(page.start_address()).is_some());
+        if self.translate(page.start_address()).is_none() {
+            return Err(MapError::PageNotMapped);
+        }
+        try!(self.check_not_pinned(page));
 
         let p1 = self.p4_mut()
                     .next_table_mut(page.p4_index())
@@ -115,11 +579,983 @@ impl Mapper {
                     .expect("mapping code does not support huge pages");
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
-        unsafe {
-            ::x86::tlb::flush(page.start_address());
-        }
         // TODO free p(1,2,3) table if empty
-        //allocator.deallocate_frame(frame);
+        Ok((frame, MapperFlush::new(page)))
+    }
+
+    /// Unmaps `page` and frees the frame it was mapped to. Fails like
+    /// `unmap`, without freeing anything.
+    pub fn unmap_and_free<A>(&mut self, page: Page, allocator: &mut A)
+        -> Result<MapperFlush, MapError>
+        where A: FrameAllocator
+    {
+        let (frame, flush) = try!(self.unmap(page, allocator));
+        self.assert_frame_not_table(&frame);
+        allocator.deallocate_frame(frame);
+        Ok(flush)
+    }
+
+    /// Creates all page tables needed to map the given pages, without mapping
+    /// any of them. Mapping a page of the range afterwards doesn't allocate,
+    /// e.g. in an interrupt handler.
+    pub fn populate_page_tables<A>(&mut self, pages: PageRangeInclusive, allocator: &mut A)
+        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let mut page = pages.start;
+        while page.number <= pages.end.number {
+            debug_assert!(page.p4_index() != RECURSIVE_INDEX,
+                "page at {:#x} is in the recursive mapping region", page.start_address());
+
+            let p3 = try!(self.p4_mut().try_next_table_create(page.p4_index(), allocator)
+                .ok_or(MapError::FrameAllocationFailed));
+            let p2 = try!(p3.try_next_table_create(page.p3_index(), allocator)
+                .ok_or(MapError::FrameAllocationFailed));
+            try!(p2.try_next_table_create(page.p2_index(), allocator)
+                .ok_or(MapError::FrameAllocationFailed));
+
+            // continue with the first page of the next P1 table
+            page.number = (page.number | 0o777) + 1;
+        }
+        Ok(())
     }
 
-}
\ No newline at end of file
+    /// Reserves the given pages without allocating frames for them. Only the
+    /// page tables are created; whole 2MiB blocks are reserved by a single P2
+    /// entry, so even large regions need few frames. A page is populated with
+    /// `flags` by `ActivePageTable::populate_on_fault` when it's first accessed.
+    /// Fails with `MapError::PageAlreadyMapped` if a page of the range is
+    /// mapped or reserved already and with `FrameAllocationFailed` if a page
+    /// table can't be allocated; the pages before it stay reserved.
+    pub fn reserve_region<A>(&mut self,
+                             pages: PageRangeInclusive,
+                             flags: EntryFlags,
+                             allocator: &mut A)
+                             -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert!(!flags.contains(HUGE_PAGE), "reserved regions can't use huge pages");
+        let end = pages.end.number;
+        let mut number = pages.start.number;
+
+        while number <= end {
+            let page = Page { number: number };
+            debug_assert!(page.p4_index() != RECURSIVE_INDEX,
+                "page at {:#x} is in the recursive mapping region", page.start_address());
+
+            let p3 = try!(self.p4_mut().try_next_table_create(page.p4_index(), allocator)
+                .ok_or(MapError::FrameAllocationFailed));
+            let p2 = try!(p3.try_next_table_create(page.p3_index(), allocator)
+                .ok_or(MapError::FrameAllocationFailed));
+
+            if page.p1_index() == 0 && number + ENTRY_COUNT - 1 <= end &&
+               p2[page.p2_index()].is_unused() {
+                p2[page.p2_index()].set_demand_paged(flags);
+                number += ENTRY_COUNT;
+                continue;
+            }
+
+            let p1 = try!(p2.try_next_table_create(page.p2_index(), allocator)
+                .ok_or(MapError::FrameAllocationFailed));
+            if !p1[page.p1_index()].is_unused() {
+                return Err(MapError::PageAlreadyMapped);
+            }
+            p1[page.p1_index()].set_demand_paged(flags);
+            number += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the flags a demand paged page will be populated with, or `None`
+    /// if the page isn't demand paged.
+    pub fn demand_flags(&self, page: Page) -> Option<EntryFlags> {
+        let p2 = match self.p4().next_table(page.p4_index())
+                                .and_then(|p3| p3.next_table(page.p3_index())) {
+            Some(p2) => p2,
+            None => return None,
+        };
+        if let Some(flags) = p2[page.p2_index()].demand_flags() {
+            return Some(flags);
+        }
+        p2.next_table(page.p2_index())
+          .and_then(|p1| p1[page.p1_index()].demand_flags())
+    }
+
+    /// Maps a demand paged page to `frame` with the flags it was reserved
+    /// with. Returns `None` if the page isn't demand paged, and
+    /// `MapError::FrameAllocationFailed` if a page table for it couldn't be
+    /// allocated.
+    pub fn populate<A>(&mut self, page: Page, frame: Frame, allocator: &mut A)
+        -> Result<Option<MapperFlush>, MapError>
+        where A: FrameAllocator
+    {
+        if self.demand_flags(page).is_none() {
+            return Ok(None);
+        }
+
+        // creating the P1 table splits a reserved 2MiB block into pages
+        let p3 = try!(self.p4_mut().try_next_table_create(page.p4_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+        let p2 = try!(p3.try_next_table_create(page.p3_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+        let p1 = try!(p2.try_next_table_create(page.p2_index(), allocator)
+            .ok_or(MapError::FrameAllocationFailed));
+        let flags = p1[page.p1_index()].demand_flags().unwrap();
+        p1[page.p1_index()].set(frame, supported_flags(flags) | PRESENT);
+        Ok(Some(MapperFlush::new(page)))
+    }
+
+    /// Marks `page` as swapped out to the given swap slot and returns the
+    /// frame that backed it. The caller is responsible for writing the frame's
+    /// contents to the backing store before reusing it. Pinned pages can't be
+    /// swapped out, the mapping is left alone in that case.
+    pub fn swap_out(&mut self, page: Page, slot: usize) -> Result<(Frame, MapperFlush), MapError> {
+        let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("mapping code does not support huge pages");
+        let frame = try!(swap_out_entry(&mut p1[page.p1_index()], page, slot));
+        Ok((frame, MapperFlush::new(page)))
+    }
+
+    /// Maps a swapped out page to `frame` again, restoring the flags it had
+    /// before `swap_out`. The frame must already hold the page's contents.
+    pub fn swap_in(&mut self, page: Page, frame: Frame) -> MapperFlush {
+        let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("page is not swapped out");
+        swap_in_entry(&mut p1[page.p1_index()], frame);
+        MapperFlush::new(page)
+    }
+
+    /// Returns the swap slot of the given page if it is swapped out.
+    pub fn swap_slot(&self, page: Page) -> Option<usize> {
+        self.p4()
+            .next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .and_then(|p1| p1[page.p1_index()].swap_slot())
+    }
+
+    /// Returns the state of the given 4KiB page, or `None` if it has no entry.
+    pub fn get_page_state(&self, page: Page) -> Option<PageState> {
+        self.p4()
+            .next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .and_then(|p1| p1[page.p1_index()].page_state())
+    }
+
+    /// Sets the state of a mapped 4KiB page, keeping its frame and flags. The
+    /// CPU ignores the state bits, so no TLB flush is needed. The state only
+    /// records the intent, e.g. a `CopyOnWrite` page also has to be mapped
+    /// read-only by the caller.
+    pub fn set_page_state(&mut self, page: Page, state: PageState) {
+        let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("mapping code does not support huge pages");
+        p1[page.p1_index()].set_page_state(state);
+    }
+
+    /// Returns `MapError::PagePinned` if the frame of `page` must not be
+    /// swapped out or moved, see `PageState::Pinned`.
+    pub fn check_not_pinned(&self, page: Page) -> Result<(), MapError> {
+        if self.get_page_state(page) == Some(PageState::Pinned) {
+            Err(MapError::PagePinned(page.start_address()))
+        } else {
+            Ok(())
+        }
+    }
+
+}
+
+/// Marks the P1 entry of `page` as swapped out to `slot` and returns the
+/// frame it mapped, unless the page is pinned.
+fn swap_out_entry(entry: &mut Entry, page: Page, slot: usize) -> Result<Frame, MapError> {
+    if entry.page_state() == Some(PageState::Pinned) {
+        return Err(MapError::PagePinned(page.start_address()));
+    }
+    let frame = entry.pointed_frame().expect("page is not mapped");
+    let flags = entry.flags();
+    entry.set_swapped(slot, flags);
+    Ok(frame)
+}
+
+/// Points the P1 entry of a swapped out page at `frame` again, with the flags
+/// it had before `swap_out_entry`.
+fn swap_in_entry(entry: &mut Entry, frame: Frame) {
+    assert!(entry.swap_slot().is_some(), "page is not swapped out");
+    let flags = entry.flags() - SWAPPED;
+    entry.set(frame, supported_flags(flags) | PRESENT);
+}
+
+/// Returns the page of `mapping` that is mapped to `frame`, if any.
+fn mapping_page(mapping: &Mapping, frame: &Frame) -> Option<Page> {
+    let address = frame.start_address();
+    if address >= mapping.frame && address - mapping.frame < mapping.size.bytes() {
+        Some(Page::containing_address(mapping.start + (address - mapping.frame)))
+    } else {
+        None
+    }
+}
+
+fn print_mapping_run(start: Mapping, len: usize) {
+    println!("{:#x}-{:#x} -> {:#x} {:?}",
+        start.start, start.start + len - 1, start.frame, start.flags);
+}
+
+/// The reasons a mapping can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// A frame for a new page table could not be allocated.
+    FrameAllocationFailed,
+    /// The page is already mapped.
+    PageAlreadyMapped,
+    /// The page or the frame isn't aligned to the huge page size.
+    MisalignedForHugePage,
+    /// The page isn't mapped.
+    PageNotMapped,
+    /// A page of the temporary page is still mapped, e.g. by an earlier
+    /// operation that didn't unmap it again.
+    TemporaryPageInUse,
+    /// The page at the given address is pinned, so its frame can't be
+    /// swapped out or moved.
+    PagePinned(VirtualAddress),
+}
+
+/// Returns the number of frames mapping every page of `pages` takes in the
+/// worst case: one data frame per page, plus a frame for each P3, P2 and P1
+/// table that doesn't exist yet. Tables shared by several pages of the range
+/// are only counted once. Compare against the allocator's `free_frames` to
+/// avoid running out of frames halfway through a large mapping.
+pub fn frames_needed(pages: PageRangeInclusive, mapper: &Mapper) -> usize {
+    let mut frames = 0;
+    // the tables covering consecutive pages are identified by the page
+    // number without the lower table indices
+    let mut last_p3 = None;
+    let mut last_p2 = None;
+    let mut last_p1 = None;
+
+    for page in pages {
+        frames += 1;
+
+        let p3 = mapper.p4().next_table(page.p4_index());
+        if last_p3 != Some(page.number >> 27) {
+            last_p3 = Some(page.number >> 27);
+            if p3.is_none() {
+                frames += 1;
+            }
+        }
+
+        let p2 = p3.and_then(|p3| p3.next_table(page.p3_index()));
+        if last_p2 != Some(page.number >> 18) {
+            last_p2 = Some(page.number >> 18);
+            if p2.is_none() {
+                frames += 1;
+            }
+        }
+
+        if last_p1 != Some(page.number >> 9) {
+            last_p1 = Some(page.number >> 9);
+            if p2.and_then(|p2| p2.next_table(page.p2_index())).is_none() {
+                frames += 1;
+            }
+        }
+    }
+
+    frames
+}
+
+/// The number of pages of each size a range was mapped with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageSizeCounts {
+    pub pages_4kib: usize,
+    pub pages_2mib: usize,
+    pub pages_1gib: usize,
+}
+
+impl PageSizeCounts {
+    fn add(&mut self, size: PageSize) {
+        match size {
+            PageSize::Size4KiB => self.pages_4kib += 1,
+            PageSize::Size2MiB => self.pages_2mib += 1,
+            PageSize::Size1GiB => self.pages_1gib += 1,
+        }
+    }
+
+    /// Returns the number of frames the mapped pages cover.
+    pub fn frames(&self) -> usize {
+        self.pages_4kib + self.pages_2mib * PageSize::Size2MiB.bytes() / PAGE_SIZE
+            + self.pages_1gib * PageSize::Size1GiB.bytes() / PAGE_SIZE
+    }
+}
+
+/// The number of mapped bytes per protection class, see
+/// `Mapper::protection_summary`. The first four classes don't overlap,
+/// `user_bytes` counts the user accessible bytes of all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtectionSummary {
+    pub writable_executable_bytes: usize,
+    pub writable_bytes: usize,
+    pub executable_bytes: usize,
+    pub readonly_bytes: usize,
+    pub user_bytes: usize,
+}
+
+impl ProtectionSummary {
+    fn add(&mut self, mapping: &Mapping) {
+        let bytes = mapping.size.bytes();
+        let writable = mapping.flags.contains(WRITABLE);
+        let executable = !mapping.flags.contains(NO_EXECUTE);
+        match (writable, executable) {
+            (true, true) => self.writable_executable_bytes += bytes,
+            (true, false) => self.writable_bytes += bytes,
+            (false, true) => self.executable_bytes += bytes,
+            (false, false) => self.readonly_bytes += bytes,
+        }
+        if mapping.flags.contains(USER_ACCESSIBLE) {
+            self.user_bytes += bytes;
+        }
+    }
+
+    /// Prints the summary. Writable and executable memory defeats W^X, so it
+    /// gets an extra warning.
+    pub fn print(&self) {
+        println!("mapped: {} KiB writable+executable, {} KiB writable, {} KiB executable, \
+                  {} KiB read-only, {} KiB user accessible",
+                 self.writable_executable_bytes / 1024, self.writable_bytes / 1024,
+                 self.executable_bytes / 1024, self.readonly_bytes / 1024,
+                 self.user_bytes / 1024);
+        if self.writable_executable_bytes != 0 {
+            println!("WARNING: {} bytes are mapped both writable and executable",
+                     self.writable_executable_bytes);
+        }
+    }
+}
+
+/// A pending TLB flush for a page whose entry was changed. Changes to the
+/// active table have to be flushed, changes to an inactive table (made
+/// through `ActivePageTable::with` or `edit`) are not cached by the processor
+/// and can be ignored with `ignore_in_session`. Both need a reference to what
+/// was changed, so a flush of the active table can't be dropped by mistake.
+#[must_use = "the TLB has to be flushed, or the flush explicitly ignored"]
+pub struct MapperFlush(Page);
+
+impl MapperFlush {
+    fn new(page: Page) -> MapperFlush {
+        MapperFlush(page)
+    }
+
+    /// Flushes the page from the TLB. Requiring the active table makes sure
+    /// the change was made to the tables the processor uses.
+    pub fn flush(self, _active_table: &ActivePageTable) {
+        hw::flush(self.0.start_address());
+    }
+
+    /// Discards the flush, for changes to entries the processor can't have
+    /// cached, e.g. unused ones.
+    fn ignore(self) {}
+
+    /// Discards the flush of a change made in an editing session. The change
+    /// went to the inactive table, so the page may be mapped differently in
+    /// the active one; the session flushes the whole TLB when it ends.
+    /// Requiring the session makes sure the change isn't live yet.
+    pub fn ignore_in_session(self, _session: &Session) {}
+}
+
+static NX_UNSUPPORTED_REPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+static PGE_DISABLED_REPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Removes the flags the processor can't honor. On processors without NX
+/// support bit 63 is reserved, so a `NO_EXECUTE` entry would fault on every
+/// access. `GLOBAL` is dropped unless CR4.PGE is set, since it has no effect
+/// then and shouldn't suggest that the entry survives CR3 reloads.
+pub fn supported_flags(flags: EntryFlags) -> EntryFlags {
+    let flags = global_supported(flags);
+    if !flags.contains(NO_EXECUTE) {
+        return flags;
+    }
+
+    if ::cpu_features::has_nx() {
+        flags
+    } else {
+        if !NX_UNSUPPORTED_REPORTED.swap(true, Ordering::Relaxed) {
+            println!("NX is not supported by this processor, ignoring NO_EXECUTE");
+        }
+        flags - NO_EXECUTE
+    }
+}
+
+fn global_supported(flags: EntryFlags) -> EntryFlags {
+    if !flags.contains(GLOBAL) || (::cpu_features::has_global_pages() && hw::pge_enabled()) {
+        return flags;
+    }
+
+    if !PGE_DISABLED_REPORTED.swap(true, Ordering::Relaxed) {
+        println!("global pages are not enabled, ignoring GLOBAL");
+    }
+    flags - GLOBAL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+
+    fn mapping(start: VirtualAddress, size: PageSize, flags: EntryFlags) -> Mapping {
+        Mapping {
+            start: start,
+            frame: start,
+            size: size,
+            flags: flags,
+        }
+    }
+
+    fn mapped_entry(state: PageState) -> Entry {
+        let mut entry: Entry = unsafe { mem::zeroed() };
+        entry.set(Frame { number: 0x1234 }, PRESENT | WRITABLE);
+        entry.set_page_state(state);
+        entry
+    }
+
+    #[test]
+    fn swapping_out_a_pinned_page_fails_and_keeps_the_mapping() {
+        let page = Page::containing_address(0x40_0000);
+        let mut entry = mapped_entry(PageState::Pinned);
+
+        assert_eq!(swap_out_entry(&mut entry, page, 3), Err(MapError::PagePinned(0x40_0000)));
+        assert_eq!(entry.pointed_frame(), Some(Frame { number: 0x1234 }));
+        assert_eq!(entry.page_state(), Some(PageState::Pinned));
+        assert!(entry.flags().contains(PRESENT | WRITABLE));
+    }
+
+    #[test]
+    fn swapping_out_an_unpinned_page_records_the_slot() {
+        let page = Page::containing_address(0x40_0000);
+        let mut entry = mapped_entry(PageState::Normal);
+
+        assert_eq!(swap_out_entry(&mut entry, page, 3), Ok(Frame { number: 0x1234 }));
+        assert_eq!(entry.swap_slot(), Some(3));
+        assert_eq!(entry.page_state(), Some(PageState::Swapped));
+    }
+
+    #[test]
+    fn swapping_a_page_out_and_in_keeps_its_frame_and_flags() {
+        hw::enable_nxe().unwrap();
+        let page = Page::containing_address(0x40_0000);
+        let mut entry: Entry = unsafe { mem::zeroed() };
+        let flags = PRESENT | WRITABLE | USER_ACCESSIBLE | NO_EXECUTE;
+        entry.set(Frame { number: 0x1234 }, flags);
+
+        let frame = swap_out_entry(&mut entry, page, 7).unwrap();
+        assert_eq!(entry.swap_slot(), Some(7));
+        assert!(entry.pointed_frame().is_none());
+
+        swap_in_entry(&mut entry, frame);
+        assert_eq!(entry.pointed_frame(), Some(Frame { number: 0x1234 }));
+        assert_eq!(entry.flags(), flags);
+        assert_eq!(entry.swap_slot(), None);
+    }
+
+    #[test]
+    fn unmapping_a_pinned_page_fails_and_keeps_the_mapping() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        let frame = Frame { number: 0x1234 };
+        active_table.map_to(page, frame.clone(), WRITABLE, &mut allocator).flush(&active_table);
+        active_table.set_page_state(page, PageState::Pinned);
+
+        assert_eq!(active_table.unmap(page, &mut allocator).err(),
+                   Some(MapError::PagePinned(0x40_0000)));
+        assert_eq!(active_table.unmap_and_free(page, &mut allocator).err(),
+                   Some(MapError::PagePinned(0x40_0000)));
+        assert_eq!(active_table.translate_page(page), Some(frame.clone()));
+        assert_eq!(active_table.get_page_state(page), Some(PageState::Pinned));
+
+        active_table.set_page_state(page, PageState::Normal);
+        let (unmapped, _) = active_table.unmap(page, &mut allocator).unwrap();
+        assert_eq!(unmapped, frame);
+        assert_eq!(active_table.unmap(page, &mut allocator).err(), Some(MapError::PageNotMapped));
+    }
+
+    #[test]
+    fn reserving_a_gib_takes_only_the_page_tables() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let start = Page::containing_address(0x4000_0000);
+        let end = Page::containing_address(0x7fff_ffff);
+        let free_frames = allocator.free_frames();
+
+        active_table.reserve_region(Page::range_inclusive(start, end), WRITABLE, &mut allocator)
+            .unwrap();
+        // a P3 and a P2 table, the P2 entries reserve 2MiB each
+        assert_eq!(allocator.free_frames(), free_frames - 2);
+        assert_eq!(active_table.demand_flags(end), Some(WRITABLE));
+        assert_eq!(active_table.translate_page(start), None);
+    }
+
+    #[test]
+    fn reserving_a_mapped_page_fails() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_3000);
+        active_table.map_to(page, Frame { number: 0x1234 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        let pages = Page::range_inclusive(Page::containing_address(0x40_0000), page);
+        assert_eq!(active_table.reserve_region(pages, WRITABLE, &mut allocator),
+                   Err(MapError::PageAlreadyMapped));
+        assert_eq!(active_table.translate_page(page), Some(Frame { number: 0x1234 }));
+    }
+
+    #[test]
+    #[should_panic(expected = "recursive mapping region")]
+    fn mapping_a_page_in_the_recursive_region_panics() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0xffff_ff80_0000_0000);
+        assert_eq!(page.p4_index(), RECURSIVE_INDEX);
+
+        active_table.map_to(page, Frame { number: 0x1234 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+    }
+
+    #[test]
+    fn frames_needed_counts_only_missing_tables_once() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        // creates the P3, P2 and P1 tables for 0x40_0000..0x60_0000
+        active_table.map_to(Page::containing_address(0x40_0000), Frame { number: 0x1234 },
+                            WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        // two pages in the existing P1 table, two in a new one
+        let pages = Page::range_inclusive(Page::containing_address(0x5f_e000),
+                                          Page::containing_address(0x60_1000));
+        assert_eq!(frames_needed(pages.clone(), &active_table), 4 + 1);
+        // a new P2 and P1 table below the existing P3 table
+        let far_pages = Page::range_inclusive(Page::containing_address(0x8000_0000),
+                                              Page::containing_address(0x8000_1000));
+        assert_eq!(frames_needed(far_pages, &active_table), 2 + 2);
+
+        let free_frames = allocator.free_frames();
+        for page in pages {
+            active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        }
+        assert_eq!(allocator.free_frames(), free_frames - 5);
+    }
+
+    #[test]
+    fn translate_with_flags_matches_translate_and_get_flags() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        active_table.map_to(Page::containing_address(0x40_0000), Frame { number: 0x1234 },
+                            WRITABLE | NO_EXECUTE, &mut allocator)
+            .flush(&active_table);
+        active_table.try_map_to_huge(Page::containing_address(0x4000_0000),
+                                     Frame::containing_address(0x20_0000), PageSize::Size2MiB,
+                                     WRITABLE, &mut allocator)
+            .unwrap()
+            .flush(&active_table);
+
+        for &address in &[0x40_0123, 0x4000_0000, 0x4012_3456, 0x401f_ffff] {
+            let page = Page::containing_address(address);
+            let expected = active_table.translate(address)
+                .map(|physical| (physical, active_table.get_flags(page).unwrap()));
+            assert!(expected.is_some());
+            assert_eq!(active_table.translate_with_flags(address), expected);
+        }
+        assert_eq!(active_table.translate_with_flags(0x4012_3456).unwrap().0, 0x32_3456);
+        assert_eq!(active_table.translate_with_flags(0x40_1000), None);
+    }
+
+    #[test]
+    fn used_frames_count_every_table_and_data_frame() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let used_frames = allocator.used_frames();
+
+        // the caller owns the frames of the huge page, only the P3 and P2
+        // tables come from the allocator
+        active_table.try_map_to_huge(Page::containing_address(0x4000_0000),
+                                     Frame::containing_address(0x20_0000), PageSize::Size2MiB,
+                                     WRITABLE, &mut allocator)
+            .unwrap()
+            .flush(&active_table);
+        assert_eq!(allocator.used_frames(), used_frames + 2);
+
+        // a data frame and a P1 table
+        let page = Page::containing_address(0x4020_0000);
+        active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        assert_eq!(allocator.used_frames(), used_frames + 4);
+
+        // the P1 table stays
+        active_table.unmap_and_free(page, &mut allocator).unwrap().flush(&active_table);
+        assert_eq!(allocator.used_frames(), used_frames + 3);
+    }
+
+    #[test]
+    fn addresses_in_the_non_canonical_hole_translate_to_nothing() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let lower_end = Page::containing_address(0x0000_7fff_ffff_f000);
+        let upper_start = Page::containing_address(0xffff_8000_0000_0000);
+        active_table.map_to(lower_end, Frame { number: 0x1234 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+        active_table.map_to(upper_start, Frame { number: 0x1235 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        assert_eq!(active_table.translate(0x0000_7fff_ffff_ffff), Some(0x1234_fff));
+        assert_eq!(active_table.translate(0xffff_8000_0000_0000), Some(0x1235_000));
+        // these would alias the mappings above if the upper bits were masked
+        assert_eq!(active_table.translate(0x0000_8000_0000_0000), None);
+        assert_eq!(active_table.translate(0xffff_7fff_ffff_ffff), None);
+    }
+
+    #[test]
+    fn the_first_page_mapping_a_frame_is_found() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let frame = Frame { number: 0x1234 };
+        for &address in &[0x8000_0000, 0x40_5000] {
+            active_table.map_to(Page::containing_address(address), frame.clone(), WRITABLE,
+                                &mut allocator)
+                .flush(&active_table);
+        }
+        active_table.try_map_to_huge(Page::containing_address(0x4000_0000),
+                                     Frame::containing_address(0x20_0000), PageSize::Size2MiB,
+                                     WRITABLE, &mut allocator)
+            .unwrap()
+            .flush(&active_table);
+
+        let start_address = |page: Option<Page>| page.map(|page| page.start_address());
+        assert_eq!(start_address(active_table.find_page_mapping(&frame)), Some(0x40_5000));
+        assert_eq!(active_table.count_page_mappings(&frame), 2);
+        // a frame inside a huge page
+        let frame = Frame::containing_address(0x20_3000);
+        assert_eq!(start_address(active_table.find_page_mapping(&frame)), Some(0x4000_3000));
+        assert!(!active_table.is_frame_mapped(&Frame { number: 0x1235 }));
+    }
+
+    #[test]
+    fn global_is_stripped_while_pge_is_disabled() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0xffff_8000_0000_0000);
+        assert!(!hw::pge_enabled());
+
+        active_table.map_to(page, Frame { number: 0x1234 }, WRITABLE | GLOBAL, &mut allocator)
+            .flush(&active_table);
+        assert_eq!(active_table.get_flags(page), Some(PRESENT | WRITABLE));
+
+        if ::cpu_features::has_global_pages() {
+            hw::enable_pge();
+            let page = Page::containing_address(0xffff_8000_0000_1000);
+            active_table.map_to(page, Frame { number: 0x1235 }, WRITABLE | GLOBAL,
+                                &mut allocator)
+                .flush(&active_table);
+            assert_eq!(active_table.get_flags(page), Some(PRESENT | WRITABLE | GLOBAL));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "NX requested but NXE disabled")]
+    fn mapping_no_execute_pages_while_nxe_is_disabled_panics() {
+        use hw::Hw;
+
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        hw::with_mock(|hw| unsafe {
+            hw.write_msr(hw::IA32_EFER, hw.read_msr(hw::IA32_EFER) & !hw::EFER_NXE)
+        });
+
+        active_table.map_to(Page::containing_address(0x40_0000), Frame { number: 0x1234 },
+                            WRITABLE | NO_EXECUTE, &mut allocator)
+            .flush(&active_table);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds MAXPHYADDR (36 bits)")]
+    fn mapping_frames_beyond_maxphyaddr_panics() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        hw::with_mock(|hw| hw.physical_address_bits.set(36));
+
+        active_table.map_to(Page::containing_address(0x40_0000),
+                            Frame::containing_address(1 << 36), WRITABLE, &mut allocator)
+            .flush(&active_table);
+    }
+
+    /// Hands out no frames at all.
+    struct EmptyAllocator;
+
+    impl FrameAllocator for EmptyAllocator {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            None
+        }
+
+        fn deallocate_frame(&mut self, _: Frame) {
+            unreachable!("no frame was allocated");
+        }
+    }
+
+    #[test]
+    fn populated_tables_allow_mapping_without_allocating() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let free_frames = allocator.free_frames();
+        // three P1 tables below one P2 table
+        let pages = Page::range_inclusive(Page::containing_address(0x5f_f000),
+                                          Page::containing_address(0x80_1000));
+
+        active_table.populate_page_tables(pages.clone(), &mut allocator).unwrap();
+        assert_eq!(allocator.free_frames(), free_frames - 5);
+        for page in pages.clone() {
+            assert_eq!(active_table.translate_page(page), None);
+        }
+
+        for (i, page) in pages.enumerate() {
+            active_table.try_map_to(page, Frame { number: 0x1000 + i }, WRITABLE,
+                                    &mut EmptyAllocator)
+                .unwrap()
+                .flush(&active_table);
+        }
+        assert_eq!(active_table.try_map_to(Page::containing_address(0xa0_0000),
+                                           Frame { number: 0x2000 }, WRITABLE,
+                                           &mut EmptyAllocator).err(),
+                   Some(MapError::FrameAllocationFailed));
+    }
+
+    /// Maps 3GiB + 3MiB + 3 pages from 1GiB at 256GiB with the largest pages
+    /// that fit and checks the translation of every page size transition.
+    fn map_best_fit_range() -> PageSizeCounts {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let virt_base = 0x40_0000_0000;
+        let length = 3 * 0x4000_0000 + 3 * 0x10_0000 + 3 * PAGE_SIZE;
+        let frames = FrameRange::containing_addresses(0x4000_0000, 0x4000_0000 + length);
+
+        let counts = active_table.map_range_best_fit(frames, virt_base, WRITABLE, &mut allocator)
+            .unwrap();
+        assert_eq!(counts.frames(), length / PAGE_SIZE);
+        for &offset in &[0, 0x3fff_ffff, 0xc000_0000, 0xc01f_ffff, 0xc020_0000, length - 1] {
+            assert_eq!(active_table.translate(virt_base + offset), Some(0x4000_0000 + offset));
+        }
+        assert_eq!(active_table.translate(virt_base + length), None);
+        counts
+    }
+
+    #[test]
+    fn best_fit_uses_1gib_then_2mib_then_4kib_pages() {
+        assert_eq!(map_best_fit_range(), PageSizeCounts {
+            pages_4kib: 256 + 3,
+            pages_2mib: 1,
+            pages_1gib: 3,
+        });
+    }
+
+    #[test]
+    fn best_fit_uses_2mib_pages_without_1gib_page_support() {
+        hw::with_mock(|hw| hw.huge_pages_1gib.set(false));
+        assert_eq!(map_best_fit_range(), PageSizeCounts {
+            pages_4kib: 256 + 3,
+            pages_2mib: 3 * 512 + 1,
+            pages_1gib: 0,
+        });
+    }
+
+    #[test]
+    fn frames_are_mapped_until_their_page_is_unmapped() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        let frame = Frame { number: 0x1234 };
+        assert!(!active_table.is_frame_mapped(&frame));
+
+        active_table.map_to(page, frame.clone(), WRITABLE, &mut allocator).flush(&active_table);
+        assert!(active_table.is_frame_mapped(&frame));
+
+        active_table.unmap(page, &mut allocator).unwrap().1.flush(&active_table);
+        assert!(!active_table.is_frame_mapped(&frame));
+    }
+
+    #[test]
+    fn unmap_hands_the_frame_back_without_freeing_it() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        let frame = active_table.translate_page(page).unwrap();
+        let used_frames = allocator.used_frames();
+
+        let (unmapped, flush) = active_table.unmap(page, &mut allocator).unwrap();
+        flush.flush(&active_table);
+        assert_eq!(unmapped, frame);
+        assert_eq!(allocator.used_frames(), used_frames);
+
+        allocator.deallocate_frame(unmapped);
+        assert_eq!(allocator.used_frames(), used_frames - 1);
+
+        active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        active_table.unmap_and_free(page, &mut allocator).unwrap().flush(&active_table);
+        assert_eq!(allocator.used_frames(), used_frames - 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "still used as a page table")]
+    fn freeing_a_live_p1_table_frame_panics() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        let data_frame = active_table.translate_page(page).unwrap();
+        active_table.assert_frame_not_table(&data_frame);
+
+        let p1_frame = {
+            let p2 = active_table.p4().next_table(page.p4_index())
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .unwrap();
+            p2[page.p2_index()].pointed_frame().unwrap()
+        };
+        active_table.assert_frame_not_table(&p1_frame);
+    }
+
+    #[test]
+    fn direct_map_translations_match_the_recursive_walk() {
+        use memory::paging::{PageSize, map_physical_memory};
+        use memory::test_support::MIB;
+
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        let huge_page = Page::containing_address(0x4000_0000);
+        let huge_frame = Frame::containing_address(0x20_0000);
+        active_table.try_map_to_huge(huge_page, huge_frame, PageSize::Size2MiB, WRITABLE,
+                                     &mut allocator)
+            .unwrap()
+            .flush(&active_table);
+        let addresses = [0x40_0123, 0x40_1000, 0x4000_0000, 0x4012_3456, 0x4020_0000,
+                         0x8000_0000_0000, 0xffff_8000_0000_0000];
+        let recursive: Vec<_> = addresses.iter().map(|&va| active_table.translate(va)).collect();
+
+        let frames = FrameRange::containing_addresses(0, 8 * MIB as usize);
+        map_physical_memory(frames, PageSize::Size2MiB, &mut active_table, &mut allocator)
+            .unwrap();
+        assert_eq!(active_table.direct_map(), Some(frames));
+
+        let direct: Vec<_> = addresses.iter()
+            .map(|&va| active_table.translate_via_direct_map(va))
+            .collect();
+        assert_eq!(direct, recursive);
+        assert_eq!(direct[0], Some(active_table.translate_page(page).unwrap().start_address() +
+                                   0x123));
+        assert_eq!(direct[3], Some(0x32_3456));
+        assert_eq!(direct[4], None);
+    }
+
+    #[test]
+    fn translate_level_reports_the_size_of_each_mapping() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        active_table.map_to(page, Frame { number: 0x1000 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+        for &(address, frame, size) in &[(0x4000_0000, 0x20_0000, PageSize::Size2MiB),
+                                         (0x80_0000_0000, 0x4000_0000, PageSize::Size1GiB)] {
+            active_table.try_map_to_huge(Page::containing_address(address),
+                                         Frame::containing_address(frame), size,
+                                         WRITABLE | NO_EXECUTE, &mut allocator)
+                .unwrap()
+                .flush(&active_table);
+        }
+
+        assert_eq!(active_table.translate_level(0x40_0123),
+                   Some((Frame { number: 0x1000 }, PRESENT | WRITABLE, PageSize::Size4KiB)));
+        // the frame is the first one of the huge page, whatever the offset
+        let huge_flags = PRESENT | WRITABLE | NO_EXECUTE | HUGE_PAGE;
+        assert_eq!(active_table.translate_level(0x4012_3456),
+                   Some((Frame::containing_address(0x20_0000), huge_flags,
+                         PageSize::Size2MiB)));
+        assert_eq!(active_table.translate_level(0x80_1234_5678),
+                   Some((Frame::containing_address(0x4000_0000), huge_flags,
+                         PageSize::Size1GiB)));
+        assert_eq!(active_table.translate_level(0x40_1000), None);
+    }
+
+    #[test]
+    fn map_with_takes_data_and_table_frames_from_their_allocators() {
+        use memory::test_support::{MIB, allocator_with_areas};
+
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let mut dma_allocator = allocator_with_areas(&[(8 * MIB, MIB)]);
+        let used_frames = allocator.used_frames();
+        let page = Page::containing_address(0x40_0000);
+
+        active_table.map_with(page, WRITABLE, &mut dma_allocator, &mut allocator)
+            .flush(&active_table);
+
+        let dma_frame = Frame::containing_address(8 * MIB as usize);
+        assert_eq!(active_table.translate_page(page), Some(dma_frame));
+        assert_eq!(dma_allocator.used_frames(), 1);
+        // the P3, P2 and P1 tables
+        assert_eq!(allocator.used_frames(), used_frames + 3);
+    }
+
+    #[test]
+    fn huge_pages_must_be_aligned_to_their_size() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let aligned_page = Page::containing_address(0x4000_0000);
+        let aligned_frame = Frame::containing_address(0x20_0000);
+
+        assert_eq!(active_table.try_map_to_huge(Page::containing_address(0x4000_1000),
+                                                aligned_frame.clone(), PageSize::Size2MiB,
+                                                WRITABLE, &mut allocator).err(),
+                   Some(MapError::MisalignedForHugePage));
+        assert_eq!(active_table.try_map_to_huge(aligned_page, Frame::containing_address(0x20_1000),
+                                                PageSize::Size2MiB, WRITABLE,
+                                                &mut allocator).err(),
+                   Some(MapError::MisalignedForHugePage));
+
+        active_table.try_map_to_huge(aligned_page, aligned_frame, PageSize::Size2MiB, WRITABLE,
+                                     &mut allocator)
+            .unwrap()
+            .flush(&active_table);
+        assert_eq!(active_table.translate(0x4012_3456), Some(0x32_3456));
+    }
+
+    #[test]
+    fn protection_summary_sums_each_class() {
+        let huge_page = PageSize::Size2MiB.bytes();
+        let mappings = [
+            mapping(0x1000, PageSize::Size4KiB, PRESENT | WRITABLE),
+            mapping(0x2000, PageSize::Size4KiB, PRESENT | WRITABLE | NO_EXECUTE),
+            mapping(0x3000, PageSize::Size4KiB, PRESENT),
+            mapping(0x4000, PageSize::Size4KiB, PRESENT | NO_EXECUTE),
+            mapping(0x5000, PageSize::Size4KiB, PRESENT | NO_EXECUTE | USER_ACCESSIBLE),
+            mapping(0x20_0000, PageSize::Size2MiB,
+                    PRESENT | WRITABLE | NO_EXECUTE | USER_ACCESSIBLE | HUGE_PAGE),
+        ];
+
+        let mut summary = ProtectionSummary::default();
+        for mapping in &mappings {
+            summary.add(mapping);
+        }
+
+        assert_eq!(summary, ProtectionSummary {
+            writable_executable_bytes: PAGE_SIZE,
+            writable_bytes: PAGE_SIZE + huge_page,
+            executable_bytes: PAGE_SIZE,
+            readonly_bytes: 2 * PAGE_SIZE,
+            user_bytes: PAGE_SIZE + huge_page,
+        });
+    }
+}
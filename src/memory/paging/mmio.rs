@@ -0,0 +1,151 @@
+use super::{Page, ActivePageTable, VirtualAddress, PhysicalAddress};
+use super::entry::*;
+use super::region::{VirtualRegionManager, RegionPurpose, RegionError};
+use super::mapper::{MapError, PageSizeCounts};
+use super::walk::PageSize;
+use memory::{PAGE_SIZE, Frame, FrameAllocator, FrameRange};
+use hw;
+
+
+const IO_APIC_BASE: PhysicalAddress = 0xfec0_0000;
+
+/// The virtual address physical address 0 is mapped to by
+/// `map_physical_memory`. The direct map takes the whole P4 slot 384.
+pub const DIRECT_MAP_BASE: VirtualAddress = 0xffff_c000_0000_0000;
+
+/// The size of the direct map region.
+pub const DIRECT_MAP_SIZE: usize = 1 << 39;
+
+/// Maps the `size` bytes of device memory at `physical_address` to a newly
+/// allocated virtual region and returns the virtual address of
+/// `physical_address`. The memory is mapped uncached and kernel only.
+pub fn map_mmio<A>(physical_address: PhysicalAddress,
+                   size: usize,
+                   regions: &mut VirtualRegionManager,
+                   active_table: &mut ActivePageTable,
+                   allocator: &mut A)
+                   -> Result<VirtualAddress, RegionError>
+    where A: FrameAllocator
+{
+    let frames = FrameRange::containing_addresses(physical_address,
+                                                  physical_address + size);
+    let region_start = try!(regions.alloc_region(frames.len() * PAGE_SIZE,
+                                                 RegionPurpose::Mmio));
+
+    let flags = WRITABLE | NO_CACHE | NO_EXECUTE | GLOBAL;
+    for (i, frame) in frames.iter().enumerate() {
+        let page = Page::containing_address(region_start + i * PAGE_SIZE);
+        active_table.map_to(page, frame, flags, allocator).flush(active_table);
+    }
+    Ok(region_start + physical_address % PAGE_SIZE)
+}
+
+/// Maps `frames` to `DIRECT_MAP_BASE` plus their physical address, using
+/// pages of up to `max_size` where the processor and the alignment permit.
+/// Pass `PageSize::Size1GiB` to use the largest supported pages. Returns how
+/// many pages of each size were used.
+pub fn map_physical_memory<A>(frames: FrameRange,
+                              max_size: PageSize,
+                              active_table: &mut ActivePageTable,
+                              allocator: &mut A)
+                              -> Result<PageSizeCounts, MapError>
+    where A: FrameAllocator
+{
+    assert!(frames.end().start_address() <= DIRECT_MAP_SIZE,
+            "physical memory above {:#x} doesn't fit into the direct map", DIRECT_MAP_SIZE);
+    let base = DIRECT_MAP_BASE + frames.start().start_address();
+    let counts = try!(active_table.map_range_with_max_size(frames, base, max_size,
+                                                           WRITABLE | NO_EXECUTE | GLOBAL,
+                                                           allocator));
+    unsafe { active_table.set_direct_map(frames) };
+    Ok(counts)
+}
+
+/// The virtual addresses of the APIC registers.
+#[derive(Debug, Clone, Copy)]
+pub struct ApicMappings {
+    pub local_apic: VirtualAddress,
+    pub io_apic: VirtualAddress,
+}
+
+/// Maps the registers of the local APIC and the IO APIC uncached. The local
+/// APIC base is read from the IA32_APIC_BASE MSR, the IO APIC is expected at
+/// its default address.
+pub fn map_apic<A>(regions: &mut VirtualRegionManager,
+                   active_table: &mut ActivePageTable,
+                   allocator: &mut A)
+                   -> ApicMappings
+    where A: FrameAllocator
+{
+    let local_apic_base = Frame::containing_address(hw::read_apic_base() as usize)
+        .start_address();
+
+    ApicMappings {
+        local_apic: map_mmio(local_apic_base, PAGE_SIZE, regions, active_table, allocator)
+            .expect("failed to map the local APIC"),
+        io_apic: map_mmio(IO_APIC_BASE, PAGE_SIZE, regions, active_table, allocator)
+            .expect("failed to map the IO APIC"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hw::Hw;
+    use memory::test_support::{small_allocator, active_table};
+
+    #[test]
+    fn apic_registers_are_mapped_uncached_and_kernel_only() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let mut regions = VirtualRegionManager::new();
+        // a relocated local APIC, with the enable and BSP flags set
+        hw::with_mock(|hw| unsafe { hw.write_msr(hw::IA32_APIC_BASE, 0xfed0_0900) });
+
+        let mappings = map_apic(&mut regions, &mut active_table, &mut allocator);
+
+        for &(address, physical) in &[(mappings.local_apic, 0xfed0_0000),
+                                      (mappings.io_apic, IO_APIC_BASE)] {
+            let (frame, flags) = active_table.translate_with_flags(address).unwrap();
+            assert_eq!(frame, physical);
+            assert!(flags.contains(PRESENT | WRITABLE | NO_CACHE | NO_EXECUTE));
+            assert!(!flags.contains(USER_ACCESSIBLE));
+        }
+    }
+
+    fn direct_map_page_sizes(max_size: PageSize) -> PageSizeCounts {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let frames = FrameRange::containing_addresses(0, 4 * 0x4000_0000 + 0x20_0000);
+
+        let counts = map_physical_memory(frames, max_size, &mut active_table, &mut allocator)
+            .unwrap();
+        assert_eq!(active_table.translate(DIRECT_MAP_BASE + 0x1_0012_3456), Some(0x1_0012_3456));
+        counts
+    }
+
+    #[test]
+    fn direct_map_prefers_1gib_pages() {
+        assert_eq!(direct_map_page_sizes(PageSize::Size1GiB), PageSizeCounts {
+            pages_4kib: 0,
+            pages_2mib: 1,
+            pages_1gib: 4,
+        });
+    }
+
+    #[test]
+    fn direct_map_can_be_limited_to_2mib_pages() {
+        assert_eq!(direct_map_page_sizes(PageSize::Size2MiB), PageSizeCounts {
+            pages_4kib: 0,
+            pages_2mib: 4 * 512 + 1,
+            pages_1gib: 0,
+        });
+    }
+
+    #[test]
+    fn direct_map_falls_back_to_2mib_pages_without_1gib_page_support() {
+        hw::with_mock(|hw| hw.huge_pages_1gib.set(false));
+        assert_eq!(direct_map_page_sizes(PageSize::Size1GiB),
+                   direct_map_page_sizes(PageSize::Size2MiB));
+    }
+}
@@ -1,41 +1,148 @@
 pub use self::entry::*;
-pub use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MapperFlush, MapError, PageSizeCounts, ProtectionSummary,
+                       frames_needed};
+pub use self::self_test::{self_test, SelfTestError};
+pub use self::fault::{PageFaultErrorCode, PageFaultKind, PageFaultInfo, GuardPages,
+    decode_page_fault, decode_page_fault_at};
+pub use self::address_space::{AddressSpace, AddressSpaceBuilder};
+pub use self::dma::DmaBuffer;
+pub use self::region::{VirtualRegionManager, VirtualRegion, RegionPurpose, RegionError,
+                       reserve_paging_regions};
+pub use self::walk::{PageSize, Mapping, MappingIter};
+pub use self::mmio::{ApicMappings, DIRECT_MAP_BASE, map_mmio, map_apic, map_physical_memory};
+pub use self::stack::{Stack, alloc_stack};
+pub use self::remap::{RemapError, remap_the_kernel, try_remap_the_kernel,
+                      apply_kernel_protections};
+pub use self::mode::{PagingMode, PagingModeError, check_paging_mode, assert_paging_features};
 
-use memory::{PAGE_SIZE, Frame, FrameAllocator}; // needed later
-use self::table::{Table, Level4};
-use core::ptr::Unique;
+use memory::{PAGE_SIZE, Frame, FrameAllocator, FrameRange}; // needed later
+use self::table::{Table, TableLevel, Level4, Level1};
 use self::temporary_page::TemporaryPage;
+use self::mapper::supported_flags;
 use core::ops::{Deref, DerefMut};
-use multiboot2::BootInformation;
+use core::fmt;
+use multiboot2::ElfSection;
+use hw;
 
 
+mod address_space;
+mod dma;
 mod entry;
+mod fault;
 mod table;
 mod temporary_page;
 mod mapper;
+mod mmio;
+mod mode;
+mod region;
+mod remap;
+mod self_test;
+mod stack;
+mod walk;
 
 const ENTRY_COUNT: usize = 512;
 
+/// The kernel half P4 slots the kernel maps into after boot, whose P3 tables
+/// `ActivePageTable::preallocate_kernel_tables` creates: the dynamic region
+/// for MMIO, device windows and stacks, and the direct map. Preallocating
+/// all 255 slots would take about 1MiB. A new kernel half region has to be
+/// added here to be shared by all address spaces.
+const KERNEL_TABLE_SLOTS: [usize; 2] = [(region::DYNAMIC_REGION_START >> 39) & 0o777,
+                                        (DIRECT_MAP_BASE >> 39) & 0o777];
+
+/// The P4 slot that maps the P4 table onto itself.
+pub const RECURSIVE_INDEX: usize = 511;
+
+/// The virtual address of the active P4 table, reached by following the
+/// recursive entry at all four levels (`0xffffffff_fffff000` for slot 511).
+pub const RECURSIVE_MAP_BASE: VirtualAddress = recursive_table_address(RECURSIVE_INDEX);
+
+// fails to compile if `RECURSIVE_MAP_BASE` is not canonical
+#[allow(dead_code)]
+const RECURSIVE_MAP_BASE_IS_CANONICAL: [(); 0] =
+    [(); ((RECURSIVE_MAP_BASE as isize >> 47) != 0
+          && (RECURSIVE_MAP_BASE as isize >> 47) != -1) as usize];
+
+/// Returns the address whose four table indexes are all `index`, sign
+/// extended from bit 47.
+const fn recursive_table_address(index: usize) -> VirtualAddress {
+    (((index << 39 | index << 30 | index << 21 | index << 12) << 16) as isize >> 16) as usize
+}
+
+/// The first P4 slot of the kernel half of the address space.
+pub const KERNEL_P4_INDEX: usize = 256;
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
+/// The virtual base of a higher-half kernel image. Sections linked below it
+/// are expected to be identity mapped.
+pub const KERNEL_BASE: VirtualAddress = 0xffff_ffff_8000_0000;
+
+/// Returns whether bits 48 to 63 of the address are copies of bit 47. Other
+/// addresses lie in the hole in the middle of the address space.
+pub fn is_canonical(address: VirtualAddress) -> bool {
+    address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000
+}
+
+/// Returns a pointer to `address`, to access memory that was mapped there.
+#[cfg(not(test))]
+pub fn virtual_pointer<T>(address: VirtualAddress) -> *mut T {
+    address as *mut T
+}
 
-#[derive(Debug, Clone, Copy)]
+/// In host tests, the address is translated through the model page tables
+/// of `test_support`. An access across a page boundary is only valid there
+/// if the pages are mapped to contiguous frames.
+#[cfg(test)]
+pub fn virtual_pointer<T>(address: VirtualAddress) -> *mut T {
+    ::memory::test_support::host_address(address) as *mut T
+}
+
+/// The page `ActivePageTable::with_frame_mapped` maps frames to.
+const FRAME_WINDOW_PAGE: Page = Page { number: 0xcafeb000 };
+
+
+#[derive(Clone, Copy)]
 pub struct Page {
     number: usize,
 }
 
+impl fmt::Debug for Page {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Page({:#x})", self.start_address())
+    }
+}
+
 impl Page {
     fn start_address(&self) -> usize {
         self.number * PAGE_SIZE
     }
 
     pub fn containing_address(address: VirtualAddress) -> Page {
-        assert!(address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000,
-            "invalid address: 0x{:x}", address);
+        assert!(is_canonical(address), "invalid address: 0x{:x}", address);
         Page { number: address / PAGE_SIZE }
     }
 
+    /// Returns the page number, i.e. the start address divided by the page
+    /// size.
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// The inverse of `number`. Panics if the page doesn't start at a
+    /// canonical address.
+    pub fn from_number(number: usize) -> Page {
+        let page = Page { number: number };
+        assert!(is_canonical(page.start_address()), "invalid page number: 0x{:x}", number);
+        page
+    }
+
+    /// Returns whether the page can start a huge page of the given size.
+    pub fn is_aligned_to(&self, size: PageSize) -> bool {
+        self.start_address() % size.bytes() == 0
+    }
+
     fn p4_index(&self) -> usize {
         (self.number >> 27) & 0o777
     }
@@ -52,11 +159,58 @@ impl Page {
         (self.number >> 0) & 0o777
     }
 
+    pub fn range_inclusive(start: Page, end: Page) -> PageRangeInclusive {
+        PageRangeInclusive {
+            start: start,
+            end: end,
+        }
+    }
+
+}
+
+/// The pages from `start` up to and including `end`.
+#[derive(Debug, Clone)]
+pub struct PageRangeInclusive {
+    start: Page,
+    end: Page,
+}
+
+impl PageRangeInclusive {
+    /// Returns the number of pages in the range.
+    pub fn len(&self) -> usize {
+        if self.start.number <= self.end.number {
+            self.end.number - self.start.number + 1
+        } else {
+            0
+        }
+    }
+
+    /// Flushes the TLB entries of all pages in the range, after their entries
+    /// in the active table were changed directly.
+    pub fn flush(&self, _active_table: &ActivePageTable) {
+        hw::flush_range(self.start.start_address(), self.len());
+    }
+}
+
+impl Iterator for PageRangeInclusive {
+    type Item = Page;
+
+    fn next(&mut self) -> Option<Page> {
+        if self.start.number <= self.end.number {
+            let page = self.start;
+            self.start.number += 1;
+            Some(page)
+        } else {
+            None
+        }
+    }
 }
 
 
 pub struct ActivePageTable {
     mapper: Mapper,
+    // the number of `switch` calls that didn't need to reload CR3
+    switches_skipped: usize,
 }
 
 impl Deref for ActivePageTable {
@@ -74,135 +228,1282 @@ impl DerefMut for ActivePageTable {
 }
 
 impl ActivePageTable {
-    unsafe fn new() -> ActivePageTable {
+    pub(crate) unsafe fn new() -> ActivePageTable {
         ActivePageTable {
             mapper: Mapper::new(),
+            switches_skipped: 0,
         }
     }
 
+    /// Passes an editing session on `table` to `f`, see `edit`.
     pub fn with<F>(&mut self,
                table: &mut InactivePageTable,
                temporary_page: &mut temporary_page::TemporaryPage,
                f: F)
-        where F: FnOnce(&mut Mapper)
+        where F: FnOnce(&mut Session)
     {
-        use x86::{controlregs, tlb};
-        let flush_tlb = || unsafe { tlb::flush_all() };
+        let mut session = self.edit(table, temporary_page);
 
-        {
-            let backup = Frame::containing_address(
-                unsafe { controlregs::cr3() } as usize);
+        // execute f in the new context
+        f(&mut session);
+    }
+
+    /// Starts an editing session on the given inactive table. The recursive
+    /// mapping is pointed at `table` until the returned `Session` is dropped,
+    /// so any number of mappings can be made without re-entering the new
+    /// context for each of them.
+    pub fn edit<'a>(&'a mut self,
+                    table: &'a mut InactivePageTable,
+                    temporary_page: &'a mut TemporaryPage)
+                    -> Session<'a>
+    {
+        self.try_edit(table, temporary_page).expect("temporary page is still mapped")
+    }
+
+    /// Like `edit`, but returns `MapError::TemporaryPageInUse` instead of
+    /// starting the session if `temporary_page` is mapped already.
+    pub fn try_edit<'a>(&'a mut self,
+                        table: &'a mut InactivePageTable,
+                        temporary_page: &'a mut TemporaryPage)
+                        -> Result<Session<'a>, MapError>
+    {
+        try!(temporary_page.check_free(self));
+        let backup = Frame::containing_address(hw::read_cr3());
 
-            // map temporary_page to current p4 table
-            let p4_table = temporary_page.map_table_frame(backup.clone(), self);
+        // map temporary_page to current p4 table
+        let p4_table = temporary_page.map_table_frame(backup.clone(), self)
+            as *mut Table<Level1>;
 
-            // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
-            flush_tlb();
+        // overwrite recursive mapping
+        set_recursive_entry(self.p4_mut(), table.p4_frame.clone());
 
-            // execute f in the new context
-            f(self);
+        Ok(Session {
+            active_table: self,
+            temporary_page: temporary_page,
+            _table: table,
+            backup: backup,
+            p4_table: p4_table,
+        })
+    }
 
-            // Restory recursive mapping to its original p4 table.
-            p4_table[511].set(backup, PRESENT | WRITABLE);
-            flush_tlb();
+    /// Temporarily maps `frame` read-only and passes its contents to `f`. The
+    /// frame is unmapped again afterwards, so device frames don't stay mapped.
+    /// Frames covered by the direct map are read through it instead, without
+    /// a temporary mapping. This isn't a `Mapper` method because a mapping
+    /// made in an editing session isn't accessible.
+    pub fn with_frame_mapped<A, F, R>(&mut self, frame: &Frame, allocator: &mut A, f: F) -> R
+        where A: FrameAllocator, F: FnOnce(&[u8; PAGE_SIZE]) -> R
+    {
+        if let Some(address) = self.direct_map_address(frame) {
+            return f(unsafe { &*virtual_pointer(address) });
         }
 
-        temporary_page.unmap(self);
+        self.map_to(FRAME_WINDOW_PAGE, frame.clone(), NO_EXECUTE, allocator).flush(self);
+        let result = f(unsafe { &*virtual_pointer(FRAME_WINDOW_PAGE.start_address()) });
+        self.unmap(FRAME_WINDOW_PAGE, allocator)
+            .expect("failed to unmap the frame window").1.flush(self);
+        result
     }
 
-    pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-        use x86::controlregs;
+    /// Like `with_frame_mapped`, but maps the frame writable. The direct map
+    /// is only used if its view of the frame is writable, which it isn't for
+    /// page tables protected by `protect_page_tables`.
+    pub fn with_frame_mapped_mut<A, F, R>(&mut self, frame: &Frame, allocator: &mut A, f: F)
+        -> R
+        where A: FrameAllocator, F: FnOnce(&mut [u8; PAGE_SIZE]) -> R
+    {
+        if let Some(address) = self.direct_map_address(frame) {
+            let writable = self.translate_with_flags(address)
+                .map_or(false, |(_, flags)| flags.contains(WRITABLE));
+            if writable {
+                return f(unsafe { &mut *virtual_pointer(address) });
+            }
+        }
+
+        self.map_to(FRAME_WINDOW_PAGE, frame.clone(), WRITABLE | NO_EXECUTE, allocator)
+            .flush(self);
+        let result = f(unsafe { &mut *virtual_pointer(FRAME_WINDOW_PAGE.start_address()) });
+        self.unmap(FRAME_WINDOW_PAGE, allocator)
+            .expect("failed to unmap the frame window").1.flush(self);
+        result
+    }
+
+    /// Returns the address of `frame` in the direct map, if it covers the
+    /// frame.
+    fn direct_map_address(&self, frame: &Frame) -> Option<VirtualAddress> {
+        match self.direct_map() {
+            Some(frames) if frames.contains(frame) => {
+                Some(DIRECT_MAP_BASE + frame.start_address())
+            }
+            _ => None,
+        }
+    }
+
+    /// Copies the contents of `src` to `dst`, mapping both through two slots
+    /// of `temporary_page` (see `TemporaryPage::with_slots`), which are
+    /// unmapped again afterwards. Copying a frame to itself does nothing.
+    pub fn copy_frame(&mut self, src: &Frame, dst: &Frame, temporary_page: &mut TemporaryPage) {
+        if src == dst {
+            return;
+        }
+
+        let src_address = temporary_page.map(src.clone(), self);
+        let dst_address = temporary_page.map(dst.clone(), self);
+        unsafe {
+            ::core::ptr::copy_nonoverlapping(virtual_pointer::<u8>(src_address),
+                                             virtual_pointer(dst_address),
+                                             PAGE_SIZE);
+        }
+        temporary_page.unmap_address(dst_address, self);
+        temporary_page.unmap_address(src_address, self);
+    }
+
+    /// Moves `page` to `new_frame`: copies its contents over, points its
+    /// entry at `new_frame` with the same flags and flushes it. Returns the
+    /// old frame, which the caller has to free. Fails with
+    /// `MapError::PageNotMapped` if the page isn't mapped, with
+    /// `MisalignedForHugePage` if it's part of a huge page, with
+    /// `PageAlreadyMapped` if it's already mapped to `new_frame` and with
+    /// `PagePinned` if it's pinned, e.g. for DMA. The page stays mapped all
+    /// along, but writes to it during the copy are lost, so interrupt
+    /// handlers must not modify it.
+    ///
+    /// Only the local TLB is flushed. Once there are other CPUs, they could
+    /// still write to the old frame through a stale TLB entry until they are
+    /// shot down, and devices doing DMA to the page must be stopped first.
+    pub fn remap<A>(&mut self, page: Page, new_frame: Frame, allocator: &mut A)
+        -> Result<Frame, MapError>
+        where A: FrameAllocator
+    {
+        // check before copying, not only in `replace_frame`
+        let current_frame = match self.translate_level(page.start_address()) {
+            Some((frame, _, PageSize::Size4KiB)) => frame,
+            Some(_) => return Err(MapError::MisalignedForHugePage),
+            None => return Err(MapError::PageNotMapped),
+        };
+        if current_frame == new_frame {
+            return Err(MapError::PageAlreadyMapped);
+        }
+        try!(self.check_not_pinned(page));
+
+        self.with_frame_mapped_mut(&new_frame, allocator, |dst| unsafe {
+            ::core::ptr::copy_nonoverlapping(virtual_pointer::<u8>(page.start_address()),
+                                             dst.as_mut_ptr(),
+                                             PAGE_SIZE);
+        });
+        let (old_frame, flush) = try!(self.replace_frame(page, new_frame));
+        flush.flush(self);
+        Ok(old_frame)
+    }
+
+    /// Creates the P3 tables of the kernel half P4 entries in
+    /// `KERNEL_TABLE_SLOTS` that don't have one yet and returns the number of
+    /// created tables. Address spaces copy the kernel half P4 entries when
+    /// they are created (see `InactivePageTable::share_kernel_mappings`) and
+    /// then share the P3 tables with the active table, so once they exist,
+    /// kernel mappings made later in these slots, e.g. a late MMIO window,
+    /// appear in every address space without updating their P4 tables. This
+    /// has to be called before the first address space is created.
+    pub fn preallocate_kernel_tables<A>(&mut self, allocator: &mut A) -> usize
+        where A: FrameAllocator
+    {
+        let mut created = 0;
+        for &index in &KERNEL_TABLE_SLOTS {
+            if self.p4().next_table(index).is_none() {
+                self.p4_mut().next_table_create(index, allocator);
+                created += 1;
+            }
+        }
+        created
+    }
+
+    /// Maps the direct map view of every page table frame read-only, so that
+    /// stray writes through the direct map can't corrupt the page tables.
+    /// The processor ignores these mappings when walking the tables, and the
+    /// `Mapper` methods edit the tables through the recursive mapping, which
+    /// stays writable, so they keep working. Only views mapped by 4KiB pages
+    /// are protected, huge pages of the direct map aren't split. Returns the
+    /// number of protected frames.
+    ///
+    /// Tables created later aren't protected, so this should be called again
+    /// after larger changes.
+    pub fn protect_page_tables(&mut self) -> usize {
+        const BATCH_SIZE: usize = 64;
+
+        let direct_map = match self.direct_map() {
+            Some(frames) => frames,
+            None => return 0,
+        };
+
+        let mut protected = 0;
+        let mut skip = 0;
+        loop {
+            // collect the table frames in batches, the walk borrows the tables
+            let mut batch = [0; BATCH_SIZE];
+            let mut batch_len = 0;
+            {
+                let mut seen = 0;
+                let mut collect = |frame: &Frame| {
+                    if direct_map.contains(frame) {
+                        if seen >= skip && batch_len < BATCH_SIZE {
+                            batch[batch_len] = frame.number;
+                            batch_len += 1;
+                        }
+                        seen += 1;
+                    }
+                    false
+                };
+                collect(&self.p4()[RECURSIVE_INDEX].pointed_frame().unwrap());
+                self.count_table_frames(&mut collect);
+            }
 
+            for &number in &batch[..batch_len] {
+                let address = DIRECT_MAP_BASE + number * PAGE_SIZE;
+                if let Some((_, flags, PageSize::Size4KiB)) = self.translate_level(address) {
+                    if flags.contains(WRITABLE) {
+                        let page = Page::containing_address(address);
+                        self.update_flags(page, flags - WRITABLE).flush(self);
+                        protected += 1;
+                    }
+                }
+            }
+
+            if batch_len < BATCH_SIZE {
+                return protected;
+            }
+            skip += BATCH_SIZE;
+        }
+    }
+
+    /// Handles a page fault at `address` by populating the page with a zeroed
+    /// frame if it is demand paged, see `Mapper::reserve_region`. Returns
+    /// whether the fault was handled, or `MapError::FrameAllocationFailed`
+    /// if there is no frame left for the page or its page tables, so that the
+    /// fault handler can decide what to do.
+    pub fn populate_on_fault<A>(&mut self, address: VirtualAddress, allocator: &mut A)
+        -> Result<bool, MapError>
+        where A: FrameAllocator
+    {
+        let page = Page::containing_address(address);
+        if self.demand_flags(page).is_none() {
+            return Ok(false);
+        }
+
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return Err(MapError::FrameAllocationFailed),
+        };
+        self.with_frame_mapped_mut(&frame, allocator, |contents| {
+            for byte in contents.iter_mut() {
+                *byte = 0;
+            }
+        });
+        match self.populate(page, frame.clone(), allocator) {
+            Ok(flush) => {
+                flush.expect("page is demand paged").flush(self);
+                Ok(true)
+            }
+            Err(error) => {
+                allocator.deallocate_frame(frame);
+                Err(error)
+            }
+        }
+    }
+
+    /// Makes `new_table` the active table and returns the previous one. If
+    /// `new_table` is active already, e.g. when switching between kernel
+    /// threads, CR3 isn't reloaded, which would flush the TLB needlessly.
+    pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(unsafe { controlregs::cr3() } as usize),
+            p4_frame: Frame::containing_address(hw::read_cr3()),
         };
-        unsafe {
-            controlregs::cr3_write(new_table.p4_frame.start_address() as u64);
+        if new_table.p4_frame == old_table.p4_frame {
+            self.switches_skipped += 1;
+        } else {
+            unsafe {
+                hw::write_cr3(new_table.p4_frame.start_address());
+            }
         }
         old_table
     }
+
+    /// Returns the number of `switch` calls that didn't reload CR3.
+    pub fn switches_skipped(&self) -> usize {
+        self.switches_skipped
+    }
+
+    /// Prints the used entries of the P4 table: index, frame of the P3 table
+    /// and flags, marking the recursive entry. Unlike `dump_mappings`, no
+    /// lower level table is read, so this is safe to call with corrupted
+    /// tables, e.g. from a double fault handler.
+    pub fn dump_p4(&self) {
+        use vga_buffer::WRITER;
+        write_p4(self.p4(), &mut *WRITER.lock()).unwrap();
+    }
+}
+
+
+/// An editing session on an inactive page table, see `ActivePageTable::edit`.
+///
+/// The session derefs to a `Mapper` whose changes and translations apply to
+/// the inactive table. Since the active table is borrowed, their flushes
+/// can't be applied by mistake and have to be discarded with
+/// `MapperFlush::ignore_in_session`.
+pub struct Session<'a> {
+    active_table: &'a mut ActivePageTable,
+    temporary_page: &'a mut TemporaryPage,
+    _table: &'a mut InactivePageTable,
+    backup: Frame,
+    p4_table: *mut Table<Level1>,
+}
+
+impl<'a> Deref for Session<'a> {
+    type Target = Mapper;
+
+    fn deref(&self) -> &Mapper {
+        &self.active_table.mapper
+    }
+}
+
+impl<'a> DerefMut for Session<'a> {
+    fn deref_mut(&mut self) -> &mut Mapper {
+        &mut self.active_table.mapper
+    }
 }
 
+impl<'a> Drop for Session<'a> {
+    fn drop(&mut self) {
+        // Restore recursive mapping to its original p4 table.
+        let p4_table = unsafe { &mut *self.p4_table };
+        set_recursive_entry(p4_table, self.backup.clone());
+
+        self.temporary_page.unmap(self.active_table);
+    }
+}
+
+/// Writes the P4 dump of `ActivePageTable::dump_p4` to `out`.
+fn write_p4<W: fmt::Write>(p4: &Table<Level4>, out: &mut W) -> fmt::Result {
+    let mut unused = 0;
+    for index in 0..ENTRY_COUNT {
+        let entry = &p4[index];
+        if entry.is_unused() {
+            unused += 1;
+            continue;
+        }
+        let recursive = if index == RECURSIVE_INDEX { " (recursive)" } else { "" };
+        match entry.pointed_frame() {
+            Some(frame) => try!(writeln!(out, "P4[{}] -> {:?} {:?}{}",
+                                         index, frame, entry.flags(), recursive)),
+            None => try!(writeln!(out, "P4[{}] not present {:?}{}",
+                                  index, entry.flags(), recursive)),
+        }
+    }
+    writeln!(out, "{} unused P4 entries", unused)
+}
+
+/// Points the recursive entry of `p4_table` at `frame`. The whole TLB is
+/// flushed, since every recursive address now resolves through a different
+/// table. Starting and ending an editing session both go through here, so
+/// `with` flushes exactly twice.
+fn set_recursive_entry<L: TableLevel>(p4_table: &mut Table<L>, frame: Frame) {
+    p4_table[RECURSIVE_INDEX].set(frame, PRESENT | WRITABLE);
+    hw::flush_all();
+}
 
 pub struct InactivePageTable {
     p4_frame: Frame,
 }
 
+/// The reasons the verification of a new `InactivePageTable` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InactiveTableError {
+    /// The recursive entry doesn't point to the table itself.
+    RecursiveEntryMismatch,
+    /// The entry with the given index wasn't zeroed.
+    NotZeroed(usize),
+    /// The temporary page couldn't be used to access the table.
+    Map(MapError),
+}
+
 impl InactivePageTable {
     pub fn new(frame: Frame,
                active_table: &mut ActivePageTable,
                temporary_page: &mut TemporaryPage)
                -> InactivePageTable {
-        {
-            let table = temporary_page.map_table_frame(frame.clone(), active_table);
+        InactivePageTable::try_new(frame, active_table, temporary_page)
+            .expect("failed to set up inactive page table")
+    }
+
+    /// Like `new`, but in debug builds reads the table back through the
+    /// temporary page and returns an error if it wasn't set up correctly.
+    pub fn try_new(frame: Frame,
+                   active_table: &mut ActivePageTable,
+                   temporary_page: &mut TemporaryPage)
+                   -> Result<InactivePageTable, InactiveTableError> {
+        try!(temporary_page.check_free(active_table).map_err(InactiveTableError::Map));
+        let result = {
+            let mut mapping = temporary_page.map_scoped(frame.clone(), active_table);
+            let table = mapping.table::<Level1>();
             // now we are able to zero the table
             table.zero();
             // set up recursive mapping for the table
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
-        }
-        temporary_page.unmap(active_table);
+            table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
+
+            if cfg!(debug_assertions) {
+                verify_new_table(table, &frame)
+            } else {
+                Ok(())
+            }
+        };
+
+        result.map(|()| InactivePageTable { p4_frame: frame })
+    }
+
+    /// Maps the P4 table through `temporary_page` and passes it to `f`. Unlike
+    /// `ActivePageTable::edit`, the recursive mapping isn't changed, so only
+    /// the P4 entries themselves can be accessed.
+    pub fn map_p4_temporarily<F, R>(&mut self,
+                                    active_table: &mut ActivePageTable,
+                                    temporary_page: &mut TemporaryPage,
+                                    f: F)
+                                    -> R
+        where F: FnOnce(&mut Table<Level4>) -> R
+    {
+        self.try_map_p4_temporarily(active_table, temporary_page, f)
+            .expect("temporary page is still mapped")
+    }
+
+    /// Like `map_p4_temporarily`, but returns `MapError::TemporaryPageInUse`
+    /// instead of calling `f` if `temporary_page` is mapped already.
+    pub fn try_map_p4_temporarily<F, R>(&mut self,
+                                        active_table: &mut ActivePageTable,
+                                        temporary_page: &mut TemporaryPage,
+                                        f: F)
+                                        -> Result<R, MapError>
+        where F: FnOnce(&mut Table<Level4>) -> R
+    {
+        try!(temporary_page.check_free(active_table));
+        let mut mapping = temporary_page.map_scoped(self.p4_frame.clone(), active_table);
+        let result = f(mapping.table::<Level4>());
+        Ok(result)
+    }
+
+    /// Copies the kernel half of the active P4 table into this table, so that
+    /// the kernel stays mapped while this table is active. The recursive entry
+    /// is skipped, it has to keep pointing to this table. Only the P4 entries
+    /// are copied, so kernel mappings added later below an existing entry are
+    /// shared, see `ActivePageTable::preallocate_kernel_tables`.
+    pub fn share_kernel_mappings(&mut self,
+                                 active_table: &mut ActivePageTable,
+                                 temporary_page: &mut TemporaryPage)
+    {
+        // the active P4 table stays reachable through the recursive mapping,
+        // and mapping the temporary page only touches its user half
+        let active_p4: *const Table<Level4> = active_table.p4();
+        self.map_p4_temporarily(active_table, temporary_page, |p4| {
+            copy_kernel_entries(unsafe { &*active_p4 }, p4);
+        });
+    }
+
+    /// Counts the data frames mapped in the user half of this table without
+    /// switching to it. Page table frames aren't counted, and neither is the
+    /// kernel half, which is shared between all address spaces.
+    pub fn resident_frames(&mut self,
+                           active_table: &mut ActivePageTable,
+                           temporary_page: &mut TemporaryPage)
+                           -> usize
+    {
+        let session = active_table.edit(self, temporary_page);
+        count_user_frames(session.p4())
+    }
+
+    /// Frees every frame mapped in the user half of this table, as well as
+    /// the page tables mapping them, and clears the user half. The kernel half
+    /// is shared between all address spaces and left alone.
+    pub fn free_user_space<A>(&mut self,
+                              active_table: &mut ActivePageTable,
+                              temporary_page: &mut TemporaryPage,
+                              allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let mut session = active_table.edit(self, temporary_page);
+        free_user_frames(session.p4_mut(), allocator);
+    }
 
-        InactivePageTable { p4_frame: frame }
+    /// Frees the P3, P2 and P1 tables of the user half that have no used
+    /// entries left, e.g. after many pages were unmapped. Returns the number
+    /// of freed table frames. The P4 table and the kernel half, which is
+    /// shared with other address spaces, are left alone.
+    pub fn compact<A>(&mut self,
+                      active_table: &mut ActivePageTable,
+                      temporary_page: &mut TemporaryPage,
+                      allocator: &mut A)
+                      -> usize
+        where A: FrameAllocator
+    {
+        let mut session = active_table.edit(self, temporary_page);
+        free_empty_user_tables(session.p4_mut(), allocator)
     }
 }
 
+fn free_empty_user_tables<A>(p4: &mut Table<Level4>, allocator: &mut A) -> usize
+    where A: FrameAllocator
+{
+    let mut freed = 0;
+
+    for p4_index in 0..KERNEL_P4_INDEX {
+        if p4.next_table(p4_index).is_none() {
+            continue;
+        }
+        {
+            let p3 = p4.next_table_mut(p4_index).unwrap();
+            for p3_index in 0..ENTRY_COUNT {
+                if p3.next_table(p3_index).is_none() {
+                    // unused or a 1GiB page
+                    continue;
+                }
+                {
+                    let p2 = p3.next_table_mut(p3_index).unwrap();
+                    for p2_index in 0..ENTRY_COUNT {
+                        if p2.next_table(p2_index).map_or(false, |p1| p1.is_empty()) {
+                            let p1_frame = p2[p2_index].pointed_frame().unwrap();
+                            p2[p2_index].set_unused();
+                            allocator.deallocate_frame(p1_frame);
+                            freed += 1;
+                        }
+                    }
+                }
+                if p3.next_table(p3_index).unwrap().is_empty() {
+                    let p2_frame = p3[p3_index].pointed_frame().unwrap();
+                    p3[p3_index].set_unused();
+                    allocator.deallocate_frame(p2_frame);
+                    freed += 1;
+                }
+            }
+        }
+        if p4.next_table(p4_index).unwrap().is_empty() {
+            let p3_frame = p4[p4_index].pointed_frame().unwrap();
+            p4[p4_index].set_unused();
+            allocator.deallocate_frame(p3_frame);
+            freed += 1;
+        }
+    }
+
+    freed
+}
 
-pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation)
+fn free_user_frames<A>(p4: &mut Table<Level4>, allocator: &mut A)
     where A: FrameAllocator
 {
-    use core::ops::Range;
+    for p4_index in 0..KERNEL_P4_INDEX {
+        let p3_frame = match p4[p4_index].pointed_frame() {
+            Some(frame) => frame,
+            None => continue,
+        };
+        {
+            let p3 = p4.next_table_mut(p4_index).unwrap();
+            for p3_index in 0..ENTRY_COUNT {
+                let p2_frame = match p3[p3_index].pointed_frame() {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                if p3[p3_index].flags().contains(HUGE_PAGE) {
+                    // 1GiB page
+                    free_frames(p2_frame, ENTRY_COUNT * ENTRY_COUNT, allocator);
+                    continue;
+                }
+                {
+                    let p2 = p3.next_table_mut(p3_index).unwrap();
+                    for p2_index in 0..ENTRY_COUNT {
+                        let p1_frame = match p2[p2_index].pointed_frame() {
+                            Some(frame) => frame,
+                            None => continue,
+                        };
+                        if p2[p2_index].flags().contains(HUGE_PAGE) {
+                            // 2MiB page
+                            free_frames(p1_frame, ENTRY_COUNT, allocator);
+                            continue;
+                        }
+                        {
+                            let p1 = p2.next_table_mut(p2_index).unwrap();
+                            for p1_index in 0..ENTRY_COUNT {
+                                if let Some(frame) = p1[p1_index].pointed_frame() {
+                                    allocator.deallocate_frame(frame);
+                                }
+                            }
+                        }
+                        allocator.deallocate_frame(p1_frame);
+                    }
+                }
+                allocator.deallocate_frame(p2_frame);
+            }
+        }
+        p4[p4_index].set_unused();
+        allocator.deallocate_frame(p3_frame);
+    }
+}
 
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe },
-        allocator);
+/// Checks that `table` was zeroed and maps itself recursively.
+fn verify_new_table(table: &Table<Level1>, frame: &Frame) -> Result<(), InactiveTableError> {
+    let recursive_entry = &table[RECURSIVE_INDEX];
+    if recursive_entry.pointed_frame().as_ref() != Some(frame) ||
+       recursive_entry.flags() != PRESENT | WRITABLE {
+        return Err(InactiveTableError::RecursiveEntryMismatch);
+    }
 
-    let mut active_table = unsafe { ActivePageTable::new() };
-    let mut new_table = {
-        let frame = allocator.allocate_frame().expect("no more frames");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
-    };
+    for &index in &[0, KERNEL_P4_INDEX - 1, KERNEL_P4_INDEX, RECURSIVE_INDEX - 1] {
+        if !table[index].is_unused() {
+            return Err(InactiveTableError::NotZeroed(index));
+        }
+    }
+    Ok(())
+}
 
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-        let elf_sections_tag = boot_info.elf_sections_tag()
-            .expect("Memory map tag required");
+/// Frees the P3, P2 and P1 tables of the user half, but none of the frames
+/// they map, and clears the user half. The kernel half is shared.
+fn free_page_tables<A>(p4: &mut Table<Level4>, allocator: &mut A)
+    where A: FrameAllocator
+{
+    for p4_index in 0..KERNEL_P4_INDEX {
+        let p3_frame = match p4[p4_index].pointed_frame() {
+            Some(frame) => frame,
+            None => continue,
+        };
+        {
+            let p3 = p4.next_table_mut(p4_index).unwrap();
+            for p3_index in 0..ENTRY_COUNT {
+                let p2_frame = match p3.next_table(p3_index) {
+                    Some(_) => p3[p3_index].pointed_frame().unwrap(),
+                    None => continue,
+                };
+                {
+                    let p2 = p3.next_table_mut(p3_index).unwrap();
+                    for p2_index in 0..ENTRY_COUNT {
+                        if p2.next_table(p2_index).is_some() {
+                            allocator.deallocate_frame(p2[p2_index].pointed_frame().unwrap());
+                        }
+                    }
+                }
+                allocator.deallocate_frame(p2_frame);
+            }
+        }
+        p4[p4_index].set_unused();
+        allocator.deallocate_frame(p3_frame);
+    }
+}
 
-        for section in elf_sections_tag.sections() {
-            use multiboot2::ELF_SECTION_ALLOCATED;
-            use self::entry::WRITABLE;
+fn free_frames<A>(start: Frame, count: usize, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let range = FrameRange { start: start.number, end: start.number + count };
+    for frame in range.iter() {
+        allocator.deallocate_frame(frame);
+    }
+}
+
+fn count_user_frames(p4: &Table<Level4>) -> usize {
+    let mut count = 0;
 
-            if !section.flags().contains(ELF_SECTION_ALLOCATED) {
-                // section is not loaded to memory
+    for p4_index in 0..KERNEL_P4_INDEX {
+        let p3 = match p4.next_table(p4_index) {
+            Some(p3) => p3,
+            None => continue,
+        };
+        for p3_index in 0..ENTRY_COUNT {
+            let p3_flags = p3[p3_index].flags();
+            if !p3_flags.contains(PRESENT) {
+                continue;
+            }
+            if p3_flags.contains(HUGE_PAGE) {
+                // 1GiB page
+                count += ENTRY_COUNT * ENTRY_COUNT;
                 continue;
             }
 
-            println!("mapping section at addr: {:#x}, size: {:#x}",
-                section.addr, section.size);
+            let p2 = p3.next_table(p3_index).unwrap();
+            for p2_index in 0..ENTRY_COUNT {
+                let p2_flags = p2[p2_index].flags();
+                if !p2_flags.contains(PRESENT) {
+                    continue;
+                }
+                if p2_flags.contains(HUGE_PAGE) {
+                    // 2MiB page
+                    count += ENTRY_COUNT;
+                    continue;
+                }
+
+                let p1 = p2.next_table(p2_index).unwrap();
+                count += (0..ENTRY_COUNT)
+                    .filter(|&p1_index| p1[p1_index].flags().contains(PRESENT))
+                    .count();
+            }
+        }
+    }
+
+    count
+}
+
+
+/// Copies the kernel half entries of `source` except the recursive one to
+/// `target`. Entries that aren't present in `source` are cleared.
+fn copy_kernel_entries(source: &Table<Level4>, target: &mut Table<Level4>) {
+    for index in KERNEL_P4_INDEX..RECURSIVE_INDEX {
+        let entry = &source[index];
+        match entry.pointed_frame() {
+            Some(frame) => target[index].set(frame, entry.flags()),
+            None => target[index].set_unused(),
+        }
+    }
+}
+
+/// Checks that every page of the given ELF section is mapped to the frame the
+/// linker placed it at, and with the flags `remap_the_kernel` derives from the
+/// section flags. Sections linked above `KERNEL_BASE` are expected at
+/// `virt - KERNEL_BASE`, all others are expected to be identity mapped.
+pub fn check_section_mapped(active_table: &ActivePageTable, section: &ElfSection)
+    -> bool
+{
+    let checked_flags = PRESENT | WRITABLE | NO_EXECUTE;
+    // the section was mapped with the flags the processor supports
+    let expected_flags =
+        supported_flags(EntryFlags::from_elf_section_flags(section)) & checked_flags;
+
+    let start = section.start_address() - section.start_address() % PAGE_SIZE;
+    let end = section.end_address();
+
+    let mut address = start;
+    while address < end {
+        let page = Page::containing_address(address);
+        let physical_address = if address >= KERNEL_BASE {
+            address - KERNEL_BASE
+        } else {
+            address
+        };
+
+        match active_table.translate_page(page) {
+            Some(frame) => {
+                if frame != Frame::containing_address(physical_address) {
+                    return false;
+                }
+            }
+            None => return false,
+        }
+        match active_table.get_flags(page) {
+            Some(flags) => {
+                if flags & checked_flags != expected_flags {
+                    return false;
+                }
+            }
+            None => return false,
+        }
+
+        address += PAGE_SIZE;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+    use hw;
+
+    /// Returns a new inactive table and a temporary page to edit it with.
+    fn inactive_table<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+                         -> (InactivePageTable, TemporaryPage)
+        where A: FrameAllocator
+    {
+        let mut temporary_page = TemporaryPage::new(address_space::TEMPORARY_PAGE,
+                                                    active_table, allocator);
+        let frame = allocator.allocate_frame().unwrap();
+        let table = InactivePageTable::new(frame, active_table, &mut temporary_page);
+        (table, temporary_page)
+    }
+
+    #[test]
+    fn new_inactive_tables_are_zeroed_and_map_themselves() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let mut temporary_page = TemporaryPage::new(address_space::TEMPORARY_PAGE,
+                                                    &active_table, &mut allocator);
+        let frame = allocator.allocate_frame().unwrap();
+        let entries = ::memory::test_support::physical_pointer::<[u64; ENTRY_COUNT]>(
+            frame.start_address());
+        // leftovers of a previous use of the frame
+        unsafe { (*entries)[0] = 0x1234_0003 };
+
+        let table = InactivePageTable::try_new(frame.clone(), &mut active_table,
+                                               &mut temporary_page);
+        assert_eq!(table.map(|table| table.p4_frame), Ok(frame.clone()));
+        let entries = unsafe { &*entries };
+        assert_eq!(entries[RECURSIVE_INDEX], frame.start_address() as u64 | 0b11);
+        assert!(entries[..RECURSIVE_INDEX].iter().all(|&entry| entry == 0));
+    }
+
+    #[test]
+    fn tables_written_through_a_broken_temporary_page_are_detected() {
+        let frame = Frame::containing_address(0x5000);
+        // the temporary page mapped another frame, which holds some old table
+        let mut table: Box<Table<Level1>> = Box::new(unsafe { mem::zeroed() });
+        table[RECURSIVE_INDEX].set(Frame::containing_address(0x6000), PRESENT | WRITABLE);
+        assert_eq!(verify_new_table(&table, &frame),
+                   Err(InactiveTableError::RecursiveEntryMismatch));
+
+        table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE | USER_ACCESSIBLE);
+        assert_eq!(verify_new_table(&table, &frame),
+                   Err(InactiveTableError::RecursiveEntryMismatch));
+
+        // the recursive entry made it, but the zeroing went elsewhere
+        table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
+        table[KERNEL_P4_INDEX].set(Frame::containing_address(0x7000), PRESENT | WRITABLE);
+        assert_eq!(verify_new_table(&table, &frame),
+                   Err(InactiveTableError::NotZeroed(KERNEL_P4_INDEX)));
+
+        table[KERNEL_P4_INDEX].set_unused();
+        assert_eq!(verify_new_table(&table, &frame), Ok(()));
+    }
+
+    #[test]
+    fn kernel_entries_copied_through_the_temporary_p4_mapping_match() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        for &address in &[0xffff_8000_0000_0000, 0xffff_9000_0000_0000, 0xffff_fe80_0000_0000] {
+            active_table.map(Page::containing_address(address), WRITABLE | GLOBAL, &mut allocator)
+                .flush(&active_table);
+        }
+        let (mut table, mut temporary_page) = inactive_table(&mut active_table, &mut allocator);
+        let table_frame = table.p4_frame.clone();
+
+        table.share_kernel_mappings(&mut active_table, &mut temporary_page);
+
+        let active_p4: *const Table<Level4> = active_table.p4();
+        let active_p4 = unsafe { &*active_p4 };
+        table.map_p4_temporarily(&mut active_table, &mut temporary_page, |p4| {
+            for index in 0..ENTRY_COUNT {
+                let expected = match index {
+                    RECURSIVE_INDEX => (Some(table_frame.clone()), PRESENT | WRITABLE),
+                    index if index >= KERNEL_P4_INDEX => {
+                        (active_p4[index].pointed_frame(), active_p4[index].flags())
+                    }
+                    _ => (None, EntryFlags::empty()),
+                };
+                assert_eq!((p4[index].pointed_frame(), p4[index].flags()), expected,
+                           "P4 entry {}", index);
+            }
+            assert!(p4[KERNEL_P4_INDEX].pointed_frame().is_some());
+        });
+        assert!(active_table.translate_page(address_space::TEMPORARY_PAGE).is_none());
+    }
+
+    #[test]
+    fn resident_frames_counts_the_user_data_frames() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let (mut table, mut temporary_page) = inactive_table(&mut active_table, &mut allocator);
+        let active_p4 = active_table.p4()[RECURSIVE_INDEX].pointed_frame();
+
+        active_table.with(&mut table, &mut temporary_page, |session| {
+            for i in 0..5 {
+                // spread over several page tables, which must not count
+                let page = Page::containing_address(0x40_0000 + i * 0x4000_0000);
+                session.map_to(page, Frame { number: 0x1000 + i }, WRITABLE, &mut allocator)
+                    .ignore_in_session(session);
+            }
+            // the kernel half is shared and doesn't count either
+            let kernel_page = Page::containing_address(0xffff_8000_0000_0000);
+            session.map_to(kernel_page, Frame { number: 0x2000 }, WRITABLE, &mut allocator)
+                .ignore_in_session(session);
+        });
+
+        assert_eq!(table.resident_frames(&mut active_table, &mut temporary_page), 5);
+        assert_eq!(active_table.p4()[RECURSIVE_INDEX].pointed_frame(), active_p4);
+        assert!(active_table.translate_page(address_space::TEMPORARY_PAGE).is_none());
+    }
+
+    #[test]
+    fn compacting_frees_exactly_the_empty_tables() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let (mut table, mut temporary_page) = inactive_table(&mut active_table, &mut allocator);
+        // the second page shares its P1 table with the first, the third has
+        // its own P2 and P1 tables, and the last has its own P3, P2 and P1 tables
+        let pages: Vec<Page> = [0x40_0000, 0x40_1000, 0x8000_0000, 0x80_0000_0000].iter()
+            .map(|&address| Page::containing_address(address))
+            .collect();
+
+        active_table.with(&mut table, &mut temporary_page, |session| {
+            for (i, &page) in pages.iter().enumerate() {
+                session.map_to(page, Frame { number: 0x1000 + i }, WRITABLE, &mut allocator)
+                    .ignore_in_session(session);
+            }
+            for &i in &[0, 2, 3] {
+                let (_, flush) = session.unmap(pages[i], &mut allocator).unwrap();
+                flush.ignore_in_session(session);
+            }
+        });
+        let used_frames = allocator.used_frames();
+
+        assert_eq!(table.compact(&mut active_table, &mut temporary_page, &mut allocator), 5);
+        assert_eq!(allocator.used_frames(), used_frames - 5);
+        assert_eq!(table.compact(&mut active_table, &mut temporary_page, &mut allocator), 0);
+        active_table.with(&mut table, &mut temporary_page, |session| {
+            assert_eq!(session.translate_page(pages[1]), Some(Frame { number: 0x1001 }));
+            assert!(session.translate_page(pages[2]).is_none());
+        });
+    }
+
+    #[test]
+    fn page_tables_are_read_only_in_the_direct_map_after_protecting_them() {
+        use memory::test_support::MIB;
+
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let frames = FrameRange::containing_addresses(0, 8 * MIB as usize);
+        map_physical_memory(frames, PageSize::Size4KiB, &mut active_table, &mut allocator)
+            .unwrap();
+        let data_frame = allocator.allocate_frame().unwrap();
+        let p4_frame = active_table.p4()[RECURSIVE_INDEX].pointed_frame().unwrap();
+        let p1_frame = {
+            let page = Page::containing_address(DIRECT_MAP_BASE);
+            let p2 = active_table.p4().next_table(page.p4_index())
+                .and_then(|p3| p3.next_table(page.p3_index()))
+                .unwrap();
+            p2[page.p2_index()].pointed_frame().unwrap()
+        };
+        let view_flags = |active_table: &ActivePageTable, frame: &Frame| {
+            active_table.get_flags(Page::containing_address(DIRECT_MAP_BASE +
+                                                            frame.start_address()))
+                .unwrap()
+        };
+        assert!(view_flags(&active_table, &p1_frame).contains(WRITABLE));
 
-            let flags = EntryFlags::from_elf_section_flags(section);
+        let table_frames = 1 + active_table.count_table_frames(|_| true);
+        assert_eq!(active_table.protect_page_tables(), table_frames);
 
-            let range = Range {
-                start: section.addr as usize,
-                end: (section.addr + section.size) as usize,
-            };
-            
-            for address in range.step_by(PAGE_SIZE) {
-                assert!(address % PAGE_SIZE == 0,
-                "sections need to be page aligned");
-                let frame = Frame::containing_address(address);
-                mapper.identity_map(frame, flags, allocator);
+        for frame in &[p4_frame, p1_frame] {
+            assert!(!view_flags(&active_table, frame).contains(WRITABLE));
+        }
+        assert!(view_flags(&active_table, &data_frame).contains(WRITABLE));
+        // the tables stay writable through the recursive mapping
+        let page = Page::containing_address(0x40_0000);
+        active_table.map(page, WRITABLE, &mut allocator).flush(&active_table);
+        assert!(active_table.translate_page(page).is_some());
+        assert_eq!(active_table.protect_page_tables(), 3);
+    }
+
+    #[test]
+    fn remapped_pages_keep_their_contents_and_flags() {
+        use memory::test_support::physical_pointer;
+
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let page = Page::containing_address(0x40_0000);
+        active_table.map(page, WRITABLE | NO_EXECUTE, &mut allocator).flush(&active_table);
+        let old_frame = active_table.translate_page(page).unwrap();
+        let contents = unsafe { &mut *virtual_pointer::<[u8; PAGE_SIZE]>(page.start_address()) };
+        for (i, byte) in contents.iter_mut().enumerate() {
+            *byte = (i * 3) as u8;
+        }
+        let new_frame = allocator.allocate_frame().unwrap();
+        let new_frame_number = new_frame.number;
+
+        assert_eq!(active_table.remap(page, new_frame, &mut allocator), Ok(old_frame.clone()));
+
+        assert_eq!(active_table.translate_page(page), Some(Frame { number: new_frame_number }));
+        assert_eq!(active_table.get_flags(page), Some(PRESENT | WRITABLE | NO_EXECUTE));
+        let new_contents = unsafe {
+            &*physical_pointer::<[u8; PAGE_SIZE]>(new_frame_number * PAGE_SIZE)
+        };
+        assert!(new_contents.iter().enumerate().all(|(i, &byte)| byte == (i * 3) as u8));
+        assert_eq!(active_table.remap(page, Frame { number: new_frame_number }, &mut allocator),
+                   Err(MapError::PageAlreadyMapped));
+        let unmapped_page = Page::containing_address(0x40_1000);
+        assert_eq!(active_table.remap(unmapped_page, old_frame, &mut allocator),
+                   Err(MapError::PageNotMapped));
+    }
+
+    #[test]
+    fn frame_contents_written_through_the_window_can_be_read_back() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let frame = allocator.allocate_frame().unwrap();
+
+        active_table.with_frame_mapped_mut(&frame, &mut allocator, |bytes| {
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = i as u8 ^ 0xa5;
+            }
+        });
+        assert!(active_table.translate_page(FRAME_WINDOW_PAGE).is_none());
+
+        let matches = active_table.with_frame_mapped(&frame, &mut allocator, |bytes| {
+            bytes.iter().enumerate().all(|(i, &byte)| byte == i as u8 ^ 0xa5)
+        });
+        assert!(matches);
+        assert!(active_table.translate_page(FRAME_WINDOW_PAGE).is_none());
+    }
+
+    #[test]
+    fn sessions_and_the_active_table_translate_their_own_mappings() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let (mut table, mut temporary_page) = inactive_table(&mut active_table, &mut allocator);
+        let active_page = Page::containing_address(0x40_0000);
+        let inactive_page = Page::containing_address(0x40_1000);
+        active_table.map_to(active_page, Frame { number: 0x1234 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        active_table.with(&mut table, &mut temporary_page, |session| {
+            session.map_to(inactive_page, Frame { number: 0x1235 }, WRITABLE, &mut allocator)
+                .ignore_in_session(session);
+            assert_eq!(session.translate_page(inactive_page), Some(Frame { number: 0x1235 }));
+            assert_eq!(session.translate_page(active_page), None);
+        });
+
+        assert_eq!(active_table.translate_page(active_page), Some(Frame { number: 0x1234 }));
+        assert_eq!(active_table.translate_page(inactive_page), None);
+    }
+
+    #[test]
+    fn copied_frames_match_byte_for_byte() {
+        use memory::test_support::physical_pointer;
+
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let base = address_space::TEMPORARY_PAGE;
+        let mut temporary_page = TemporaryPage::with_slots(base, 2, &active_table,
+                                                           &mut allocator);
+        let frames: Vec<Frame> = (0..3).map(|_| allocator.allocate_frame().unwrap()).collect();
+        let contents = |frame: &Frame| unsafe {
+            &mut *physical_pointer::<[u8; PAGE_SIZE]>(frame.start_address())
+        };
+        for (i, byte) in contents(&frames[0]).iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        for byte in contents(&frames[2]).iter_mut() {
+            *byte = 0x5a;
+        }
+
+        active_table.copy_frame(&frames[0], &frames[1], &mut temporary_page);
+        active_table.copy_frame(&frames[0], &frames[0], &mut temporary_page);
+
+        assert!(contents(&frames[1]).iter().enumerate().all(|(i, &byte)| byte == (i * 7) as u8));
+        assert!(contents(&frames[0]).iter().enumerate().all(|(i, &byte)| byte == (i * 7) as u8));
+        assert!(contents(&frames[2]).iter().all(|&byte| byte == 0x5a));
+        assert_eq!(temporary_page.check_free(&active_table), Ok(()));
+    }
+
+    #[test]
+    fn pages_mapped_in_one_session_are_all_in_the_inactive_table() {
+        let mut allocator = ::memory::test_support::small_allocator();
+        let mut active_table = ::memory::test_support::active_table(&mut allocator);
+        let (mut table, mut temporary_page) = inactive_table(&mut active_table, &mut allocator);
+        let pages: Vec<Page> = [0x40_0000, 0x40_1000, 0x7f_f000, 0x4000_0000].iter()
+            .map(|&address| Page::containing_address(address))
+            .collect();
+
+        {
+            let mut session = active_table.edit(&mut table, &mut temporary_page);
+            for (i, &page) in pages.iter().enumerate() {
+                session.map_to(page, Frame { number: 0x1000 + i }, WRITABLE, &mut allocator)
+                    .ignore_in_session(&session);
             }
         }
 
-        // Identity map the VGA text buffer.
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);    
-    });
+        for &page in &pages {
+            assert_eq!(active_table.translate_page(page), None);
+        }
+        let session = active_table.edit(&mut table, &mut temporary_page);
+        for (i, &page) in pages.iter().enumerate() {
+            assert_eq!(session.translate_page(page), Some(Frame { number: 0x1000 + i }));
+        }
+    }
 
-    let old_table = active_table.switch(new_table);
-    println!("NEW TABLE!!!");
+    #[test]
+    fn page_and_frame_numbers_round_trip() {
+        for &address in &[0, 0x1000, 0x20_3000, 0x7fff_ffff_f000, 0xffff_8000_0000_0000,
+                          0xffff_ffff_ffff_f000] {
+            let page = Page::containing_address(address);
+            assert_eq!(page.number(), page.start_address() / PAGE_SIZE);
+            assert_eq!(Page::from_number(page.number()).start_address(), address);
+        }
+        for &address in &[0, 0x1000, 0x20_3000, 0x000f_ffff_ffff_f000] {
+            let frame = Frame::containing_address(address);
+            assert_eq!(frame.number(), frame.start_address() / PAGE_SIZE);
+            assert_eq!(Frame::from_number(frame.number()), frame);
+        }
+    }
 
-    // Turn the old p4 page table into a guard page.
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
-    println!("guard page at {:#x}", old_p4_page.start_address());
+    #[test]
+    fn debug_output_shows_addresses_and_flag_names() {
+        assert_eq!(format!("{:?}", Frame::containing_address(0x1000)), "Frame(0x1000)");
+        assert_eq!(format!("{:?}", Page::containing_address(0xffff_8000_0000_0000)),
+                   "Page(0xffff800000000000)");
+        assert_eq!(format!("{:?}", PRESENT | WRITABLE | NO_EXECUTE),
+                   "PRESENT | WRITABLE | NO_EXECUTE");
+    }
+
+    #[test]
+    fn pages_and_frames_are_aligned_to_the_sizes_dividing_their_address() {
+        let cases = [
+            (0x1000, [true, false, false]),
+            (0x20_0000, [true, true, false]),
+            (0x4000_0000, [true, true, true]),
+            (0x4020_1000, [true, false, false]),
+        ];
+        let sizes = [PageSize::Size4KiB, PageSize::Size2MiB, PageSize::Size1GiB];
+        for &(address, aligned) in &cases {
+            for (&size, &aligned) in sizes.iter().zip(aligned.iter()) {
+                assert_eq!(Page::containing_address(address).is_aligned_to(size), aligned);
+                assert_eq!(Frame::containing_address(address).is_aligned_to(size), aligned);
+            }
+        }
+    }
+
+    #[test]
+    fn switching_to_the_active_table_skips_the_cr3_write() {
+        hw::with_mock(|hw| hw.cr3.set(0x5000));
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let old_table = active_table.switch(InactivePageTable {
+            p4_frame: Frame::containing_address(0x5000),
+        });
+
+        assert_eq!(old_table.p4_frame, Frame::containing_address(0x5000));
+        assert_eq!(active_table.switches_skipped(), 1);
+        hw::with_mock(|hw| assert_eq!(hw.cr3_writes.get(), 0));
+    }
+
+    #[test]
+    fn switching_to_another_table_writes_cr3() {
+        hw::with_mock(|hw| hw.cr3.set(0x5000));
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        active_table.switch(InactivePageTable {
+            p4_frame: Frame::containing_address(0x6000),
+        });
+
+        assert_eq!(active_table.switches_skipped(), 0);
+        hw::with_mock(|hw| {
+            assert_eq!(hw.cr3_writes.get(), 1);
+            assert_eq!(hw.cr3.get(), 0x6000);
+        });
+    }
+
+    #[test]
+    fn p4_dump_lists_used_entries() {
+        let mut p4: Box<Table<Level4>> = Box::new(unsafe { mem::zeroed() });
+        p4[0].set(Frame::containing_address(0x3000), PRESENT | WRITABLE);
+        p4[256].set(Frame::containing_address(0x4000), PRESENT | WRITABLE | GLOBAL);
+        p4[RECURSIVE_INDEX].set(Frame::containing_address(0x5000), PRESENT | WRITABLE);
+
+        let mut dump = String::new();
+        write_p4(&p4, &mut dump).unwrap();
+
+        let frame = |address| format!("{:?}", Frame::containing_address(address));
+        let expected = format!("P4[0] -> {} {:?}\n\
+                                P4[256] -> {} {:?}\n\
+                                P4[511] -> {} {:?} (recursive)\n\
+                                509 unused P4 entries\n",
+                               frame(0x3000), PRESENT | WRITABLE,
+                               frame(0x4000), PRESENT | WRITABLE | GLOBAL,
+                               frame(0x5000), PRESENT | WRITABLE);
+        assert_eq!(dump, expected);
+    }
+
+    #[test]
+    fn p4_dump_reports_entries_that_are_not_present() {
+        let mut p4: Box<Table<Level4>> = Box::new(unsafe { mem::zeroed() });
+        p4[3].set_swapped(7, WRITABLE);
+
+        let mut dump = String::new();
+        write_p4(&p4, &mut dump).unwrap();
+
+        assert!(dump.starts_with("P4[3] not present"));
+        assert!(dump.ends_with("511 unused P4 entries\n"));
+    }
+
+    #[test]
+    fn editing_session_flushes_twice() {
+        // the same entry changes `with` makes, on a table outside the
+        // recursive mapping
+        let mut p4: Box<Table<Level4>> = Box::new(unsafe { mem::zeroed() });
+        let active = Frame::containing_address(0x1000);
+        let inactive = Frame::containing_address(0x2000);
+
+        set_recursive_entry(&mut *p4, inactive);
+        set_recursive_entry(&mut *p4, active.clone());
+
+        assert_eq!(p4[RECURSIVE_INDEX].pointed_frame(), Some(active));
+        hw::with_mock(|hw| {
+            assert_eq!(hw.flush_alls.get(), 2);
+            assert_eq!(hw.cr3_writes.get(), 0);
+        });
+    }
+
+    #[test]
+    fn section_with_an_unmapped_page_is_not_mapped_correctly() {
+        use memory::test_support::{small_allocator, active_table, elf_boot_information};
+        const WRITABLE_ALLOCATED: u64 = 0x3;
+
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let boot_info = elf_boot_information(&[(".data", 0x40_0000, 0x3000, WRITABLE_ALLOCATED)]);
+        let section = boot_info.elf_sections_tag().unwrap().sections().next().unwrap();
+        let flags = WRITABLE | NO_EXECUTE;
+        for &address in &[0x40_0000, 0x40_2000] {
+            active_table.identity_map(Frame::containing_address(address), flags, &mut allocator)
+                .flush(&active_table);
+        }
+        assert!(!check_section_mapped(&active_table, section));
+
+        active_table.identity_map(Frame::containing_address(0x40_1000), flags, &mut allocator)
+            .flush(&active_table);
+        assert!(check_section_mapped(&active_table, section));
+    }
+
+    #[test]
+    fn kernel_entries_are_copied_except_the_recursive_one() {
+        let mut source: Box<Table<Level4>> = Box::new(unsafe { mem::zeroed() });
+        let mut target: Box<Table<Level4>> = Box::new(unsafe { mem::zeroed() });
+        source[1].set(Frame::containing_address(0x1000), PRESENT | WRITABLE);
+        source[KERNEL_P4_INDEX].set(Frame::containing_address(0x2000), PRESENT | WRITABLE);
+        source[384].set(Frame::containing_address(0x3000), PRESENT | WRITABLE | GLOBAL);
+        source[RECURSIVE_INDEX].set(Frame::containing_address(0x4000), PRESENT | WRITABLE);
+        target[0].set(Frame::containing_address(0x5000), PRESENT | WRITABLE);
+        target[300].set(Frame::containing_address(0x6000), PRESENT | WRITABLE);
+        target[RECURSIVE_INDEX].set(Frame::containing_address(0x7000), PRESENT | WRITABLE);
+
+        copy_kernel_entries(&source, &mut target);
+
+        for index in KERNEL_P4_INDEX..RECURSIVE_INDEX {
+            assert_eq!(target[index].pointed_frame(), source[index].pointed_frame());
+            assert_eq!(target[index].flags(), source[index].flags());
+        }
+        assert!(target[300].is_unused());
+        // the user half and the recursive entry are left alone
+        assert!(target[1].is_unused());
+        assert_eq!(target[0].pointed_frame(), Some(Frame::containing_address(0x5000)));
+        assert_eq!(target[RECURSIVE_INDEX].pointed_frame(),
+                   Some(Frame::containing_address(0x7000)));
+    }
+
+    #[test]
+    fn recursive_table_address_repeats_the_index_at_every_level() {
+        assert_eq!(recursive_table_address(511), 0xffff_ffff_ffff_f000);
+        assert_eq!(recursive_table_address(510), 0xffff_ff7f_bfdf_e000);
+        assert_eq!(recursive_table_address(0), 0);
+        // sign extended only from the upper half on
+        assert_eq!(recursive_table_address(255), 0x7fbf_dfef_f000);
+        assert_eq!(recursive_table_address(256), 0xffff_8040_2010_0000);
+        assert!(is_canonical(recursive_table_address(256)));
+    }
+
+    #[test]
+    fn kernel_table_slots_are_in_the_kernel_half() {
+        assert_eq!(KERNEL_TABLE_SLOTS, [257, 384]);
+        for &index in &KERNEL_TABLE_SLOTS {
+            assert!(index >= KERNEL_P4_INDEX && index < RECURSIVE_INDEX);
+        }
+    }
 }
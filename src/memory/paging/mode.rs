@@ -0,0 +1,102 @@
+use hw::{self, CR0_WRITE_PROTECT, CR0_PAGING, CR4_PAE, CR4_GLOBAL_PAGES, EFER_LONG_MODE_ENABLE,
+         EFER_LONG_MODE_ACTIVE, EFER_NXE};
+
+
+/// The optional paging features enabled in the control registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagingMode {
+    pub write_protect: bool,
+    pub global_pages: bool,
+    pub no_execute: bool,
+}
+
+/// A requirement of 4-level long mode paging that isn't met.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingModeError {
+    /// CR0.PG is clear.
+    PagingDisabled,
+    /// CR4.PAE is clear.
+    PaeDisabled,
+    /// EFER.LME is clear.
+    LongModeDisabled,
+    /// EFER.LMA is clear.
+    LongModeInactive,
+}
+
+/// Checks that the given CR0, CR4 and EFER values describe 4-level long mode
+/// paging, and returns the optional features they enable.
+pub fn check_paging_mode(cr0: u64, cr4: u64, efer: u64)
+    -> Result<PagingMode, PagingModeError>
+{
+    if cr0 & CR0_PAGING == 0 {
+        return Err(PagingModeError::PagingDisabled);
+    }
+    if cr4 & CR4_PAE == 0 {
+        return Err(PagingModeError::PaeDisabled);
+    }
+    if efer & EFER_LONG_MODE_ENABLE == 0 {
+        return Err(PagingModeError::LongModeDisabled);
+    }
+    if efer & EFER_LONG_MODE_ACTIVE == 0 {
+        return Err(PagingModeError::LongModeInactive);
+    }
+
+    Ok(PagingMode {
+        write_protect: cr0 & CR0_WRITE_PROTECT != 0,
+        global_pages: cr4 & CR4_GLOBAL_PAGES != 0,
+        no_execute: efer & EFER_NXE != 0,
+    })
+}
+
+/// Panics unless the processor runs with 4-level long mode paging, and
+/// prints the optional features that are enabled. Has to be called before
+/// any mapping is changed.
+pub fn assert_paging_features() -> PagingMode {
+    let mode = check_paging_mode(hw::read_cr0(), hw::read_cr4(), hw::read_efer())
+        .expect("the kernel needs to run with 4-level long mode paging");
+    println!("paging: 4-level, write protect: {}, global pages: {}, no execute: {}",
+        mode.write_protect, mode.global_pages, mode.no_execute);
+    mode
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CR0: u64 = CR0_PAGING;
+    const CR4: u64 = CR4_PAE;
+    const EFER: u64 = EFER_LONG_MODE_ENABLE | EFER_LONG_MODE_ACTIVE;
+
+    #[test]
+    fn long_mode_without_optional_features_is_accepted() {
+        assert_eq!(check_paging_mode(CR0, CR4, EFER), Ok(PagingMode {
+            write_protect: false,
+            global_pages: false,
+            no_execute: false,
+        }));
+    }
+
+    #[test]
+    fn optional_features_are_reported() {
+        let mode = check_paging_mode(CR0 | CR0_WRITE_PROTECT, CR4 | CR4_GLOBAL_PAGES,
+                                     EFER | EFER_NXE);
+        assert_eq!(mode, Ok(PagingMode {
+            write_protect: true,
+            global_pages: true,
+            no_execute: true,
+        }));
+    }
+
+    #[test]
+    fn each_missing_requirement_is_reported() {
+        assert_eq!(check_paging_mode(CR0 & !CR0_PAGING, CR4, EFER),
+                   Err(PagingModeError::PagingDisabled));
+        assert_eq!(check_paging_mode(CR0, CR4 & !CR4_PAE, EFER),
+                   Err(PagingModeError::PaeDisabled));
+        assert_eq!(check_paging_mode(CR0, CR4, EFER & !EFER_LONG_MODE_ENABLE),
+                   Err(PagingModeError::LongModeDisabled));
+        assert_eq!(check_paging_mode(CR0, CR4, EFER & !EFER_LONG_MODE_ACTIVE),
+                   Err(PagingModeError::LongModeInactive));
+    }
+}
@@ -0,0 +1,229 @@
+use super::{VirtualAddress, RECURSIVE_INDEX, FRAME_WINDOW_PAGE};
+use super::address_space::TEMPORARY_PAGE;
+use super::self_test::TEST_PAGE;
+use super::mmio::{DIRECT_MAP_BASE, DIRECT_MAP_SIZE};
+use memory::PAGE_SIZE;
+
+
+/// The maximum number of virtual regions a `VirtualRegionManager` can track.
+pub const MAX_VIRTUAL_REGIONS: usize = 32;
+
+/// `alloc_region` hands out regions from `[DYNAMIC_REGION_START,
+/// DYNAMIC_REGION_END)`, which is the address space of P4 entry 257. It's in
+/// the kernel half, so that the regions are shared by all address spaces.
+pub const DYNAMIC_REGION_START: VirtualAddress = 0o_177777_401_000_000_000_0000;
+pub const DYNAMIC_REGION_END: VirtualAddress = 0o_177777_402_000_000_000_0000;
+
+/// What a virtual region is reserved for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionPurpose {
+    IdentityMap,
+    Heap,
+    Slab,
+    TemporaryPage,
+    FrameWindow,
+    SelfTest,
+    RecursiveMapping,
+    Mmio,
+    DeviceWindow,
+    Stack,
+    DirectMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The range overlaps a region reserved for the given purpose.
+    Overlaps(RegionPurpose),
+    /// The manager already tracks `MAX_VIRTUAL_REGIONS` regions.
+    TooManyRegions,
+    /// There is no free range of the requested size left.
+    OutOfAddressSpace,
+}
+
+/// A reserved range of virtual memory. `last` is the address of its last
+/// byte, so that a region can end at the top of the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    start: VirtualAddress,
+    last: VirtualAddress,
+    purpose: RegionPurpose,
+}
+
+impl VirtualRegion {
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.last - self.start + 1
+    }
+
+    pub fn purpose(&self) -> RegionPurpose {
+        self.purpose
+    }
+
+    fn overlaps(&self, start: VirtualAddress, last: VirtualAddress) -> bool {
+        self.start <= last && start <= self.last
+    }
+}
+
+/// Keeps track of which virtual address ranges are reserved for what, so
+/// that the users of fixed ranges and of allocated ones can't collide.
+pub struct VirtualRegionManager {
+    regions: [VirtualRegion; MAX_VIRTUAL_REGIONS],
+    len: usize,
+}
+
+impl VirtualRegionManager {
+    pub fn new() -> VirtualRegionManager {
+        let unused = VirtualRegion {
+            start: 0,
+            last: 0,
+            purpose: RegionPurpose::IdentityMap,
+        };
+
+        VirtualRegionManager {
+            regions: [unused; MAX_VIRTUAL_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// The reserved regions, sorted by address.
+    pub fn regions(&self) -> &[VirtualRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Returns what the region containing `address` is reserved for.
+    pub fn purpose_of(&self, address: VirtualAddress) -> Option<RegionPurpose> {
+        self.regions().iter()
+            .find(|region| region.overlaps(address, address))
+            .map(|region| region.purpose)
+    }
+
+    /// Reserves the `size` bytes starting at `start` for `purpose`.
+    pub fn reserve(&mut self, start: VirtualAddress, size: usize, purpose: RegionPurpose)
+        -> Result<(), RegionError>
+    {
+        assert!(size > 0, "cannot reserve an empty virtual region");
+        let last = start.checked_add(size - 1)
+            .expect("virtual region exceeds the address space");
+
+        if let Some(region) = self.regions().iter().find(|r| r.overlaps(start, last)) {
+            return Err(RegionError::Overlaps(region.purpose));
+        }
+        if self.len == MAX_VIRTUAL_REGIONS {
+            return Err(RegionError::TooManyRegions);
+        }
+
+        let index = self.regions().iter().position(|r| r.start > last)
+            .unwrap_or(self.len);
+        for i in (index..self.len).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+        self.regions[index] = VirtualRegion {
+            start: start,
+            last: last,
+            purpose: purpose,
+        };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Releases the region starting at `start` and returns it, so that its
+    /// range can be reserved again.
+    pub fn release(&mut self, start: VirtualAddress) -> Option<VirtualRegion> {
+        let index = match self.regions().iter().position(|r| r.start == start) {
+            Some(index) => index,
+            None => return None,
+        };
+        let region = self.regions[index];
+        for i in index..self.len - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.len -= 1;
+        Some(region)
+    }
+
+    /// Reserves a page aligned region of at least `size` bytes in the dynamic
+    /// part of the address space and returns its start address.
+    pub fn alloc_region(&mut self, size: usize, purpose: RegionPurpose)
+        -> Result<VirtualAddress, RegionError>
+    {
+        assert!(size > 0, "cannot allocate an empty virtual region");
+        let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+        let mut start = DYNAMIC_REGION_START;
+        for region in self.regions() {
+            if start + size <= region.start {
+                break;
+            }
+            if region.overlaps(start, start + size - 1) {
+                // regions are page aligned, so this is as well
+                start = region.last + 1;
+            }
+        }
+
+        if start + size > DYNAMIC_REGION_END {
+            return Err(RegionError::OutOfAddressSpace);
+        }
+        try!(self.reserve(start, size, purpose));
+        Ok(start)
+    }
+}
+
+/// Reserves the fixed regions used by the paging code itself.
+pub fn reserve_paging_regions(regions: &mut VirtualRegionManager)
+    -> Result<(), RegionError>
+{
+    let recursive_start = 0xffff_0000_0000_0000 | (RECURSIVE_INDEX << 39);
+    try!(regions.reserve(recursive_start, 1 << 39, RegionPurpose::RecursiveMapping));
+    try!(regions.reserve(DIRECT_MAP_BASE, DIRECT_MAP_SIZE, RegionPurpose::DirectMap));
+    try!(regions.reserve(FRAME_WINDOW_PAGE.start_address(), PAGE_SIZE,
+                         RegionPurpose::FrameWindow));
+    try!(regions.reserve(TEMPORARY_PAGE.start_address(), PAGE_SIZE,
+                         RegionPurpose::TemporaryPage));
+    try!(regions.reserve(TEST_PAGE.start_address(), PAGE_SIZE, RegionPurpose::SelfTest));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::KERNEL_P4_INDEX;
+
+    #[test]
+    fn allocated_regions_do_not_overlap() {
+        let mut regions = VirtualRegionManager::new();
+        let first = regions.alloc_region(3 * PAGE_SIZE, RegionPurpose::Mmio).unwrap();
+        let second = regions.alloc_region(PAGE_SIZE, RegionPurpose::Stack).unwrap();
+
+        assert!(first + 3 * PAGE_SIZE <= second || second + PAGE_SIZE <= first);
+        assert_eq!(regions.purpose_of(first + 2 * PAGE_SIZE), Some(RegionPurpose::Mmio));
+        assert_eq!(regions.purpose_of(second), Some(RegionPurpose::Stack));
+        for &start in &[first, second] {
+            assert!(start >= DYNAMIC_REGION_START && start < DYNAMIC_REGION_END);
+            assert!((start >> 39) & 0o777 >= KERNEL_P4_INDEX);
+        }
+    }
+
+    #[test]
+    fn allocation_skips_fixed_regions() {
+        let mut regions = VirtualRegionManager::new();
+        regions.reserve(DYNAMIC_REGION_START, PAGE_SIZE, RegionPurpose::Heap).unwrap();
+
+        let start = regions.alloc_region(PAGE_SIZE, RegionPurpose::Mmio).unwrap();
+        assert_eq!(start, DYNAMIC_REGION_START + PAGE_SIZE);
+    }
+
+    #[test]
+    fn reserving_an_overlapping_range_fails() {
+        let mut regions = VirtualRegionManager::new();
+        let start = regions.alloc_region(2 * PAGE_SIZE, RegionPurpose::DeviceWindow).unwrap();
+
+        assert_eq!(regions.reserve(start + PAGE_SIZE, 4 * PAGE_SIZE, RegionPurpose::Heap),
+                   Err(RegionError::Overlaps(RegionPurpose::DeviceWindow)));
+        assert_eq!(regions.reserve(start - PAGE_SIZE, PAGE_SIZE, RegionPurpose::Heap), Ok(()));
+        assert_eq!(regions.regions().len(), 2);
+    }
+}
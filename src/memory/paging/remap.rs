@@ -0,0 +1,533 @@
+use super::{Page, ActivePageTable, InactivePageTable, Session, MapError, VirtualAddress,
+            check_section_mapped, is_canonical};
+use super::entry::*;
+use super::temporary_page::TemporaryPage;
+use super::address_space::TEMPORARY_PAGE;
+use memory::{PAGE_SIZE, Frame, FrameAllocator, FrameRange, AreaFrameAllocator};
+use memory::align::StepRange;
+use multiboot2::{BootInformation, ElfSection, ElfSectionsTag};
+
+
+/// The reasons `try_remap_the_kernel` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemapError {
+    /// The boot information contains no ELF sections tag.
+    MissingElfSectionsTag,
+    /// An allocated section doesn't start at a page boundary.
+    SectionNotPageAligned {
+        name: &'static str,
+        start: usize,
+        size: usize,
+    },
+    /// A frame for the new page tables could not be allocated.
+    FrameAllocationFailed,
+    /// The page at the given address was mapped twice.
+    PageAlreadyMapped(VirtualAddress),
+    /// The allocated sections starting at the given addresses share a frame.
+    OverlappingSections(usize, usize),
+    /// The frames to preserve can't be reserved, because the allocator
+    /// already tracks as many reserved ranges as it can.
+    TooManyReservedRanges,
+    /// An allocated section isn't completely in one of the canonical halves
+    /// of the address space.
+    SectionSpansNonCanonical {
+        name: &'static str,
+        addr: usize,
+        size: usize,
+    },
+}
+
+impl RemapError {
+    fn from_map_error(error: MapError, page: Page) -> RemapError {
+        match error {
+            MapError::FrameAllocationFailed => RemapError::FrameAllocationFailed,
+            MapError::PageAlreadyMapped => RemapError::PageAlreadyMapped(page.start_address()),
+            MapError::MisalignedForHugePage | MapError::PageNotMapped |
+            MapError::TemporaryPageInUse | MapError::PagePinned(_) => {
+                unreachable!("the kernel is mapped with new 4KiB pages")
+            }
+        }
+    }
+}
+
+pub fn remap_the_kernel(allocator: &mut AreaFrameAllocator,
+                        boot_info: &BootInformation,
+                        preserve_identity: &[FrameRange])
+                        -> ActivePageTable
+{
+    try_remap_the_kernel(allocator, boot_info, preserve_identity)
+        .expect("failed to remap the kernel")
+}
+
+/// Identity maps the kernel sections, the multiboot information and the VGA
+/// text buffer in a new page table and switches to it. The old P4 table is
+/// turned into a guard page below the stack; its page is the one containing
+/// the address `hw::read_cr3` returned before the call.
+///
+/// The frames in `preserve_identity` are identity mapped writable and
+/// executable as well, e.g. the BIOS data area or the real mode trampoline
+/// the application processors start in. They are reserved in `allocator`,
+/// so that they are never handed out. Frames that are mapped already, e.g.
+/// as part of the kernel image or the VGA buffer, keep their mapping.
+pub fn try_remap_the_kernel(allocator: &mut AreaFrameAllocator,
+                            boot_info: &BootInformation,
+                            preserve_identity: &[FrameRange])
+                            -> Result<ActivePageTable, RemapError>
+{
+    let elf_sections_tag = try!(boot_info.elf_sections_tag()
+        .ok_or(RemapError::MissingElfSectionsTag));
+
+    // validate the sections before touching any page table
+    try!(check_sections_canonical(elf_sections_tag));
+    try!(check_sections_aligned(elf_sections_tag));
+    try!(check_sections_disjoint(elf_sections_tag));
+
+    for &range in preserve_identity {
+        try!(allocator.reserve(range).map_err(|_| RemapError::TooManyReservedRanges));
+    }
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mut temporary_page = TemporaryPage::new(TEMPORARY_PAGE, &active_table, allocator);
+    let mut new_table = {
+        let frame = try!(allocator.allocate_frame()
+            .ok_or(RemapError::FrameAllocationFailed));
+        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+    };
+
+    let mut result = Ok(());
+    active_table.with(&mut new_table, &mut temporary_page, |session| {
+        result = map_kernel(session, boot_info, elf_sections_tag, preserve_identity, allocator);
+    });
+    try!(result);
+
+    let old_table = active_table.switch(new_table);
+    println!("NEW TABLE!!!");
+
+    if cfg!(debug_assertions) {
+        for section in elf_sections_tag.sections().filter(|s| s.is_allocated() && s.size > 0) {
+            assert!(check_section_mapped(&active_table, section),
+                "section at addr: {:#x}, size: {:#x} is not mapped correctly",
+                section.addr, section.size);
+        }
+    }
+
+    // Turn the old p4 page table into a guard page.
+    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
+    active_table.unmap(old_p4_page, allocator)
+        .expect("failed to unmap the old P4 table").1.flush(&active_table);
+    println!("guard page at {:#x}", old_p4_page.start_address());
+
+    Ok(active_table)
+}
+
+/// Sets the flags of every page of the already mapped kernel sections to the
+/// flags derived from the section flags, e.g. to make `.text` read-only after
+/// early code that needed to write to it.
+///
+/// `guard_page` is skipped: it is the page of the boot loader's P4 table at
+/// the start of `.bss`, which `try_remap_the_kernel` unmapped. Any other
+/// unmapped page is an error, and all pages are checked before the first
+/// flags are changed, so a failed call changes nothing.
+pub fn apply_kernel_protections(active_table: &mut ActivePageTable,
+                                boot_info: &BootInformation,
+                                guard_page: Page)
+                                -> Result<(), MapError>
+{
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("Elf sections tag required");
+    let sections = || elf_sections_tag.sections().filter(|s| s.is_allocated() && s.size > 0);
+    let pages = |section: &ElfSection| {
+        let start_page = Page::containing_address(section.start_address());
+        let end_page = Page::containing_address(section.end_address() - 1);
+        Page::range_inclusive(start_page, end_page)
+            .filter(move |page| page.number != guard_page.number)
+    };
+
+    for section in sections() {
+        for page in pages(section) {
+            if active_table.translate_page(page).is_none() {
+                return Err(MapError::PageNotMapped);
+            }
+        }
+    }
+
+    for section in sections() {
+        let flags = EntryFlags::from_elf_section_flags(section);
+        for page in pages(section) {
+            active_table.update_flags(page, flags).flush(active_table);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that every allocated section lies completely in the lower or the
+/// upper canonical half, so that iterating over its pages can't run into the
+/// non-canonical hole.
+fn check_sections_canonical(elf_sections_tag: &'static ElfSectionsTag)
+    -> Result<(), RemapError>
+{
+    let string_table = elf_sections_tag.string_table();
+
+    for section in elf_sections_tag.sections().filter(|s| s.is_allocated() && s.size > 0) {
+        let start = section.start_address();
+        let size = section.size as usize;
+        let canonical = match start.checked_add(size - 1) {
+            // the halves are separated by the hole
+            Some(last) => is_canonical(start) && is_canonical(last) &&
+                          (start < 0x0000_8000_0000_0000) == (last < 0x0000_8000_0000_0000),
+            None => false,
+        };
+        if !canonical {
+            return Err(RemapError::SectionSpansNonCanonical {
+                name: string_table.section_name(section),
+                addr: start,
+                size: size,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that all allocated sections start at a page boundary. Empty
+/// sections don't need to be mapped, they are only logged.
+fn check_sections_aligned(elf_sections_tag: &'static ElfSectionsTag)
+    -> Result<(), RemapError>
+{
+    let string_table = elf_sections_tag.string_table();
+
+    for section in elf_sections_tag.sections().filter(|s| s.is_allocated()) {
+        let name = string_table.section_name(section);
+        if section.size == 0 {
+            println!("skipping empty section {} at addr: {:#x}", name, section.addr);
+            continue;
+        }
+        if section.start_address() % PAGE_SIZE != 0 {
+            return Err(RemapError::SectionNotPageAligned {
+                name: name,
+                start: section.start_address(),
+                size: section.size as usize,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that no two allocated sections share a frame. Overlapping sections
+/// point to a broken linker script and would otherwise be mapped twice.
+fn check_sections_disjoint(elf_sections_tag: &'static ElfSectionsTag)
+    -> Result<(), RemapError>
+{
+    let section_frames = |section: &ElfSection| {
+        FrameRange::containing_addresses(section.start_address(), section.end_address())
+    };
+
+    let sections = || elf_sections_tag.sections().filter(|s| s.is_allocated() && s.size > 0);
+    for (i, first) in sections().enumerate() {
+        let first_frames = section_frames(first);
+        for second in sections().skip(i + 1) {
+            let second_frames = section_frames(second);
+            if first_frames.start() < second_frames.end() &&
+               second_frames.start() < first_frames.end() {
+                return Err(RemapError::OverlappingSections(first.start_address(),
+                                                           second.start_address()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the kernel mappings through `session`, which edits the new table.
+fn map_kernel<A>(session: &mut Session,
+                 boot_info: &BootInformation,
+                 elf_sections_tag: &'static ElfSectionsTag,
+                 preserve_identity: &[FrameRange],
+                 allocator: &mut A)
+                 -> Result<(), RemapError>
+    where A: FrameAllocator
+{
+    for section in elf_sections_tag.sections() {
+        if !section.is_allocated() {
+            // section is not loaded to memory
+            continue;
+        }
+        if section.size == 0 {
+            // occupies no page, `check_sections_aligned` already logged it
+            continue;
+        }
+
+        // NOBITS sections like `.bss` are identity mapped like all others:
+        // the bootloader zeroed them in place, and they hold the boot stack
+        // and page tables that are in use right now. Their frames are
+        // reserved together with the rest of the kernel image.
+        println!("mapping section at addr: {:#x}, size: {:#x}",
+            section.addr, section.size);
+
+        let flags = EntryFlags::from_elf_section_flags(section);
+
+        let addresses = StepRange::new(section.addr as usize,
+                                       (section.addr + section.size) as usize,
+                                       PAGE_SIZE);
+        for address in addresses {
+            let frame = Frame::containing_address(address);
+            let page = Page::containing_address(address);
+            try!(session.try_map_to(page, frame, flags, allocator)
+                .map_err(|error| RemapError::from_map_error(error, page)))
+                .ignore_in_session(session);
+        }
+    }
+
+    // Identity map the multiboot info structure read-only. Frames it
+    // shares with the kernel image are already mapped.
+    let multiboot_frames = FrameRange::containing_addresses(
+        boot_info.start_address(), boot_info.end_address());
+    for frame in multiboot_frames.iter() {
+        let page = Page::containing_address(frame.start_address());
+        if session.translate_page(page).is_none() {
+            try!(session.try_map_to(page, frame, NO_EXECUTE, allocator)
+                .map_err(|error| RemapError::from_map_error(error, page)))
+                .ignore_in_session(session);
+        }
+    }
+
+    // Identity map the VGA text buffer.
+    let vga_buffer_page = Page::containing_address(0xb8000);
+    let vga_buffer_frame = Frame::containing_address(0xb8000);
+    try!(session.try_map_to(vga_buffer_page, vga_buffer_frame, WRITABLE, allocator)
+        .map_err(|error| RemapError::from_map_error(error, vga_buffer_page)))
+        .ignore_in_session(session);
+
+    // Identity map the preserved low memory, skipping frames mapped above.
+    for frame in preserve_identity.iter().flat_map(|range| range.iter()) {
+        let page = Page::containing_address(frame.start_address());
+        if session.translate_page(page).is_none() {
+            try!(session.try_map_to(page, frame, WRITABLE, allocator)
+                .map_err(|error| RemapError::from_map_error(error, page)))
+                .ignore_in_session(session);
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hw;
+    use memory::reserved_ranges::MAX_RESERVED_RANGES;
+    use memory::test_support::{small_allocator, active_table, boot_information,
+                               elf_boot_information};
+
+    const WRITABLE_SECTION: u64 = 0x1;
+    const ALLOCATED: u64 = 0x2;
+    const EXECUTABLE: u64 = 0x4;
+
+    #[test]
+    fn kernel_protections_tighten_the_initial_mapping() {
+        let boot_info = elf_boot_information(&[
+            (".text", 0x20_0000, 0x2000, ALLOCATED | EXECUTABLE),
+            (".rodata", 0x20_2000, 0x1000, ALLOCATED),
+            (".data", 0x20_3000, 0x800, ALLOCATED | WRITABLE_SECTION),
+            (".bss", 0x20_4000, 0x2000, ALLOCATED | WRITABLE_SECTION),
+        ]);
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        // everything writable and executable, like the boot loader's mapping
+        let guard_page = Page::containing_address(0x20_4000);
+        for address in (0x20_0000..0x20_6000).step_by(PAGE_SIZE) {
+            let page = Page::containing_address(address);
+            if page.number != guard_page.number {
+                active_table.identity_map(Frame::containing_address(address), WRITABLE,
+                                          &mut allocator)
+                    .flush(&active_table);
+            }
+        }
+
+        // without the guard page the unmapped page is an error
+        let text_page = Page::containing_address(0x20_0000);
+        assert_eq!(apply_kernel_protections(&mut active_table, boot_info, text_page),
+                   Err(MapError::PageNotMapped));
+        let rodata_page = Page::containing_address(0x20_2000);
+        assert_eq!(active_table.get_flags(rodata_page), Some(PRESENT | WRITABLE));
+
+        apply_kernel_protections(&mut active_table, boot_info, guard_page).unwrap();
+        let flags = |address| active_table.get_flags(Page::containing_address(address));
+        assert_eq!(flags(0x20_1000), Some(PRESENT));
+        assert_eq!(flags(0x20_2000), Some(PRESENT | NO_EXECUTE));
+        assert_eq!(flags(0x20_3000), Some(PRESENT | WRITABLE | NO_EXECUTE));
+        assert_eq!(flags(0x20_5000), Some(PRESENT | WRITABLE | NO_EXECUTE));
+    }
+
+    #[test]
+    fn bss_is_mapped_writable_to_zeroed_frames_and_empty_sections_are_skipped() {
+        use memory::test_support::physical_pointer;
+
+        let boot_info = elf_boot_information(&[
+            (".text", 0x20_0000, 0x1000, ALLOCATED | EXECUTABLE),
+            (".empty", 0x20_1000, 0, ALLOCATED),
+            (".bss", 0x20_2000, 0x2000, ALLOCATED | WRITABLE_SECTION),
+        ]);
+        // the multiboot information lives on the host heap
+        hw::with_mock(|hw| hw.physical_address_bits.set(52));
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let mut temporary_page = TemporaryPage::new(TEMPORARY_PAGE, &active_table,
+                                                    &mut allocator);
+        let frame = allocator.allocate_frame().unwrap();
+        let mut new_table = InactivePageTable::new(frame, &mut active_table,
+                                                   &mut temporary_page);
+
+        let elf_sections_tag = boot_info.elf_sections_tag().unwrap();
+        active_table.with(&mut new_table, &mut temporary_page, |session| {
+            map_kernel(session, boot_info, elf_sections_tag, &[], &mut allocator).unwrap();
+
+            let empty_page = Page::containing_address(0x20_1000);
+            assert_eq!(session.translate_page(empty_page), None);
+            for &address in &[0x20_2000, 0x20_3000] {
+                let page = Page::containing_address(address);
+                assert_eq!(session.translate_page(page), Some(Frame::containing_address(address)));
+                assert_eq!(session.get_flags(page), Some(PRESENT | WRITABLE | NO_EXECUTE));
+                let contents = unsafe { &*physical_pointer::<[u8; PAGE_SIZE]>(address) };
+                assert!(contents.iter().all(|&byte| byte == 0));
+            }
+        });
+    }
+
+    #[test]
+    fn preserved_ranges_are_identity_mapped_in_the_new_table() {
+        // covers the initial P4 table, which becomes the guard page
+        let boot_info = elf_boot_information(&[(".text", 0x10_0000, 0x8000, ALLOCATED)]);
+        hw::with_mock(|hw| hw.physical_address_bits.set(52));
+        let mut allocator = small_allocator();
+        active_table(&mut allocator);
+        // the BIOS data area and an AP trampoline
+        let preserved = [FrameRange::containing_addresses(0, 0x1000),
+                         FrameRange::containing_addresses(0x8000, 0xa000)];
+
+        let active_table = try_remap_the_kernel(&mut allocator, boot_info, &preserved).unwrap();
+
+        for frame in preserved.iter().flat_map(|range| range.iter()) {
+            let address = frame.start_address();
+            assert_eq!(active_table.translate(address + 0x10), Some(address + 0x10));
+            let flags = active_table.get_flags(Page::containing_address(address)).unwrap();
+            assert!(flags.contains(PRESENT | WRITABLE));
+            assert!(!flags.contains(NO_EXECUTE));
+        }
+        assert_eq!(active_table.translate(0x1000), None);
+    }
+
+    #[test]
+    fn missing_elf_sections_tag_is_reported() {
+        let mut allocator = small_allocator();
+        assert_eq!(try_remap_the_kernel(&mut allocator, boot_information(&[]), &[]).err(),
+                   Some(RemapError::MissingElfSectionsTag));
+    }
+
+    #[test]
+    fn misaligned_section_is_reported_with_its_name() {
+        let boot_info = elf_boot_information(&[
+            (".text", 0x10_0000, 0x2000, ALLOCATED),
+            (".rodata", 0x10_2800, 0x1000, ALLOCATED),
+        ]);
+        let error = check_sections_aligned(boot_info.elf_sections_tag().unwrap()).unwrap_err();
+        assert_eq!(error, RemapError::SectionNotPageAligned {
+            name: ".rodata",
+            start: 0x10_2800,
+            size: 0x1000,
+        });
+        assert!(format!("{:?}", error).contains(".rodata"));
+    }
+
+    #[test]
+    fn empty_sections_need_no_alignment() {
+        let boot_info = elf_boot_information(&[(".empty", 0x10_2800, 0, ALLOCATED)]);
+        assert_eq!(check_sections_aligned(boot_info.elf_sections_tag().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn overlapping_sections_are_reported() {
+        let boot_info = elf_boot_information(&[
+            (".text", 0x10_0000, 0x2000, ALLOCATED),
+            (".data", 0x10_3000, 0x1000, ALLOCATED),
+            // shares its first frame with `.text`
+            (".rodata", 0x10_1000, 0x1000, ALLOCATED),
+        ]);
+        assert_eq!(check_sections_disjoint(boot_info.elf_sections_tag().unwrap()),
+                   Err(RemapError::OverlappingSections(0x10_0000, 0x10_1000)));
+    }
+
+    #[test]
+    fn adjacent_sections_do_not_overlap() {
+        let boot_info = elf_boot_information(&[
+            (".text", 0x10_0000, 0x2000, ALLOCATED),
+            (".rodata", 0x10_2000, 0x1000, ALLOCATED),
+        ]);
+        assert_eq!(check_sections_disjoint(boot_info.elf_sections_tag().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn too_many_preserved_ranges_are_reported() {
+        let mut allocator = small_allocator();
+        let boot_info = elf_boot_information(&[(".text", 0x10_0000, 0x2000, ALLOCATED)]);
+        // disjoint ranges, so they can't be merged
+        let ranges: Vec<FrameRange> = (0..MAX_RESERVED_RANGES)
+            .map(|i| FrameRange { start: 0x200 + 2 * i, end: 0x201 + 2 * i })
+            .collect();
+        assert_eq!(try_remap_the_kernel(&mut allocator, boot_info, &ranges).err(),
+                   Some(RemapError::TooManyReservedRanges));
+    }
+
+    #[test]
+    fn running_out_of_frames_is_reported() {
+        let mut allocator = small_allocator();
+        active_table(&mut allocator);
+        let boot_info = elf_boot_information(&[(".text", 0x10_0000, 0x2000, ALLOCATED)]);
+        while allocator.allocate_frame().is_some() {}
+
+        assert_eq!(try_remap_the_kernel(&mut allocator, boot_info, &[]).err(),
+                   Some(RemapError::FrameAllocationFailed));
+    }
+
+    #[test]
+    fn map_errors_name_the_page() {
+        let page = Page::containing_address(0xb8000);
+        assert_eq!(RemapError::from_map_error(MapError::PageAlreadyMapped, page),
+                   RemapError::PageAlreadyMapped(0xb8000));
+        assert_eq!(RemapError::from_map_error(MapError::FrameAllocationFailed, page),
+                   RemapError::FrameAllocationFailed);
+    }
+
+    #[test]
+    fn sections_in_one_half_are_canonical() {
+        let boot_info = elf_boot_information(&[
+            (".text", 0x10_0000, 0x2000, ALLOCATED),
+            (".high", 0xffff_8000_0000_0000, 0x1000, ALLOCATED),
+            (".top", 0xffff_ffff_ffff_f000, 0x1000, ALLOCATED),
+        ]);
+        assert_eq!(check_sections_canonical(boot_info.elf_sections_tag().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn section_straddling_the_hole_is_rejected() {
+        let boot_info = elf_boot_information(&[
+            (".text", 0x10_0000, 0x2000, ALLOCATED),
+            (".bad", 0x7fff_ffff_f000, 0x2000, ALLOCATED),
+        ]);
+        assert_eq!(check_sections_canonical(boot_info.elf_sections_tag().unwrap()),
+                   Err(RemapError::SectionSpansNonCanonical {
+                       name: ".bad",
+                       addr: 0x7fff_ffff_f000,
+                       size: 0x2000,
+                   }));
+    }
+
+    #[test]
+    fn unallocated_sections_are_not_checked() {
+        let boot_info = elf_boot_information(&[(".debug", 0x7fff_ffff_f000, 0x2000, 0)]);
+        assert_eq!(check_sections_canonical(boot_info.elf_sections_tag().unwrap()), Ok(()));
+    }
+}
@@ -0,0 +1,245 @@
+use super::{Page, ActivePageTable, Mapper, virtual_pointer};
+use super::entry::*;
+use memory::{Frame, FrameAllocator};
+use core::ptr;
+
+
+/// The page mapped and unmapped again by the self test.
+pub const TEST_PAGE: Page = Page { number: 0xdeadbeef };
+
+const SENTINEL: u64 = 0x5ca1_ab1e_c0ff_ee00;
+
+/// The step of the paging self test that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// The test page is already in use.
+    PageInUse,
+    /// No frame could be allocated for the test page.
+    OutOfFrames,
+    /// The sentinel written to the test page could not be read back.
+    SentinelMismatch,
+    /// The test page does not translate to the frame it was mapped to.
+    TranslateMismatch,
+    /// The test page is still writable after reprotecting it read-only.
+    ReprotectFailed,
+    /// The test page still translates after unmapping it.
+    StillMapped,
+}
+
+/// Smoke tests the mapping code: maps a fresh page, writes a sentinel to it,
+/// translates it back, reprotects it read-only, unmaps it, and returns its
+/// frame to the allocator. Meant to be run during early boot in debug builds.
+pub fn self_test<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+    -> Result<(), SelfTestError>
+    where A: FrameAllocator
+{
+    run(active_table, allocator)
+}
+
+/// The page table operations the self test goes through. The kernel runs it
+/// on the active table; host tests run it on a model table, since the active
+/// one is only reachable through the recursive mapping.
+trait TestTable {
+    fn translate_page(&self, page: Page) -> Option<Frame>;
+    fn get_flags(&self, page: Page) -> Option<EntryFlags>;
+    fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator;
+    fn update_flags(&mut self, page: Page, flags: EntryFlags);
+    fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameAllocator;
+    /// The address the start of the mapped `page` can be accessed through.
+    fn page_address(&mut self, page: Page) -> *mut u64;
+}
+
+impl TestTable for ActivePageTable {
+    fn translate_page(&self, page: Page) -> Option<Frame> {
+        Mapper::translate_page(self, page)
+    }
+
+    fn get_flags(&self, page: Page) -> Option<EntryFlags> {
+        Mapper::get_flags(self, page)
+    }
+
+    fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        Mapper::map_to(self, page, frame, flags, allocator).flush(self);
+    }
+
+    fn update_flags(&mut self, page: Page, flags: EntryFlags) {
+        Mapper::update_flags(self, page, flags).flush(self);
+    }
+
+    fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameAllocator
+    {
+        let (frame, flush) = Mapper::unmap(self, page, allocator).expect("page is not mapped");
+        flush.flush(self);
+        frame
+    }
+
+    fn page_address(&mut self, page: Page) -> *mut u64 {
+        virtual_pointer(page.start_address())
+    }
+}
+
+fn run<T, A>(table: &mut T, allocator: &mut A) -> Result<(), SelfTestError>
+    where T: TestTable, A: FrameAllocator
+{
+    if table.translate_page(TEST_PAGE).is_some() {
+        return Err(SelfTestError::PageInUse);
+    }
+    let frame = try!(allocator.allocate_frame().ok_or(SelfTestError::OutOfFrames));
+
+    table.map_to(TEST_PAGE, frame.clone(), WRITABLE | NO_EXECUTE, allocator);
+    let result = check_mapped_page(table, &frame);
+
+    table.unmap(TEST_PAGE, allocator);
+    if table.translate_page(TEST_PAGE).is_some() {
+        // the frame is still reachable through the test page, so keep it
+        return Err(SelfTestError::StillMapped);
+    }
+    allocator.deallocate_frame(frame);
+
+    result
+}
+
+fn check_mapped_page<T>(table: &mut T, frame: &Frame) -> Result<(), SelfTestError>
+    where T: TestTable
+{
+    let address = table.page_address(TEST_PAGE);
+    unsafe {
+        ptr::write_volatile(address, SENTINEL);
+        if ptr::read_volatile(address) != SENTINEL {
+            return Err(SelfTestError::SentinelMismatch);
+        }
+    }
+
+    if table.translate_page(TEST_PAGE).as_ref() != Some(frame) {
+        return Err(SelfTestError::TranslateMismatch);
+    }
+
+    table.update_flags(TEST_PAGE, NO_EXECUTE);
+    match table.get_flags(TEST_PAGE) {
+        Some(flags) if !flags.contains(WRITABLE) => Ok(()),
+        _ => Err(SelfTestError::ReprotectFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hw;
+    use memory::test_support::small_allocator;
+
+    /// A single level table with room for the test page, flushing through
+    /// the mock like the active table does.
+    struct ModelTable {
+        entries: Vec<(Page, Frame, EntryFlags)>,
+        memory: Vec<u64>,
+        ignores_update_flags: bool,
+        ignores_unmap: bool,
+    }
+
+    impl ModelTable {
+        fn new() -> ModelTable {
+            ModelTable {
+                entries: Vec::new(),
+                memory: vec![0; 512],
+                ignores_update_flags: false,
+                ignores_unmap: false,
+            }
+        }
+
+        fn entry(&self, page: Page) -> Option<&(Page, Frame, EntryFlags)> {
+            self.entries.iter().find(|entry| entry.0.number == page.number)
+        }
+    }
+
+    impl TestTable for ModelTable {
+        fn translate_page(&self, page: Page) -> Option<Frame> {
+            self.entry(page).map(|entry| entry.1.clone())
+        }
+
+        fn get_flags(&self, page: Page) -> Option<EntryFlags> {
+            self.entry(page).map(|entry| entry.2)
+        }
+
+        fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, _allocator: &mut A)
+            where A: FrameAllocator
+        {
+            assert!(self.entry(page).is_none());
+            self.entries.push((page, frame, flags | PRESENT));
+            hw::flush(page.start_address());
+        }
+
+        fn update_flags(&mut self, page: Page, flags: EntryFlags) {
+            if !self.ignores_update_flags {
+                let entry = self.entries.iter_mut().find(|entry| entry.0.number == page.number).unwrap();
+                entry.2 = flags | PRESENT;
+            }
+            hw::flush(page.start_address());
+        }
+
+        fn unmap<A>(&mut self, page: Page, _allocator: &mut A) -> Frame
+            where A: FrameAllocator
+        {
+            let frame = self.translate_page(page).unwrap();
+            if !self.ignores_unmap {
+                self.entries.retain(|entry| entry.0.number != page.number);
+            }
+            hw::flush(page.start_address());
+            frame
+        }
+
+        fn page_address(&mut self, page: Page) -> *mut u64 {
+            assert!(self.entry(page).is_some());
+            self.memory.as_mut_ptr()
+        }
+    }
+
+    #[test]
+    fn passes_and_returns_the_frame() {
+        let mut table = ModelTable::new();
+        let mut allocator = small_allocator();
+
+        assert_eq!(run(&mut table, &mut allocator), Ok(()));
+        assert!(table.entries.is_empty());
+        assert_eq!(table.memory[0], SENTINEL);
+        assert_eq!(allocator.used_frames(), 0);
+        // map, reprotect and unmap each flush the test page
+        hw::with_mock(|hw| assert_eq!(*hw.flushed.borrow(), vec![TEST_PAGE.start_address(); 3]));
+    }
+
+    #[test]
+    fn a_mapped_test_page_is_reported() {
+        let mut table = ModelTable::new();
+        table.entries.push((TEST_PAGE, Frame { number: 0x200 }, PRESENT));
+        let mut allocator = small_allocator();
+
+        assert_eq!(run(&mut table, &mut allocator), Err(SelfTestError::PageInUse));
+        assert_eq!(allocator.used_frames(), 0);
+    }
+
+    #[test]
+    fn a_reprotect_that_does_not_stick_is_reported() {
+        let mut table = ModelTable::new();
+        table.ignores_update_flags = true;
+        let mut allocator = small_allocator();
+
+        assert_eq!(run(&mut table, &mut allocator), Err(SelfTestError::ReprotectFailed));
+        // the page was still unmapped and its frame freed
+        assert!(table.entries.is_empty());
+        assert_eq!(allocator.used_frames(), 0);
+    }
+
+    #[test]
+    fn a_page_still_mapped_keeps_its_frame() {
+        let mut table = ModelTable::new();
+        table.ignores_unmap = true;
+        let mut allocator = small_allocator();
+
+        assert_eq!(run(&mut table, &mut allocator), Err(SelfTestError::StillMapped));
+        assert_eq!(allocator.used_frames(), 1);
+    }
+}
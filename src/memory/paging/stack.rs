@@ -0,0 +1,56 @@
+use super::{Page, ActivePageTable, VirtualAddress};
+use super::entry::*;
+use super::region::{VirtualRegionManager, RegionPurpose, RegionError};
+use memory::{PAGE_SIZE, FrameAllocator};
+
+
+/// A kernel stack allocated by `alloc_stack`. The page below `bottom` is its
+/// guard page and stays unmapped.
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtualAddress,
+    bottom: VirtualAddress,
+}
+
+impl Stack {
+    /// The address the stack pointer starts at, the stack grows down from it.
+    pub fn top(&self) -> VirtualAddress {
+        self.top
+    }
+
+    /// The lowest address of the stack.
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    /// The unmapped page below the stack, e.g. to register it in
+    /// `GuardPages`.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - PAGE_SIZE)
+    }
+}
+
+/// Allocates a virtual region for a stack of `pages` pages with a guard page
+/// below it and maps the stack pages to newly allocated frames.
+pub fn alloc_stack<A>(pages: usize,
+                      regions: &mut VirtualRegionManager,
+                      active_table: &mut ActivePageTable,
+                      allocator: &mut A)
+                      -> Result<Stack, RegionError>
+    where A: FrameAllocator
+{
+    assert!(pages > 0, "cannot allocate an empty stack");
+    let region_start = try!(regions.alloc_region((pages + 1) * PAGE_SIZE,
+                                                 RegionPurpose::Stack));
+
+    // the first page of the region is the guard page
+    let bottom = region_start + PAGE_SIZE;
+    for i in 0..pages {
+        let page = Page::containing_address(bottom + i * PAGE_SIZE);
+        active_table.map(page, WRITABLE | NO_EXECUTE | GLOBAL, allocator).flush(active_table);
+    }
+    Ok(Stack {
+        top: bottom + pages * PAGE_SIZE,
+        bottom: bottom,
+    })
+}
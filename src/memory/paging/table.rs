@@ -1,11 +1,10 @@
 use memory::paging::entry::*;
-use memory::paging::ENTRY_COUNT;
+use memory::paging::{ENTRY_COUNT, RECURSIVE_MAP_BASE};
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 use memory::FrameAllocator;
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
-//pub const P4: *mut Table<Level4> = 0o177777_777_777_777_0000 as *mut _;
+pub const P4: *mut Table<Level4> = RECURSIVE_MAP_BASE as *mut _;
 
 pub trait TableLevel {}
 
@@ -46,9 +45,16 @@ impl<L> Table<L> where L: TableLevel {
             entry.set_unused();
         }
     }
+
+    /// Returns true if all entries are unused. Entries that aren't present
+    /// but e.g. mark a swapped out page count as used.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L> where L: HierarchicalLevel {
+    #[cfg(not(test))]
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
@@ -59,6 +65,21 @@ impl<L> Table<L> where L: HierarchicalLevel {
         }
     }
 
+    /// The host tests have no recursive mapping to follow, the table is
+    /// looked up by its frame in the model physical memory instead. That is
+    /// the same table as long as every P4 table maps itself at
+    /// `RECURSIVE_INDEX`.
+    #[cfg(test)]
+    fn next_table_address(&self, index: usize) -> Option<usize> {
+        let entry_flags = self[index].flags();
+        if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+            let frame = self[index].pointed_frame().unwrap();
+            Some(::memory::test_support::physical_pointer::<u8>(frame.start_address()) as usize)
+        } else {
+            None
+        }
+    }
+
     pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
         self.next_table_address(index)
             .map(|address| unsafe { &*(address as *const _) })
@@ -74,15 +95,39 @@ impl<L> Table<L> where L: HierarchicalLevel {
                             allocator: &mut A)
                             -> &mut Table<L::NextLevel>
         where A: FrameAllocator
+    {
+        self.try_next_table_create(index, allocator).expect("no frames available")
+    }
+
+    /// Like `next_table_create`, but returns `None` if no frame is available
+    /// for the new table.
+    pub fn try_next_table_create<A>(&mut self,
+                                index: usize,
+                                allocator: &mut A)
+                                -> Option<&mut Table<L::NextLevel>>
+        where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(HUGE_PAGE),
                     "mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("no frames available");
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return None,
+            };
+            // a demand paged entry covers the whole new table
+            let demand_flags = self.entries[index].demand_flags();
             self.entries[index].set(frame, PRESENT | WRITABLE);
-            self.next_table_mut(index).unwrap().zero();
+            let table = self.next_table_mut(index).unwrap();
+            match demand_flags {
+                Some(flags) => {
+                    for entry in table.entries.iter_mut() {
+                        entry.set_demand_paged(flags);
+                    }
+                }
+                None => table.zero(),
+            }
         }
-        self.next_table_mut(index).unwrap()
+        self.next_table_mut(index)
     }
 }
 
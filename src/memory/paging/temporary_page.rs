@@ -1,41 +1,162 @@
-use super::{Page, ActivePageTable, VirtualAddress};
-use super::table::{Table, Level1};
+use super::{Page, ActivePageTable, MapError, Mapper, VirtualAddress, ENTRY_COUNT, virtual_pointer};
+use super::table::{Table, TableLevel, Level1};
 use memory::{Frame, FrameAllocator};
 
 
+/// The maximum number of frames a `TemporaryPage` can map at the same time.
+pub const MAX_TEMPORARY_SLOTS: usize = 8;
+
 pub struct TemporaryPage {
-    page: Page,
+    base: Page,
+    slots: usize,
+    in_use: [bool; MAX_TEMPORARY_SLOTS],
     allocator: TinyAllocator,
 }
 
 impl TemporaryPage {
 
-    pub fn new<A>(page: Page, allocator: &mut A) -> TemporaryPage
+    pub fn new<A>(page: Page, mapper: &Mapper, allocator: &mut A) -> TemporaryPage
+        where A: FrameAllocator
+    {
+        TemporaryPage::with_slots(page, 1, mapper, allocator)
+    }
+
+    /// Creates a temporary page with `count` slots, backed by the consecutive
+    /// pages starting at `base`, so that up to `count` frames can be mapped
+    /// at the same time. Panics if any of the pages is mapped in `mapper`,
+    /// since mapping a slot would silently replace that mapping.
+    pub fn with_slots<A>(base: Page, count: usize, mapper: &Mapper, allocator: &mut A)
+        -> TemporaryPage
+        where A: FrameAllocator
+    {
+        assert!(count > 0 && count <= MAX_TEMPORARY_SLOTS,
+                "a temporary page needs between 1 and {} slots", MAX_TEMPORARY_SLOTS);
+        // all slots share one P1 table, so the tiny allocator always suffices
+        assert!(base.p1_index() + count <= ENTRY_COUNT,
+                "temporary page slots must not cross a P1 table boundary");
+        for slot in 0..count {
+            let page = Page { number: base.number + slot };
+            assert!(mapper.translate_page(page).is_none(),
+                    "temporary page at {:#x} collides with an existing mapping",
+                    page.start_address());
+        }
+
+        TemporaryPage {
+            base: base,
+            slots: count,
+            in_use: [false; MAX_TEMPORARY_SLOTS],
+            allocator: TinyAllocator::new(allocator),
+        }
+    }
+
+    /// Creates a temporary page with one slot without checking that its page
+    /// is unmapped, since host tests have no recursive mapping to check.
+    #[cfg(test)]
+    pub fn unchecked<A>(page: Page, allocator: &mut A) -> TemporaryPage
         where A: FrameAllocator
     {
         TemporaryPage {
-            page: page,
+            base: page,
+            slots: 1,
+            in_use: [false; MAX_TEMPORARY_SLOTS],
             allocator: TinyAllocator::new(allocator),
         }
     }
 
+    /// Returns the first page of the temporary page.
+    pub fn page(&self) -> Page {
+        self.base
+    }
+
+    /// Checks that no slot of the temporary page is mapped in `mapper`, so
+    /// that an operation using it doesn't overwrite a live mapping.
+    pub fn check_free(&self, mapper: &Mapper) -> Result<(), MapError> {
+        for slot in 0..self.slots {
+            if self.in_use[slot] || mapper.translate_page(self.slot_page(slot)).is_some() {
+                return Err(MapError::TemporaryPageInUse);
+            }
+        }
+        Ok(())
+    }
+
+    fn slot_page(&self, slot: usize) -> Page {
+        Page { number: self.base.number + slot }
+    }
 
-    /// Maps the temporary page to the given frame in the active table.
-    /// Returns the start address of the temporary page.
+    /// Maps the given frame to a free slot of the temporary page in the active
+    /// table. Returns the start address of the slot.
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable)
         -> VirtualAddress
     {
         use super::entry::WRITABLE;
 
-        assert!(active_table.translate_page(self.page).is_none(),
+        let slot = self.in_use[..self.slots].iter().position(|&used| !used)
+            .expect("all temporary page slots are in use");
+        let page = self.slot_page(slot);
+
+        assert!(active_table.translate_page(page).is_none(),
                 "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
-        self.page.start_address()
+        active_table.map_to(page, frame, WRITABLE, &mut self.allocator)
+            .flush(active_table);
+        self.in_use[slot] = true;
+        page.start_address()
+    }
+
+    /// Like `map`, but returns a guard that unmaps the slot when it's dropped,
+    /// also on an early return or a panic. The active table stays accessible
+    /// through the guard.
+    pub fn map_scoped<'a>(&'a mut self,
+                          frame: Frame,
+                          active_table: &'a mut ActivePageTable)
+                          -> TemporaryMapping<'a>
+    {
+        let address = self.map(frame, active_table);
+        TemporaryMapping {
+            temporary_page: self,
+            active_table: active_table,
+            address: address,
+        }
+    }
+
+    /// Unmaps the slot that was mapped at `address` by `map`.
+    pub fn unmap_address(&mut self, address: VirtualAddress,
+                         active_table: &mut ActivePageTable)
+    {
+        let page = Page::containing_address(address);
+        assert!(page.number >= self.base.number &&
+                page.number < self.base.number + self.slots,
+                "address {:#x} is not part of the temporary page", address);
+
+        let slot = page.number - self.base.number;
+        assert!(self.in_use[slot], "temporary page slot {} is not mapped", slot);
+        // the frame belongs to the caller of `map`
+        active_table.unmap(page, &mut self.allocator)
+            .expect("failed to unmap the temporary page").1.flush(active_table);
+        self.in_use[slot] = false;
     }
 
-    /// Unmaps the temporary page in the active table.
+    /// Unmaps all slots of the temporary page in the active table.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        for slot in 0..self.slots {
+            if self.in_use[slot] {
+                let page = self.slot_page(slot);
+                active_table.unmap(page, &mut self.allocator)
+                    .expect("failed to unmap the temporary page").1.flush(active_table);
+                self.in_use[slot] = false;
+            }
+        }
+    }
+
+    /// Returns the frames the temporary page still holds for page tables to
+    /// `allocator`. All slots must be unmapped.
+    pub fn release<A>(mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        assert!(self.in_use.iter().all(|&used| !used),
+                "temporary page is still mapped");
+        while let Some(frame) = self.allocator.allocate_frame() {
+            allocator.deallocate_frame(frame);
+        }
     }
 
     /// Maps the temporary page to the given page table frame in the active table.
@@ -45,12 +166,42 @@ impl TemporaryPage {
                        active_table: &mut ActivePageTable)
                        -> &mut Table<Level1> {
         unsafe { 
-            &mut *(self.map(frame, active_table) as *mut Table<Level1>) 
+            &mut *virtual_pointer(self.map(frame, active_table))
         }
     }
 }
 
 
+/// A slot of a `TemporaryPage` that is unmapped when this is dropped.
+pub struct TemporaryMapping<'a> {
+    temporary_page: &'a mut TemporaryPage,
+    active_table: &'a mut ActivePageTable,
+    address: VirtualAddress,
+}
+
+impl<'a> TemporaryMapping<'a> {
+    /// Returns the start address of the mapped slot.
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    /// Interprets the mapped frame as a page table.
+    pub fn table<L: TableLevel>(&mut self) -> &mut Table<L> {
+        unsafe { &mut *virtual_pointer(self.address) }
+    }
+
+    pub fn active_table(&mut self) -> &mut ActivePageTable {
+        self.active_table
+    }
+}
+
+impl<'a> Drop for TemporaryMapping<'a> {
+    fn drop(&mut self) {
+        self.temporary_page.unmap_address(self.address, self.active_table);
+    }
+}
+
+
 struct TinyAllocator([Option<Frame>; 3]);
 
 impl TinyAllocator {
@@ -83,3 +234,61 @@ impl FrameAllocator for TinyAllocator {
         panic!("Tiny allocator can hold only 3 frames.");
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::{small_allocator, active_table};
+    use std::panic::{self, AssertUnwindSafe};
+    use super::super::entry::WRITABLE;
+
+    #[test]
+    fn a_slot_still_in_use_is_reported() {
+        let mut allocator = small_allocator();
+        let mut temporary_page = TemporaryPage::unchecked(Page { number: 0xcafec }, &mut allocator);
+        // as if an earlier `map` wasn't followed by `unmap`; the mapper isn't
+        // read once a slot is known to be in use
+        temporary_page.in_use[0] = true;
+        let mapper = unsafe { Mapper::new() };
+
+        assert_eq!(temporary_page.check_free(&mapper), Err(MapError::TemporaryPageInUse));
+    }
+
+    #[test]
+    fn scoped_mappings_are_unmapped_when_the_guard_drops() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let page = Page { number: 0xcafec };
+        let mut temporary_page = TemporaryPage::new(page, &active_table, &mut allocator);
+        let frame = Frame { number: 0x1234 };
+
+        {
+            let mut mapping = temporary_page.map_scoped(frame.clone(), &mut active_table);
+            assert_eq!(mapping.active_table().translate_page(page), Some(frame.clone()));
+        }
+        assert_eq!(active_table.translate_page(page), None);
+        assert_eq!(temporary_page.check_free(&active_table), Ok(()));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _mapping = temporary_page.map_scoped(frame.clone(), &mut active_table);
+            panic!("early exit while the temporary page is mapped");
+        }));
+        assert!(result.is_err());
+        assert_eq!(active_table.translate_page(page), None);
+        assert_eq!(temporary_page.check_free(&active_table), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "temporary page at 0xcafec2000 collides with an existing mapping")]
+    fn temporary_pages_over_a_mapped_page_are_rejected() {
+        let mut allocator = small_allocator();
+        let mut active_table = active_table(&mut allocator);
+        let mapped = Page { number: 0xcafec2 };
+        active_table.map_to(mapped, Frame { number: 0x1234 }, WRITABLE, &mut allocator)
+            .flush(&active_table);
+
+        // the third slot is mapped already
+        TemporaryPage::with_slots(Page { number: 0xcafec0 }, 4, &active_table, &mut allocator);
+    }
+}
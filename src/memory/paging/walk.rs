@@ -0,0 +1,200 @@
+use super::{VirtualAddress, PhysicalAddress, ENTRY_COUNT, RECURSIVE_INDEX, KERNEL_P4_INDEX};
+use super::entry::*;
+use super::table::{Table, Level4};
+use memory::PAGE_SIZE;
+
+
+/// The sizes a page can be mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> usize {
+        match *self {
+            PageSize::Size4KiB => PAGE_SIZE,
+            PageSize::Size2MiB => ENTRY_COUNT * PAGE_SIZE,
+            PageSize::Size1GiB => ENTRY_COUNT * ENTRY_COUNT * PAGE_SIZE,
+        }
+    }
+}
+
+/// A single present page table entry mapping a page of the given size.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub start: VirtualAddress,
+    pub frame: PhysicalAddress,
+    pub size: PageSize,
+    pub flags: EntryFlags,
+}
+
+/// Iterates over all mappings of a page table in address order, skipping the
+/// recursive entry. The walk only keeps the current index of every level, so
+/// its stack usage doesn't depend on how much memory is mapped.
+pub struct MappingIter<'a> {
+    p4: &'a Table<Level4>,
+    indices: [usize; 4],
+}
+
+impl<'a> MappingIter<'a> {
+    pub fn new(p4: &'a Table<Level4>) -> MappingIter<'a> {
+        MappingIter {
+            p4: p4,
+            indices: [0; 4],
+        }
+    }
+
+    /// Moves on to the next entry at the given level, where 0 is the P4 level,
+    /// and restarts all lower levels.
+    fn advance(&mut self, level: usize) {
+        self.indices[level] += 1;
+        for index in self.indices[level + 1..].iter_mut() {
+            *index = 0;
+        }
+    }
+
+    fn mapping(&self, entry: &Entry, size: PageSize) -> Mapping {
+        let mut start = (self.indices[0] << 39) | (self.indices[1] << 30) |
+                        (self.indices[2] << 21) | (self.indices[3] << 12);
+        if self.indices[0] >= KERNEL_P4_INDEX {
+            // sign extension
+            start |= 0xffff_0000_0000_0000;
+        }
+
+        Mapping {
+            start: start,
+            frame: entry.pointed_frame().unwrap().start_address(),
+            size: size,
+            flags: entry.flags(),
+        }
+    }
+}
+
+impl<'a> Iterator for MappingIter<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let p4 = self.p4;
+
+        loop {
+            let (p4_index, p3_index) = (self.indices[0], self.indices[1]);
+            let (p2_index, p1_index) = (self.indices[2], self.indices[3]);
+            if p4_index == ENTRY_COUNT {
+                return None;
+            }
+
+            let p3 = match p4.next_table(p4_index) {
+                Some(p3) if p4_index != RECURSIVE_INDEX && p3_index < ENTRY_COUNT => p3,
+                _ => {
+                    self.advance(0);
+                    continue;
+                }
+            };
+            let p3_entry = &p3[p3_index];
+            if !p3_entry.flags().contains(PRESENT) {
+                self.advance(1);
+                continue;
+            }
+            if p3_entry.flags().contains(HUGE_PAGE) {
+                let mapping = self.mapping(p3_entry, PageSize::Size1GiB);
+                self.advance(1);
+                return Some(mapping);
+            }
+
+            let p2 = p3.next_table(p3_index).unwrap();
+            if p2_index == ENTRY_COUNT {
+                self.advance(1);
+                continue;
+            }
+            let p2_entry = &p2[p2_index];
+            if !p2_entry.flags().contains(PRESENT) {
+                self.advance(2);
+                continue;
+            }
+            if p2_entry.flags().contains(HUGE_PAGE) {
+                let mapping = self.mapping(p2_entry, PageSize::Size2MiB);
+                self.advance(2);
+                return Some(mapping);
+            }
+
+            let p1 = p2.next_table(p2_index).unwrap();
+            if p1_index == ENTRY_COUNT {
+                self.advance(2);
+                continue;
+            }
+            let p1_entry = &p1[p1_index];
+            if p1_entry.flags().contains(PRESENT) {
+                let mapping = self.mapping(p1_entry, PageSize::Size4KiB);
+                self.advance(3);
+                return Some(mapping);
+            }
+            self.advance(3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::table::{TableLevel, Level3, Level2, Level1};
+    use memory::Frame;
+    use memory::test_support::physical_pointer;
+    use std::thread;
+
+    fn table<L: TableLevel>(frame: &Frame) -> &'static mut Table<L> {
+        unsafe { &mut *physical_pointer(frame.start_address()) }
+    }
+
+    /// Fills every entry of every level. All P4 entries point to the same P3
+    /// table, and only the last entry of the P3 and P2 tables points to a
+    /// lower table, the others map huge pages, so the walk stays short.
+    fn populate_all_levels() -> &'static Table<Level4> {
+        let frames: Vec<Frame> = (0x200..0x205).map(Frame::from_number).collect();
+        let data = &frames[4];
+        let p4 = table::<Level4>(&frames[0]);
+        for index in 0..ENTRY_COUNT {
+            p4[index].set(frames[1].clone(), PRESENT | WRITABLE);
+        }
+        let p3 = table::<Level3>(&frames[1]);
+        for index in 0..ENTRY_COUNT - 1 {
+            p3[index].set(data.clone(), PRESENT | HUGE_PAGE);
+        }
+        p3[ENTRY_COUNT - 1].set(frames[2].clone(), PRESENT | WRITABLE);
+        let p2 = table::<Level2>(&frames[2]);
+        for index in 0..ENTRY_COUNT - 1 {
+            p2[index].set(data.clone(), PRESENT | HUGE_PAGE);
+        }
+        p2[ENTRY_COUNT - 1].set(frames[3].clone(), PRESENT | WRITABLE);
+        let p1 = table::<Level1>(&frames[3]);
+        for index in 0..ENTRY_COUNT {
+            p1[index].set(data.clone(), PRESENT);
+        }
+        p4
+    }
+
+    #[test]
+    fn walking_fully_populated_tables_needs_little_stack() {
+        // the frames of the model memory are per thread, so the whole test
+        // runs on the small stack
+        let walk = thread::Builder::new().stack_size(64 * 1024).spawn(|| {
+            let mut counts = [0; 3];
+            for mapping in MappingIter::new(populate_all_levels()) {
+                counts[match mapping.size {
+                    PageSize::Size4KiB => 0,
+                    PageSize::Size2MiB => 1,
+                    PageSize::Size1GiB => 2,
+                }] += 1;
+            }
+            counts
+        }).unwrap();
+
+        // every P4 entry but the recursive one
+        let p4_entries = ENTRY_COUNT - 1;
+        assert_eq!(walk.join().unwrap(), [p4_entries * ENTRY_COUNT,
+                                          p4_entries * (ENTRY_COUNT - 1),
+                                          p4_entries * (ENTRY_COUNT - 1)]);
+    }
+}
@@ -0,0 +1,130 @@
+use memory::{Frame, FrameAllocator};
+
+/// The number of frames a `PoolAllocator` can hold.
+pub const POOL_CAPACITY: usize = 32;
+
+/// Hands out frames from a fixed pool that is filled from a parent allocator
+/// up front. Allocating never touches the parent, so this can be used where
+/// the parent can't, e.g. in interrupt handlers. An empty pool returns `None`.
+pub struct PoolAllocator {
+    frames: [usize; POOL_CAPACITY],
+    len: usize,
+}
+
+impl PoolAllocator {
+    /// Takes `count` frames from `parent`. Returns `None` if `count` exceeds
+    /// `POOL_CAPACITY`, without taking any frames, or if the parent runs out
+    /// of frames first, after giving back the frames taken so far.
+    pub fn new<A>(count: usize, parent: &mut A) -> Option<PoolAllocator>
+        where A: FrameAllocator
+    {
+        if count > POOL_CAPACITY {
+            return None;
+        }
+
+        let mut pool = PoolAllocator {
+            frames: [0; POOL_CAPACITY],
+            len: 0,
+        };
+        while pool.len < count {
+            match parent.allocate_frame() {
+                Some(frame) => pool.deallocate_frame(frame),
+                None => {
+                    pool.release(parent);
+                    return None;
+                }
+            }
+        }
+        Some(pool)
+    }
+
+    /// Returns the number of frames left in the pool.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gives all frames left in the pool back to `parent`.
+    pub fn release<A>(mut self, parent: &mut A) where A: FrameAllocator {
+        while let Some(frame) = self.allocate_frame() {
+            parent.deallocate_frame(frame);
+        }
+    }
+}
+
+impl FrameAllocator for PoolAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(Frame { number: self.frames[self.len] })
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(self.len < POOL_CAPACITY,
+            "Pool allocator can hold only {} frames.", POOL_CAPACITY);
+        self.frames[self.len] = frame.number;
+        self.len += 1;
+    }
+
+    fn peek_next_frame(&self) -> Option<Frame> {
+        if self.len == 0 {
+            return None;
+        }
+        Some(Frame { number: self.frames[self.len - 1] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::test_support::small_allocator;
+
+    #[test]
+    fn pool_hands_out_its_frames_until_empty() {
+        let mut parent = small_allocator();
+        let mut pool = PoolAllocator::new(POOL_CAPACITY, &mut parent).unwrap();
+        assert_eq!(parent.used_frames(), POOL_CAPACITY);
+
+        let frames: Vec<Frame> = (0..POOL_CAPACITY)
+            .map(|_| pool.allocate_frame().unwrap())
+            .collect();
+        assert!(pool.is_empty());
+        assert_eq!(pool.allocate_frame(), None);
+        assert_eq!(pool.peek_next_frame(), None);
+        // nothing came from the parent
+        assert_eq!(parent.used_frames(), POOL_CAPACITY);
+
+        for frame in frames {
+            pool.deallocate_frame(frame);
+        }
+        assert_eq!(pool.len(), POOL_CAPACITY);
+        let next = pool.peek_next_frame();
+        assert!(next.is_some());
+        assert_eq!(pool.allocate_frame(), next);
+        pool.release(&mut parent);
+        assert_eq!(parent.used_frames(), 1);
+    }
+
+    #[test]
+    fn pool_larger_than_its_capacity_is_refused() {
+        let mut parent = small_allocator();
+        assert!(PoolAllocator::new(POOL_CAPACITY + 1, &mut parent).is_none());
+        assert_eq!(parent.used_frames(), 0);
+    }
+
+    #[test]
+    fn pool_gives_frames_back_if_the_parent_runs_out() {
+        let mut parent = small_allocator();
+        while parent.free_frames() > 3 {
+            parent.allocate_frame().unwrap();
+        }
+        let used_frames = parent.used_frames();
+        assert!(PoolAllocator::new(4, &mut parent).is_none());
+        assert_eq!(parent.used_frames(), used_frames);
+    }
+}
@@ -0,0 +1,289 @@
+use memory::{Frame, FrameRange};
+
+/// The number of disjoint frame ranges `ReservedRanges` can hold.
+pub const MAX_RESERVED_RANGES: usize = 128;
+
+/// The reasons changing a `ReservedRanges` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangesError {
+    /// The range can't be merged into a contained one and all
+    /// `MAX_RESERVED_RANGES` slots are taken.
+    TooManyRanges,
+}
+
+/// A set of frames, stored as sorted, disjoint and non-adjacent ranges.
+#[derive(Clone, Copy)]
+pub struct ReservedRanges {
+    ranges: [FrameRange; MAX_RESERVED_RANGES],
+    len: usize,
+}
+
+impl ReservedRanges {
+    pub fn new() -> ReservedRanges {
+        ReservedRanges {
+            ranges: [FrameRange { start: 0, end: 0 }; MAX_RESERVED_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Returns the ranges in ascending order.
+    pub fn as_slice(&self) -> &[FrameRange] {
+        &self.ranges[..self.len]
+    }
+
+    /// Adds the given frames. Overlapping and adjacent ranges are merged, so
+    /// a frame is never contained twice. If the range would need a slot of
+    /// its own but none is left, nothing is changed.
+    pub fn insert(&mut self, range: FrameRange) -> Result<(), RangesError> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let touches = |other: &FrameRange| other.start <= range.end && range.start <= other.end;
+        if self.len == MAX_RESERVED_RANGES && !self.as_slice().iter().any(touches) {
+            return Err(RangesError::TooManyRanges);
+        }
+
+        // absorb all ranges that overlap or touch the new one
+        let mut merged = range;
+        let mut i = 0;
+        while i < self.len {
+            let other = self.ranges[i];
+            if other.start <= merged.end && merged.start <= other.end {
+                merged.start = ::core::cmp::min(merged.start, other.start);
+                merged.end = ::core::cmp::max(merged.end, other.end);
+                for j in i..(self.len - 1) {
+                    self.ranges[j] = self.ranges[j + 1];
+                }
+                self.len -= 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        // either a slot was free or at least one range was absorbed
+        let position = self.as_slice().iter()
+            .position(|other| other.start > merged.start)
+            .unwrap_or(self.len);
+        for j in (position..self.len).rev() {
+            self.ranges[j + 1] = self.ranges[j];
+        }
+        self.ranges[position] = merged;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the given frames. Removing frames from the middle of a range
+    /// splits it in two, which fails without changing anything if no slot is
+    /// left.
+    pub fn remove(&mut self, range: FrameRange) -> Result<(), RangesError> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let splits = |other: &FrameRange| other.start < range.start && other.end > range.end;
+        if self.len == MAX_RESERVED_RANGES && self.as_slice().iter().any(splits) {
+            return Err(RangesError::TooManyRanges);
+        }
+
+        let mut i = 0;
+        while i < self.len {
+            let other = self.ranges[i];
+            if other.end <= range.start || other.start >= range.end {
+                i += 1;
+            } else if splits(&other) {
+                for j in ((i + 1)..self.len).rev() {
+                    self.ranges[j + 1] = self.ranges[j];
+                }
+                self.ranges[i].end = range.start;
+                self.ranges[i + 1] = FrameRange { start: range.end, end: other.end };
+                self.len += 1;
+                // no other range can overlap the removed frames
+                break;
+            } else if other.start < range.start {
+                self.ranges[i].end = range.start;
+                i += 1;
+            } else if other.end > range.end {
+                self.ranges[i].start = range.end;
+                i += 1;
+            } else {
+                for j in i..(self.len - 1) {
+                    self.ranges[j] = self.ranges[j + 1];
+                }
+                self.len -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the lowest contained frame.
+    pub fn first(&self) -> Option<Frame> {
+        self.as_slice().first().map(|range| range.start())
+    }
+
+    /// Returns the highest contained frame.
+    pub fn last(&self) -> Option<Frame> {
+        self.as_slice().last().map(|range| Frame { number: range.end - 1 })
+    }
+
+    /// Returns the number of contained frames.
+    pub fn frame_count(&self) -> usize {
+        self.as_slice().iter().map(|range| range.len()).sum()
+    }
+
+    /// Returns the range containing `frame`.
+    pub fn range_containing(&self, frame: &Frame) -> Option<FrameRange> {
+        use core::cmp::Ordering;
+
+        self.as_slice().binary_search_by(|range| {
+            if range.end <= frame.number {
+                Ordering::Less
+            } else if range.start > frame.number {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        }).ok().map(|index| self.ranges[index])
+    }
+
+    pub fn contains(&self, frame: &Frame) -> bool {
+        self.range_containing(frame).is_some()
+    }
+
+    /// Returns the first frame at or above `frame` that isn't contained.
+    pub fn next_free_above(&self, frame: Frame) -> Frame {
+        match self.range_containing(&frame) {
+            // ranges aren't adjacent, so the end of a range is free
+            Some(range) => range.end(),
+            None => frame,
+        }
+    }
+
+    /// Returns the number of contained frames in the given range.
+    pub fn count_in(&self, range: FrameRange) -> usize {
+        self.as_slice().iter().map(|reserved| {
+            let start = ::core::cmp::max(reserved.start, range.start);
+            let end = ::core::cmp::min(reserved.end, range.end);
+            if end > start { end - start } else { 0 }
+        }).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> FrameRange {
+        FrameRange { start: start, end: end }
+    }
+
+    #[test]
+    fn adjacent_ranges_are_merged() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(range(10, 20)).unwrap();
+        reserved.insert(range(20, 30)).unwrap();
+        reserved.insert(range(5, 10)).unwrap();
+        assert_eq!(reserved.as_slice(), &[range(5, 30)]);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(range(10, 20)).unwrap();
+        reserved.insert(range(30, 40)).unwrap();
+        reserved.insert(range(50, 60)).unwrap();
+        // spans the first two and overlaps both
+        reserved.insert(range(15, 35)).unwrap();
+        assert_eq!(reserved.as_slice(), &[range(10, 40), range(50, 60)]);
+
+        // contained in an existing range
+        reserved.insert(range(52, 55)).unwrap();
+        assert_eq!(reserved.as_slice(), &[range(10, 40), range(50, 60)]);
+    }
+
+    #[test]
+    fn ranges_stay_sorted() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(range(50, 60)).unwrap();
+        reserved.insert(range(10, 20)).unwrap();
+        reserved.insert(range(30, 40)).unwrap();
+        reserved.insert(range(0, 0)).unwrap();
+        assert_eq!(reserved.as_slice(), &[range(10, 20), range(30, 40), range(50, 60)]);
+    }
+
+    #[test]
+    fn next_free_above_skips_reserved_frames() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(range(10, 20)).unwrap();
+        reserved.insert(range(21, 25)).unwrap();
+
+        assert_eq!(reserved.next_free_above(Frame { number: 9 }), Frame { number: 9 });
+        assert_eq!(reserved.next_free_above(Frame { number: 10 }), Frame { number: 20 });
+        assert_eq!(reserved.next_free_above(Frame { number: 19 }), Frame { number: 20 });
+        assert_eq!(reserved.next_free_above(Frame { number: 22 }), Frame { number: 25 });
+    }
+
+    #[test]
+    fn contains_and_count_in() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(range(10, 20)).unwrap();
+        reserved.insert(range(30, 40)).unwrap();
+
+        assert!(!reserved.contains(&Frame { number: 9 }));
+        assert!(reserved.contains(&Frame { number: 10 }));
+        assert!(!reserved.contains(&Frame { number: 20 }));
+        assert_eq!(reserved.range_containing(&Frame { number: 35 }), Some(range(30, 40)));
+        assert_eq!(reserved.count_in(range(15, 35)), 10);
+        assert_eq!(reserved.count_in(range(0, 100)), 20);
+    }
+
+    #[test]
+    fn remove_shrinks_splits_and_deletes_ranges() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(range(10, 20)).unwrap();
+        reserved.insert(range(30, 40)).unwrap();
+        reserved.insert(range(50, 60)).unwrap();
+
+        reserved.remove(range(15, 32)).unwrap();
+        assert_eq!(reserved.as_slice(), &[range(10, 15), range(32, 40), range(50, 60)]);
+        reserved.remove(range(34, 36)).unwrap();
+        assert_eq!(reserved.as_slice(),
+                   &[range(10, 15), range(32, 34), range(36, 40), range(50, 60)]);
+        reserved.remove(range(0, 100)).unwrap();
+        assert!(reserved.as_slice().is_empty());
+    }
+
+    #[test]
+    fn first_last_and_frame_count() {
+        let mut reserved = ReservedRanges::new();
+        assert_eq!(reserved.first(), None);
+        reserved.insert(range(30, 40)).unwrap();
+        reserved.insert(range(10, 20)).unwrap();
+        assert_eq!(reserved.first(), Some(Frame { number: 10 }));
+        assert_eq!(reserved.last(), Some(Frame { number: 39 }));
+        assert_eq!(reserved.frame_count(), 20);
+    }
+
+    #[test]
+    fn full_set_rejects_disjoint_ranges_but_merges() {
+        let mut reserved = ReservedRanges::new();
+        for i in 0..MAX_RESERVED_RANGES {
+            reserved.insert(range(i * 10, i * 10 + 5)).unwrap();
+        }
+
+        let end = MAX_RESERVED_RANGES * 10;
+        assert_eq!(reserved.insert(range(end, end + 5)), Err(RangesError::TooManyRanges));
+        assert_eq!(reserved.as_slice().len(), MAX_RESERVED_RANGES);
+        assert!(!reserved.contains(&Frame { number: end }));
+
+        // merging doesn't need a slot
+        reserved.insert(range(5, 10)).unwrap();
+        assert_eq!(reserved.as_slice()[0], range(0, 15));
+        reserved.insert(range(end, end + 5)).unwrap();
+        assert!(reserved.contains(&Frame { number: end }));
+
+        // splitting does
+        let full = reserved.as_slice().len();
+        assert_eq!(reserved.remove(range(21, 23)), Err(RangesError::TooManyRanges));
+        assert_eq!(reserved.as_slice().len(), full);
+        assert!(reserved.contains(&Frame { number: 21 }));
+    }
+}
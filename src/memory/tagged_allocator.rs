@@ -0,0 +1,143 @@
+use memory::{Frame, FrameAllocator};
+use core::fmt;
+
+/// The maximum number of outstanding frames a `TaggedAllocator` can track.
+pub const MAX_TAGGED_FRAMES: usize = 256;
+
+/// The tag of frames allocated through the plain `FrameAllocator` methods.
+pub const UNTAGGED: &'static str = "untagged";
+
+/// Forwards to an inner allocator and remembers the tag every outstanding
+/// frame was allocated with, so that `report_outstanding` can tell which call
+/// site still holds frames. Meant for hunting leaks.
+pub struct TaggedAllocator<A: FrameAllocator> {
+    inner: A,
+    // (frame number, tag) of the outstanding frames, unordered
+    tags: [(usize, &'static str); MAX_TAGGED_FRAMES],
+    len: usize,
+}
+
+impl<A> TaggedAllocator<A> where A: FrameAllocator {
+    pub fn new(inner: A) -> TaggedAllocator<A> {
+        TaggedAllocator {
+            inner: inner,
+            tags: [(0, UNTAGGED); MAX_TAGGED_FRAMES],
+            len: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    /// Allocates a frame and records `tag` for it until it's deallocated.
+    pub fn allocate_frame_tagged(&mut self, tag: &'static str) -> Option<Frame> {
+        let frame = self.inner.allocate_frame();
+        self.record(frame, tag)
+    }
+
+    /// Returns the number of outstanding frames allocated with `tag`.
+    pub fn outstanding(&self, tag: &str) -> usize {
+        self.tags[..self.len].iter().filter(|&&(_, t)| t == tag).count()
+    }
+
+    /// Prints how many frames each tag still holds, in the order the tags
+    /// were first seen.
+    pub fn report_outstanding(&self) {
+        use vga_buffer::WRITER;
+        self.write_report(&mut *WRITER.lock()).unwrap();
+    }
+
+    /// Writes the report of `report_outstanding` to `out`.
+    fn write_report<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        try!(writeln!(out, "{} outstanding frames", self.len));
+        let tags = &self.tags[..self.len];
+        for (i, &(_, tag)) in tags.iter().enumerate() {
+            // report every tag only at its first occurrence
+            if tags[..i].iter().any(|&(_, t)| t == tag) {
+                continue;
+            }
+            try!(writeln!(out, "  {}: {}", tag, self.outstanding(tag)));
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, frame: Option<Frame>, tag: &'static str) -> Option<Frame> {
+        if let Some(ref frame) = frame {
+            assert!(self.len < MAX_TAGGED_FRAMES,
+                    "TaggedAllocator can track only {} outstanding frames", MAX_TAGGED_FRAMES);
+            self.tags[self.len] = (frame.number(), tag);
+            self.len += 1;
+        }
+        frame
+    }
+}
+
+impl<A> FrameAllocator for TaggedAllocator<A> where A: FrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_frame_tagged(UNTAGGED)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        let number = frame.number();
+        let index = self.tags[..self.len].iter().position(|&(n, _)| n == number);
+        debug_assert!(index.is_some(), "deallocating untracked frame {:?}", frame);
+        if let Some(index) = index {
+            self.len -= 1;
+            self.tags[index] = self.tags[self.len];
+        }
+        self.inner.deallocate_frame(frame);
+    }
+
+    fn peek_next_frame(&self) -> Option<Frame> {
+        self.inner.peek_next_frame()
+    }
+
+    fn allocate_frame_on_node(&mut self, node: u8) -> Option<Frame> {
+        let frame = self.inner.allocate_frame_on_node(node);
+        self.record(frame, UNTAGGED)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::AreaFrameAllocator;
+    use memory::test_support::small_allocator;
+
+    fn allocator() -> TaggedAllocator<AreaFrameAllocator> {
+        TaggedAllocator::new(small_allocator())
+    }
+
+    #[test]
+    fn report_attributes_outstanding_frames_to_their_tags() {
+        let mut allocator = allocator();
+        let mut heap_frames = Vec::new();
+        for _ in 0..3 {
+            heap_frames.push(allocator.allocate_frame_tagged("heap").unwrap());
+        }
+        let mut dma_frames = Vec::new();
+        for _ in 0..2 {
+            dma_frames.push(allocator.allocate_frame_tagged("dma").unwrap());
+        }
+        let untagged = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(heap_frames.remove(1));
+        allocator.deallocate_frame(dma_frames.pop().unwrap());
+        allocator.deallocate_frame(untagged);
+
+        assert_eq!(allocator.outstanding("heap"), 2);
+        assert_eq!(allocator.outstanding("dma"), 1);
+        assert_eq!(allocator.outstanding(UNTAGGED), 0);
+        assert_eq!(allocator.inner().used_frames(), 3);
+
+        let mut report = String::new();
+        allocator.write_report(&mut report).unwrap();
+        assert_eq!(report, "3 outstanding frames\n  heap: 2\n  dma: 1\n");
+    }
+}
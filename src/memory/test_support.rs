@@ -0,0 +1,209 @@
+//! Fixtures for the host tests of the memory code.
+
+use memory::{AreaFrameAllocator, FrameAllocator};
+use memory::paging::{ActivePageTable, PhysicalAddress, VirtualAddress, RECURSIVE_INDEX};
+use multiboot2::{self, BootInformation, MemoryAreaIter};
+use hw;
+use core::mem;
+
+pub const MIB: u64 = 1024 * 1024;
+
+/// The size of the model physical memory, enough for every test allocator.
+const PHYSICAL_MEMORY_SIZE: usize = 64 * MIB as usize;
+
+thread_local!(static PHYSICAL_MEMORY: Box<[u64]> =
+    vec![0; PHYSICAL_MEMORY_SIZE / 8].into_boxed_slice());
+
+const MEMORY_MAP_TAG: u32 = 6;
+const ELF_SECTIONS_TAG: u32 = 9;
+
+/// Returns an allocator for 1MiB to 5MiB, with the kernel and the multiboot
+/// information in the first five frames. The first frame it hands out is
+/// 0x105, the last one 0x4ff.
+pub fn small_allocator() -> AreaFrameAllocator {
+    allocator_with_areas(&[(MIB, 4 * MIB)])
+}
+
+/// Like `small_allocator`, but for the given available memory areas. The
+/// kernel and the multiboot information still take the five frames from 1MiB.
+pub fn allocator_with_areas(areas: &[(u64, u64)]) -> AreaFrameAllocator {
+    AreaFrameAllocator::new(0x10_0000, 0x10_4000, 0x10_4000, 0x10_5000, memory_areas(areas))
+}
+
+/// Returns an active page table on the model physical memory of this test:
+/// the P4 table is a zeroed frame from `allocator` that maps itself at
+/// `RECURSIVE_INDEX`, and the mock CR3 points to it. NXE is enabled in the
+/// mock, so that pages can be mapped no-execute.
+pub fn active_table<A>(allocator: &mut A) -> ActivePageTable
+    where A: FrameAllocator
+{
+    let p4_frame = allocator.allocate_frame().expect("no frame for the P4 table");
+    let p4 = physical_pointer::<[u64; 512]>(p4_frame.start_address());
+    unsafe {
+        *p4 = [0; 512];
+        // present and writable
+        (*p4)[RECURSIVE_INDEX] = p4_frame.start_address() as u64 | 0b11;
+    }
+    // not through `write_cr3`, which counts the writes
+    hw::with_mock(|hw| hw.cr3.set(p4_frame.start_address()));
+    hw::enable_nxe().unwrap();
+    unsafe { ActivePageTable::new() }
+}
+
+/// Returns a pointer to the given address of the model physical memory. It
+/// is zeroed when a test starts and only accessed by that test.
+pub fn physical_pointer<T>(address: PhysicalAddress) -> *mut T {
+    assert!(address + mem::size_of::<T>() <= PHYSICAL_MEMORY_SIZE,
+            "physical address {:#x} is outside of the model memory", address);
+    PHYSICAL_MEMORY.with(|memory| unsafe {
+        (memory.as_ptr() as *mut u8).offset(address as isize) as *mut T
+    })
+}
+
+/// Translates `address` through the model page tables the mock CR3 points
+/// to, like the MMU, and returns the host address it refers to. There is no
+/// TLB, so changed entries take effect immediately. Panics if the address
+/// isn't mapped.
+pub fn host_address(address: VirtualAddress) -> usize {
+    const PRESENT: u64 = 1 << 0;
+    const HUGE_PAGE: u64 = 1 << 7;
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    let mut table = hw::read_cr3() as u64 & ADDRESS_MASK;
+    for level in 0..4 {
+        let shift = 39 - 9 * level;
+        let index = (address >> shift) & 0o777;
+        let entry = unsafe { *physical_pointer::<u64>(table as usize + index * 8) };
+        assert!(entry & PRESENT != 0, "page fault at {:#x}", address);
+        // a P1 entry maps 4KiB, P2 and P3 huge entries 2MiB and 1GiB
+        if level == 3 || (level > 0 && entry & HUGE_PAGE != 0) {
+            let page_size = 1 << shift;
+            let frame = (entry & ADDRESS_MASK) as usize & !(page_size - 1);
+            return physical_pointer::<u8>(frame + address % page_size) as usize;
+        }
+        table = entry & ADDRESS_MASK;
+    }
+    unreachable!()
+}
+
+/// Returns an iterator over available memory areas with the given base
+/// addresses and lengths, like the one of a multiboot memory map tag.
+pub fn memory_areas(areas: &[(u64, u64)]) -> MemoryAreaIter {
+    boot_information(&[(MEMORY_MAP_TAG, &memory_map_contents(areas))])
+        .memory_map_tag().unwrap()
+        .memory_areas()
+}
+
+/// Returns multiboot information containing the given tags, each given by its
+/// type and the bytes following its size field, and the end tag.
+pub fn boot_information(tags: &[(u32, &[u8])]) -> &'static BootInformation {
+    // total size and reserved field, the size is filled in below
+    let mut bytes = vec![0; 8];
+    for &(typ, contents) in tags.iter().chain(&[(0, &[][..])]) {
+        push_u32(&mut bytes, typ);
+        push_u32(&mut bytes, 8 + contents.len() as u32);
+        bytes.extend_from_slice(contents);
+        // tags are 8 byte aligned
+        while bytes.len() % 8 != 0 {
+            bytes.push(0);
+        }
+    }
+    let total_size = bytes.len();
+    for i in 0..4 {
+        bytes[i] = (total_size >> (8 * i)) as u8;
+    }
+
+    // copy to u64 words, for the alignment
+    let mut words = vec![0u64; total_size / 8];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(8)) {
+        *word = chunk.iter().rev().fold(0, |word, &byte| word << 8 | byte as u64);
+    }
+    let words: &'static [u64] = Box::leak(words.into_boxed_slice());
+    unsafe { multiboot2::load(words.as_ptr() as usize) }
+}
+
+/// Returns multiboot information with an ELF sections tag describing the
+/// given sections, each given by its name, address, size and ELF flags.
+pub fn elf_boot_information(sections: &[(&str, u64, u64, u64)]) -> &'static BootInformation {
+    kernel_boot_information(&[], sections)
+}
+
+/// Returns multiboot information with a memory map of the given available
+/// areas and an ELF sections tag of the given sections, see `memory_areas`
+/// and `elf_boot_information`.
+pub fn kernel_boot_information(areas: &[(u64, u64)], sections: &[(&str, u64, u64, u64)])
+    -> &'static BootInformation
+{
+    boot_information(&[(MEMORY_MAP_TAG, &memory_map_contents(areas)),
+                       (ELF_SECTIONS_TAG, &elf_sections_contents(sections))])
+}
+
+fn memory_map_contents(areas: &[(u64, u64)]) -> Vec<u8> {
+    const AVAILABLE: u32 = 1;
+    const ENTRY_SIZE: u32 = 24;
+
+    let mut bytes = Vec::new();
+    push_u32(&mut bytes, ENTRY_SIZE);
+    push_u32(&mut bytes, 0); // entry version
+    for &(base, length) in areas {
+        push_u64(&mut bytes, base);
+        push_u64(&mut bytes, length);
+        push_u32(&mut bytes, AVAILABLE);
+        push_u32(&mut bytes, 0);
+    }
+    bytes
+}
+
+fn elf_sections_contents(sections: &[(&str, u64, u64, u64)]) -> Vec<u8> {
+    const PROGRAM_SECTION: u32 = 1;
+    const STRING_TABLE: u32 = 3;
+    const ENTRY_SIZE: u32 = 64;
+
+    // like in an ELF file, the first section is the null section; the string
+    // table goes last
+    let mut names = vec![0];
+    let mut entries = vec![section_entry(0, 0, 0, 0, 0)];
+    for &(name, addr, size, flags) in sections {
+        entries.push(section_entry(names.len() as u32, PROGRAM_SECTION, flags, addr, size));
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    let names: &'static [u8] = Box::leak(names.into_boxed_slice());
+    entries.push(section_entry(0, STRING_TABLE, 0, names.as_ptr() as u64, names.len() as u64));
+
+    let mut bytes = Vec::new();
+    push_u32(&mut bytes, entries.len() as u32);
+    push_u32(&mut bytes, ENTRY_SIZE);
+    push_u32(&mut bytes, entries.len() as u32 - 1);
+    for entry in &entries {
+        bytes.extend_from_slice(entry);
+    }
+    bytes
+}
+
+fn section_entry(name_index: u32, typ: u32, flags: u64, addr: u64, size: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    push_u32(&mut bytes, name_index);
+    push_u32(&mut bytes, typ);
+    push_u64(&mut bytes, flags);
+    push_u64(&mut bytes, addr);
+    push_u64(&mut bytes, 0); // offset
+    push_u64(&mut bytes, size);
+    // link, info, alignment and entry size
+    push_u32(&mut bytes, 0);
+    push_u32(&mut bytes, 0);
+    push_u64(&mut bytes, 0);
+    push_u64(&mut bytes, 0);
+    bytes
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn push_u64(bytes: &mut Vec<u8>, value: u64) {
+    push_u32(bytes, value as u32);
+    push_u32(bytes, (value >> 32) as u32);
+}
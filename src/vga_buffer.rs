@@ -13,11 +13,14 @@ pub static WRITER: Mutex<Writer> = Mutex::new(
     }
 );
 
+// host tests print through std instead
+#[cfg(not(test))]
 macro_rules! println {
     ($fmt:expr) => (print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+#[cfg(not(test))]
 macro_rules! print {
     ($($arg:tt)*) => ({
         use core::fmt::Write;